@@ -0,0 +1,157 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json;
+
+use config::{AccessLogConfig, AccessLogFormat};
+use connection::{Connection, CloseReason};
+
+#[derive(RustcEncodable)]
+struct AccessLogRecord {
+    client_addr: String,
+    frontend: String,
+    backend: String,
+    target: String,
+    start_time: String,
+    duration_ms: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    reason: String,
+}
+
+/// Writes one record per closed connection, either to a file or to stdout
+pub struct AccessLog {
+    format: AccessLogFormat,
+    output: Box<Write>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> io::Result<AccessLog> {
+        let output: Box<Write> = match config.path {
+            Some(ref path) => {
+                Box::new(try!(OpenOptions::new().create(true).append(true).open(path)))
+            }
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(AccessLog {
+            format: config.format(),
+            output: output,
+        })
+    }
+
+    pub fn log(&mut self, connection: &Connection, reason: CloseReason) {
+        let duration = connection.start_time().elapsed().unwrap_or(Duration::new(0, 0));
+
+        let record = AccessLogRecord {
+            client_addr: connection.client_addr().to_string(),
+            frontend: connection.frontend().name().to_owned(),
+            backend: connection.backend().borrow().name().to_owned(),
+            target: connection.target().to_string(),
+            start_time: format_time(connection.start_time()),
+            duration_ms: duration.as_secs() * 1000 + (duration.subsec_nanos() / 1000000) as u64,
+            bytes_in: connection.bytes_in(),
+            bytes_out: connection.bytes_out(),
+            reason: reason.as_str().to_owned(),
+        };
+
+        let line = format_record(&record, self.format);
+        let written = self.output.write_all(line.as_bytes()).and_then(|_| self.output.flush());
+
+        if let Err(e) = written {
+            error!("Could not write access log record: {}", e);
+        }
+    }
+}
+
+fn format_record(record: &AccessLogRecord, format: AccessLogFormat) -> String {
+    match format {
+        AccessLogFormat::Text => {
+            format!("{} {} {} -> {} {} {}ms {} {} {}\n",
+                    record.start_time,
+                    record.client_addr,
+                    record.frontend,
+                    record.backend,
+                    record.target,
+                    record.duration_ms,
+                    record.bytes_in,
+                    record.bytes_out,
+                    record.reason)
+        }
+        AccessLogFormat::Json => {
+            format!("{}\n", json::encode(record).unwrap_or_else(|_| "{}".to_owned()))
+        }
+    }
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC with millisecond precision
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    let secs = since_epoch.as_secs();
+    let millis = since_epoch.subsec_nanos() / 1000000;
+
+    // Converts days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            millis)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccessLogRecord, format_record, format_time};
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use config::AccessLogFormat;
+
+    fn record() -> AccessLogRecord {
+        AccessLogRecord {
+            client_addr: "10.0.0.1:50000".to_owned(),
+            frontend: "in".to_owned(),
+            backend: "out".to_owned(),
+            target: "127.0.0.1:8000".to_owned(),
+            start_time: "2016-02-29T12:30:05.250Z".to_owned(),
+            duration_ms: 1500,
+            bytes_in: 10,
+            bytes_out: 20,
+            reason: "client_close".to_owned(),
+        }
+    }
+
+    #[test]
+    fn times_are_formatted_as_rfc3339() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_millis(1456749005250)),
+                   "2016-02-29T12:30:05.250Z");
+    }
+
+    #[test]
+    fn records_are_formatted_as_text_or_json_lines() {
+        assert_eq!(format_record(&record(), AccessLogFormat::Text),
+                   "2016-02-29T12:30:05.250Z 10.0.0.1:50000 in -> out 127.0.0.1:8000 1500ms 10 \
+                    20 client_close\n");
+        assert_eq!(format_record(&record(), AccessLogFormat::Json),
+                   "{\"client_addr\":\"10.0.0.1:50000\",\"frontend\":\"in\",\"backend\":\"out\",\
+                    \"target\":\"127.0.0.1:8000\",\"start_time\":\"2016-02-29T12:30:05.250Z\",\
+                    \"duration_ms\":1500,\"bytes_in\":10,\"bytes_out\":20,\
+                    \"reason\":\"client_close\"}\n");
+    }
+}
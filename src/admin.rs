@@ -0,0 +1,356 @@
+use std::str;
+
+use mio::{EventSet, TryRead, TryWrite};
+use mio::tcp::TcpStream;
+
+use rustc_serialize::{json, Encodable};
+
+use backend::TargetStatus;
+use config::TargetConfig;
+use driver::DriverMessage;
+
+// Requests larger than this are rejected without being parsed
+const MAX_REQUEST_SIZE: usize = 65536;
+
+pub const MAX_CONNECTIONS: usize = 32;
+
+pub struct AdminRequest {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+pub struct AdminResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+pub enum AdminRoute {
+    Metrics,
+    Frontends,
+    Backends,
+    Connections,
+    Config,
+    Action(DriverMessage),
+    Error(u16, String),
+}
+
+pub enum AdminEvent {
+    Pending,
+    Request(AdminRequest),
+    Done,
+}
+
+#[derive(RustcEncodable)]
+pub struct FrontendStatus {
+    pub name: String,
+    pub listen_addr: String,
+    pub backends: Vec<String>,
+    pub active_connections: usize,
+}
+
+#[derive(RustcEncodable)]
+pub struct BackendStatus {
+    pub name: String,
+    pub strategy: String,
+    pub targets: Vec<TargetStatus>,
+}
+
+#[derive(RustcEncodable)]
+pub struct ConnectionStatus {
+    pub active: usize,
+    pub draining: usize,
+    pub capacity: usize,
+}
+
+#[derive(RustcEncodable)]
+struct StatusResponse {
+    status: String,
+}
+
+#[derive(RustcEncodable)]
+struct ErrorResponse {
+    error: String,
+}
+
+enum AdminState {
+    Reading(Vec<u8>),
+    Writing(Vec<u8>, usize),
+}
+
+pub struct AdminConnection {
+    stream: TcpStream,
+    state: AdminState,
+    metrics_only: bool,
+}
+
+impl AdminConnection {
+    pub fn new(stream: TcpStream, metrics_only: bool) -> AdminConnection {
+        AdminConnection {
+            stream: stream,
+            state: AdminState::Reading(Vec::new()),
+            metrics_only: metrics_only,
+        }
+    }
+
+    pub fn stream<'a>(&'a self) -> &'a TcpStream {
+        &self.stream
+    }
+
+    /// True for connections accepted on the metrics listener, which only
+    /// serves `/metrics`
+    pub fn is_metrics_only(&self) -> bool {
+        self.metrics_only
+    }
+
+    pub fn interest(&self) -> EventSet {
+        let interest = match self.state {
+            AdminState::Reading(_) => EventSet::readable(),
+            AdminState::Writing(..) => EventSet::writable(),
+        };
+
+        interest | EventSet::error() | EventSet::hup()
+    }
+
+    /// Reads the request or writes the response, depending on how far
+    /// along the connection is. Invalid requests are answered right away.
+    pub fn ready(&mut self, events: EventSet) -> AdminEvent {
+        if events.is_error() {
+            return AdminEvent::Done;
+        }
+
+        let parsed = match self.state {
+            AdminState::Reading(ref mut request) => {
+                let mut buf = [0; 4096];
+                let mut closed = false;
+
+                loop {
+                    match self.stream.try_read(&mut buf) {
+                        Ok(Some(0)) => {
+                            closed = true;
+                            break;
+                        }
+                        Ok(Some(n)) => request.extend(buf[..n].iter().cloned()),
+                        Ok(None) => break,
+                        Err(e) => {
+                            debug!("Could not read admin request: {}", e);
+                            return AdminEvent::Done;
+                        }
+                    }
+                }
+
+                match parse_request(request) {
+                    Ok(None) if closed => return AdminEvent::Done,
+                    Ok(None) => return AdminEvent::Pending,
+                    Ok(Some(request)) => Ok(request),
+                    Err(message) => Err(message),
+                }
+            }
+            AdminState::Writing(ref response, ref mut offset) => {
+                match self.stream.try_write(&response[*offset..]) {
+                    Ok(Some(n)) => *offset += n,
+                    Ok(None) => {}
+                    Err(e) => {
+                        debug!("Could not write admin response: {}", e);
+                        return AdminEvent::Done;
+                    }
+                }
+
+                if *offset < response.len() {
+                    return AdminEvent::Pending;
+                } else {
+                    return AdminEvent::Done;
+                }
+            }
+        };
+
+        match parsed {
+            Ok(request) => AdminEvent::Request(request),
+            Err(message) => {
+                self.respond(error_response(400, &message));
+
+                AdminEvent::Pending
+            }
+        }
+    }
+
+    pub fn respond(&mut self, response: AdminResponse) {
+        let response = format!("HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: \
+                                {}\r\nConnection: close\r\n\r\n{}",
+                               response.status,
+                               reason_phrase(response.status),
+                               response.content_type,
+                               response.body.len(),
+                               response.body);
+
+        self.state = AdminState::Writing(response.into_bytes(), 0);
+    }
+}
+
+/// Parses a buffered request. Returns `None` while the request is still
+/// incomplete.
+fn parse_request(buf: &[u8]) -> Result<Option<AdminRequest>, String> {
+    if buf.len() > MAX_REQUEST_SIZE {
+        return Err("Request too large".to_owned());
+    }
+
+    let head_end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+
+    let head = try!(str::from_utf8(&buf[..head_end]).map_err(|_| "Request is not valid UTF-8"));
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+
+    if method.is_empty() || path.is_empty() {
+        return Err("Malformed request line".to_owned());
+    }
+
+    let mut content_length = 0;
+
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = try!(value.parse::<usize>()
+                                       .map_err(|_| "Malformed Content-Length header"));
+        }
+    }
+
+    let body_start = head_end + 4;
+
+    if content_length > MAX_REQUEST_SIZE - body_start {
+        return Err("Request too large".to_owned());
+    }
+
+    if buf.len() < body_start + content_length {
+        return Ok(None);
+    }
+
+    Ok(Some(AdminRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        body: buf[body_start..body_start + content_length].to_vec(),
+    }))
+}
+
+pub fn route(request: &AdminRequest) -> AdminRoute {
+    let path = request.path.split('?').next().unwrap_or("");
+    let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+    let method = &request.method[..];
+
+    let is_get = match &segments[..] {
+        &["metrics"] | &["frontends"] | &["backends"] | &["connections"] | &["config"] => true,
+        &["reload"] | &["backends", _, "targets"] | &["backends", _, "targets", _, _] => false,
+        _ => return AdminRoute::Error(404, format!("No such endpoint: {}", path)),
+    };
+
+    if is_get && method != "GET" || !is_get && method != "POST" {
+        return AdminRoute::Error(405, format!("Method {} not allowed on {}", method, path));
+    }
+
+    match &segments[..] {
+        &["metrics"] => AdminRoute::Metrics,
+        &["frontends"] => AdminRoute::Frontends,
+        &["backends"] => AdminRoute::Backends,
+        &["connections"] => AdminRoute::Connections,
+        &["config"] => AdminRoute::Config,
+        &["reload"] => AdminRoute::Action(DriverMessage::Reload),
+        &["backends", backend, "targets"] => {
+            let body = String::from_utf8_lossy(&request.body);
+
+            match json::decode::<TargetConfig>(&body) {
+                Ok(target) => {
+                    AdminRoute::Action(DriverMessage::AddTarget {
+                        backend: backend.to_owned(),
+                        target: target,
+                    })
+                }
+                Err(e) => AdminRoute::Error(400, format!("Invalid target: {}", e)),
+            }
+        }
+        &["backends", backend, "targets", addr, action] => {
+            let backend = backend.to_owned();
+            let addr = addr.to_owned();
+
+            match action {
+                "drain" => {
+                    AdminRoute::Action(DriverMessage::SetTargetEnabled {
+                        backend: backend,
+                        addr: addr,
+                        enabled: false,
+                    })
+                }
+                "enable" => {
+                    AdminRoute::Action(DriverMessage::SetTargetEnabled {
+                        backend: backend,
+                        addr: addr,
+                        enabled: true,
+                    })
+                }
+                "remove" => {
+                    AdminRoute::Action(DriverMessage::RemoveTarget {
+                        backend: backend,
+                        addr: addr,
+                    })
+                }
+                _ => AdminRoute::Error(404, format!("Unknown target action {}", action)),
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+pub fn json_response<T: Encodable>(value: &T) -> AdminResponse {
+    match json::encode(value) {
+        Ok(body) => {
+            AdminResponse {
+                status: 200,
+                content_type: "application/json",
+                body: body,
+            }
+        }
+        Err(e) => error_response(500, &format!("Could not encode response: {}", e)),
+    }
+}
+
+pub fn text_response(body: String) -> AdminResponse {
+    AdminResponse {
+        status: 200,
+        content_type: "text/plain; version=0.0.4",
+        body: body,
+    }
+}
+
+pub fn ok_response() -> AdminResponse {
+    json_response(&StatusResponse { status: "ok".to_owned() })
+}
+
+pub fn error_response(status: u16, message: &str) -> AdminResponse {
+    let body = json::encode(&ErrorResponse { error: message.to_owned() })
+                   .unwrap_or_else(|_| "{}".to_owned());
+
+    AdminResponse {
+        status: status,
+        content_type: "application/json",
+        body: body,
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
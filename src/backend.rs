@@ -1,24 +1,692 @@
-use std::net::SocketAddr;
+use std::net::{SocketAddr, IpAddr};
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+
+use mio::tcp::TcpStream;
+
+use openssl::ssl::SslConnector;
+
+use config::{BackendConfig, BalancingStrategy, HealthCheckConfig, ProxyProtocolVersion};
+use tls::Stream;
+
+struct Target {
+    addr: SocketAddr,
+    weight: usize,
+    current_weight: isize,
+    // Shared with the target this one replaced on reconfiguration, and with
+    // the connections counted in it
+    active_connections: Rc<Cell<usize>>,
+    enabled: bool,
+    healthy: bool,
+    consecutive_successes: usize,
+    consecutive_failures: usize,
+}
 
 pub struct Backend {
-    targets: Vec<SocketAddr>,
+    name: String,
+    targets: Vec<Target>,
+    config: BackendConfig,
+    strategy: BalancingStrategy,
     next_target: usize,
+    hash_ring: Vec<(u64, usize)>,
+    tls: Option<SslConnector>,
+}
+
+/// A connection counted in the active connections of its target. Closing
+/// it takes it off the count of the target it was opened to, even if that
+/// target was removed or added again since.
+pub struct ConnectionCount(Rc<Cell<usize>>);
+
+#[derive(RustcEncodable)]
+pub struct TargetStatus {
+    pub addr: String,
+    pub weight: usize,
+    pub enabled: bool,
+    pub healthy: bool,
+    pub active_connections: usize,
+}
+
+// Number of points each unit of weight gets on the consistent hash ring
+const RING_POINTS_PER_WEIGHT: usize = 160;
+
+impl ConnectionCount {
+    pub fn closed(self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+impl Target {
+    fn new(addr: SocketAddr, weight: usize) -> Target {
+        Target {
+            addr: addr,
+            weight: weight,
+            current_weight: 0,
+            active_connections: Rc::new(Cell::new(0)),
+            enabled: true,
+            healthy: true,
+            consecutive_successes: 0,
+            consecutive_failures: 0,
+        }
+    }
+
+    fn is_available(&self, excluded: &[SocketAddr]) -> bool {
+        self.weight > 0 && self.enabled && self.healthy && !excluded.contains(&self.addr)
+    }
+
+    fn is_less_loaded_than(&self, other: &Target) -> bool {
+        self.active_connections.get() * other.weight <
+        other.active_connections.get() * self.weight
+    }
 }
 
 impl Backend {
-    pub fn new(targets: Vec<SocketAddr>) -> Rc<RefCell<Backend>> {
+    pub fn new(name: &str,
+               targets: Vec<(SocketAddr, usize)>,
+               tls: Option<SslConnector>,
+               config: &BackendConfig)
+               -> Rc<RefCell<Backend>> {
+        let strategy = config.strategy.unwrap_or_default();
+
+        let targets = targets.into_iter()
+                             .map(|(addr, weight)| Target::new(addr, weight))
+                             .collect::<Vec<_>>();
+
+        let hash_ring = match strategy {
+            BalancingStrategy::SourceHash => build_hash_ring(&targets),
+            _ => Vec::new(),
+        };
+
         Rc::new(RefCell::new(Backend {
+            name: name.to_owned(),
             targets: targets,
+            config: config.clone(),
+            strategy: strategy,
             next_target: 0,
+            hash_ring: hash_ring,
+            tls: tls,
         }))
     }
 
-    pub fn decide_target(&mut self) -> SocketAddr {
-        let target = self.targets[self.next_target];
-        self.next_target = (self.next_target + 1) % self.targets.len();
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheckConfig> {
+        self.config.health_check.as_ref()
+    }
+
+    pub fn connect_retries(&self) -> usize {
+        self.config.connect_retries.unwrap_or(0)
+    }
+
+    pub fn send_proxy_protocol(&self) -> Option<ProxyProtocolVersion> {
+        self.config.send_proxy_protocol
+    }
+
+    /// How many idle keep-alive connections to keep open to each target
+    /// for later requests. Connections that started with a PROXY header
+    /// belong to a single client, and are never kept.
+    pub fn pool_max_idle(&self) -> usize {
+        match self.config.send_proxy_protocol {
+            Some(_) => 0,
+            None => self.config.pool_max_idle.unwrap_or(0),
+        }
+    }
+
+    pub fn pool_idle_timeout_ms(&self) -> u64 {
+        self.config.pool_idle_timeout_ms.unwrap_or(30000)
+    }
+
+    /// Whether both backends set up connections to their targets the same
+    /// way, so that idle connections opened by one can be used by the other
+    pub fn connects_like(&self, other: &Backend) -> bool {
+        self.config.send_proxy_protocol == other.config.send_proxy_protocol &&
+        self.config.tls == other.config.tls &&
+        self.config.tls_ca_path == other.config.tls_ca_path &&
+        self.config.tls_server_name == other.config.tls_server_name &&
+        self.config.tls_client_certificate == other.config.tls_client_certificate
+    }
+
+    /// Wraps a new connection to one of the targets in a TLS session on
+    /// backends with `tls = true`. Targets are verified against
+    /// `tls_server_name`, or their IP address if it isn't set.
+    pub fn wrap_stream(&self, stream: TcpStream, target: SocketAddr) -> Result<Stream, String> {
+        match self.tls {
+            Some(ref connector) => {
+                let server_name = match self.config.tls_server_name {
+                    Some(ref name) => name.clone(),
+                    None => target.ip().to_string(),
+                };
+
+                Stream::connect(connector, &server_name, stream)
+            }
+            None => Ok(Stream::plain(stream)),
+        }
+    }
+
+    pub fn strategy(&self) -> BalancingStrategy {
+        self.strategy
+    }
+
+    pub fn target_addrs(&self) -> Vec<SocketAddr> {
+        self.targets.iter().map(|t| t.addr).collect()
+    }
+
+    pub fn target_statuses(&self) -> Vec<TargetStatus> {
+        self.targets
+            .iter()
+            .map(|t| {
+                TargetStatus {
+                    addr: t.addr.to_string(),
+                    weight: t.weight,
+                    enabled: t.enabled,
+                    healthy: t.healthy,
+                    active_connections: t.active_connections.get(),
+                }
+            })
+            .collect()
+    }
+
+    /// Adds a target to the running backend. Returns false if the target
+    /// is already part of the backend.
+    pub fn add_target(&mut self, addr: SocketAddr, weight: usize) -> bool {
+        if self.targets.iter().any(|t| t.addr == addr) {
+            return false;
+        }
+
+        info!("Adding target {} to backend {}", addr, self.name);
+
+        self.targets.push(Target::new(addr, weight));
+        self.rebuild_hash_ring();
+
+        true
+    }
+
+    /// Removes a target from the running backend. Returns false if there
+    /// was no such target.
+    pub fn remove_target(&mut self, addr: SocketAddr) -> bool {
+        let count = self.targets.len();
+
+        self.targets.retain(|t| t.addr != addr);
+
+        if self.targets.len() == count {
+            return false;
+        }
+
+        info!("Removing target {} from backend {}", addr, self.name);
+
+        self.next_target = 0;
+        self.rebuild_hash_ring();
+
+        true
+    }
+
+    /// Takes over the connection counts, health and enabled flags of the
+    /// targets that were also part of `old`, the backend this one replaces
+    pub fn carry_over(&mut self, old: &Backend) {
+        let has_health_check = self.config.health_check.is_some();
+
+        for target in self.targets.iter_mut() {
+            if let Some(previous) = old.targets.iter().find(|t| t.addr == target.addr) {
+                target.active_connections = previous.active_connections.clone();
+                target.enabled = previous.enabled;
+
+                if has_health_check {
+                    target.healthy = previous.healthy;
+                    target.consecutive_successes = previous.consecutive_successes;
+                    target.consecutive_failures = previous.consecutive_failures;
+                }
+            }
+        }
+    }
+
+    /// Enables or disables a target. Disabled targets keep their existing
+    /// connections, but don't receive any new ones.
+    pub fn set_target_enabled(&mut self, addr: SocketAddr, enabled: bool) -> bool {
+        match self.targets.iter_mut().find(|t| t.addr == addr) {
+            Some(target) => {
+                info!("{} target {} in backend {}",
+                      if enabled { "Enabling" } else { "Draining" },
+                      addr,
+                      self.name);
+                target.enabled = enabled;
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn decide_target(&mut self, client_addr: &SocketAddr) -> Option<SocketAddr> {
+        self.decide_target_excluding(client_addr, &[])
+    }
+
+    /// Like `decide_target`, but never picks any of the `excluded` targets
+    pub fn decide_target_excluding(&mut self,
+                                   client_addr: &SocketAddr,
+                                   excluded: &[SocketAddr])
+                                   -> Option<SocketAddr> {
+        let index = match self.strategy {
+            BalancingStrategy::RoundRobin => self.weighted_round_robin_target(excluded),
+            BalancingStrategy::LeastConnections => self.least_connected_target(excluded),
+            BalancingStrategy::SourceHash => {
+                self.source_hashed_target(&client_addr.ip(), excluded)
+            }
+        };
+
+        index.map(|index| {
+            self.next_target = (index + 1) % self.targets.len();
+
+            self.targets[index].addr
+        })
+    }
+
+    pub fn connection_opened(&mut self, addr: SocketAddr) -> Option<ConnectionCount> {
+        self.targets.iter().find(|t| t.addr == addr).map(|target| {
+            target.active_connections.set(target.active_connections.get() + 1);
+
+            ConnectionCount(target.active_connections.clone())
+        })
+    }
+
+    pub fn report_health(&mut self, addr: SocketAddr, success: bool) {
+        let (rise, fall) = match self.config.health_check {
+            Some(ref config) => (config.rise(), config.fall()),
+            None => return,
+        };
+
+        let name = &self.name;
+        let target = match self.targets.iter_mut().find(|t| t.addr == addr) {
+            Some(target) => target,
+            None => return,
+        };
+
+        if success {
+            target.consecutive_failures = 0;
+            target.consecutive_successes += 1;
+
+            if !target.healthy && target.consecutive_successes >= rise {
+                info!("Target {} in backend {} is up", addr, name);
+                target.healthy = true;
+            }
+        } else {
+            target.consecutive_successes = 0;
+            target.consecutive_failures += 1;
+
+            if target.healthy && target.consecutive_failures >= fall {
+                warn!("Target {} in backend {} is down", addr, name);
+                target.healthy = false;
+            }
+        }
+    }
+
+    fn rebuild_hash_ring(&mut self) {
+        if self.strategy == BalancingStrategy::SourceHash {
+            self.hash_ring = build_hash_ring(&self.targets);
+        }
+    }
+
+    fn weighted_round_robin_target(&mut self, excluded: &[SocketAddr]) -> Option<usize> {
+        // Smooth weighted round-robin: every target accumulates its weight
+        // on each pick, and the chosen one pays back the total. This
+        // interleaves targets instead of sending bursts to the heaviest one.
+        let mut total_weight = 0;
+        let mut best: Option<usize> = None;
+
+        for i in 0..self.targets.len() {
+            {
+                let target = &mut self.targets[i];
+
+                if !target.is_available(excluded) {
+                    continue;
+                }
+
+                target.current_weight += target.weight as isize;
+                total_weight += target.weight as isize;
+            }
+
+            best = match best {
+                Some(b) if self.targets[b].current_weight >= self.targets[i].current_weight => {
+                    Some(b)
+                }
+                _ => Some(i),
+            };
+        }
+
+        if let Some(b) = best {
+            self.targets[b].current_weight -= total_weight;
+        }
+
+        best
+    }
+
+    fn least_connected_target(&self, excluded: &[SocketAddr]) -> Option<usize> {
+        // Start looking at the round-robin position so that ties are spread
+        // out over all targets instead of always picking the first one
+        let count = self.targets.len();
+        let mut best: Option<usize> = None;
+
+        for i in (0..count).map(|i| (self.next_target + i) % count) {
+            if !self.targets[i].is_available(excluded) {
+                continue;
+            }
+
+            best = match best {
+                Some(b) if !self.targets[i].is_less_loaded_than(&self.targets[b]) => Some(b),
+                _ => Some(i),
+            };
+        }
+
+        best
+    }
+
+    fn source_hashed_target(&self,
+                            client_ip: &IpAddr,
+                            excluded: &[SocketAddr])
+                            -> Option<usize> {
+        if self.hash_ring.is_empty() {
+            return None;
+        }
+
+        let hash = hash_of(client_ip);
+        let start = match self.hash_ring.binary_search_by(|&(h, _)| h.cmp(&hash)) {
+            Ok(i) | Err(i) => i,
+        };
+
+        // Walk clockwise past unavailable targets, so clients of a target
+        // that went down are spread over the remaining ones
+        let ring_size = self.hash_ring.len();
+
+        (0..ring_size)
+            .map(|i| self.hash_ring[(start + i) % ring_size].1)
+            .find(|&index| self.targets[index].is_available(excluded))
+    }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn build_hash_ring(targets: &[Target]) -> Vec<(u64, usize)> {
+    let mut ring = Vec::new();
+
+    for (index, target) in targets.iter().enumerate() {
+        for point in 0..(target.weight * RING_POINTS_PER_WEIGHT) {
+            ring.push((hash_of(&(target.addr, point)), index));
+        }
+    }
+
+    ring.sort();
+    ring
+}
+
+#[cfg(test)]
+mod test {
+    use super::Backend;
+
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::default::Default;
+
+    use config::{BackendConfig, BalancingStrategy, HealthCheckConfig};
+
+    fn addrs(n: u16) -> Vec<SocketAddr> {
+        (0..n).map(|i| FromStr::from_str(&format!("127.0.0.1:{}", 8000 + i)).unwrap()).collect()
+    }
+
+    fn client() -> SocketAddr {
+        FromStr::from_str("10.0.0.1:40000").unwrap()
+    }
+
+    fn backend(addrs: &[SocketAddr],
+               weights: &[usize],
+               strategy: BalancingStrategy)
+               -> Rc<RefCell<Backend>> {
+        Backend::new("test",
+                     addrs.iter().cloned().zip(weights.iter().cloned()).collect(),
+                     None,
+                     &BackendConfig { strategy: Some(strategy), ..Default::default() })
+    }
+
+    #[test]
+    fn round_robin() {
+        let targets = addrs(3);
+        let backend = backend(&targets, &[1, 1, 1], BalancingStrategy::RoundRobin);
+        let mut backend = backend.borrow_mut();
+
+        let chosen = (0..6)
+                         .map(|_| backend.decide_target(&client()).unwrap())
+                         .collect::<Vec<_>>();
+
+        assert_eq!(chosen,
+                   vec![targets[0], targets[1], targets[2], targets[0], targets[1], targets[2]]);
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let targets = addrs(3);
+        let backend = backend(&targets, &[5, 1, 1], BalancingStrategy::RoundRobin);
+        let mut backend = backend.borrow_mut();
+
+        let chosen = (0..7)
+                         .map(|_| backend.decide_target(&client()).unwrap())
+                         .collect::<Vec<_>>();
+
+        assert_eq!(chosen,
+                   vec![targets[0], targets[0], targets[1], targets[0], targets[2], targets[0],
+                        targets[0]]);
+    }
+
+    #[test]
+    fn zero_weight_targets_receive_no_traffic() {
+        let targets = addrs(2);
+        let partial = backend(&targets, &[0, 2], BalancingStrategy::RoundRobin);
+
+        for _ in 0..4 {
+            assert_eq!(partial.borrow_mut().decide_target(&client()), Some(targets[1]));
+        }
+
+        let disabled = backend(&targets, &[0, 0], BalancingStrategy::RoundRobin);
+
+        assert_eq!(disabled.borrow_mut().decide_target(&client()), None);
+    }
+
+    #[test]
+    fn least_connections() {
+        let targets = addrs(3);
+        let backend = backend(&targets, &[1, 1, 1], BalancingStrategy::LeastConnections);
+        let mut backend = backend.borrow_mut();
+
+        let first = backend.connection_opened(targets[0]).unwrap();
+        let second = backend.connection_opened(targets[0]).unwrap();
+        backend.connection_opened(targets[1]);
+
+        assert_eq!(backend.decide_target(&client()), Some(targets[2]));
+        backend.connection_opened(targets[2]);
+
+        assert_eq!(backend.decide_target(&client()), Some(targets[1]));
+        backend.connection_opened(targets[1]);
+
+        first.closed();
+        second.closed();
+
+        assert_eq!(backend.decide_target(&client()), Some(targets[0]));
+    }
+
+    #[test]
+    fn weighted_least_connections() {
+        let targets = addrs(2);
+        let backend = backend(&targets, &[3, 1], BalancingStrategy::LeastConnections);
+        let mut backend = backend.borrow_mut();
+
+        backend.connection_opened(targets[0]);
+        backend.connection_opened(targets[0]);
+
+        assert_eq!(backend.decide_target(&client()), Some(targets[1]));
+        backend.connection_opened(targets[1]);
+
+        assert_eq!(backend.decide_target(&client()), Some(targets[0]));
+    }
+
+    #[test]
+    fn source_hash_is_sticky() {
+        let targets = addrs(4);
+        let backend = backend(&targets, &[1, 1, 1, 1], BalancingStrategy::SourceHash);
+        let mut backend = backend.borrow_mut();
+
+        for i in 0..50 {
+            let first: SocketAddr = FromStr::from_str(&format!("10.0.1.{}:1000", i)).unwrap();
+            let second: SocketAddr = FromStr::from_str(&format!("10.0.1.{}:2000", i)).unwrap();
+
+            assert_eq!(backend.decide_target(&first), backend.decide_target(&second));
+        }
+    }
+
+    #[test]
+    fn source_hash_moves_few_clients() {
+        let targets = addrs(5);
+        let before = backend(&targets[..4], &[1, 1, 1, 1], BalancingStrategy::SourceHash);
+        let after = backend(&targets, &[1, 1, 1, 1, 1], BalancingStrategy::SourceHash);
+
+        let clients = (0..1000)
+                          .map(|i| {
+                              FromStr::from_str(&format!("10.{}.{}.1:1000", i / 256, i % 256))
+                                  .unwrap()
+                          })
+                          .collect::<Vec<SocketAddr>>();
+
+        let moved = clients.iter()
+                           .filter(|c| {
+                               before.borrow_mut().decide_target(c) !=
+                               after.borrow_mut().decide_target(c)
+                           })
+                           .count();
+
+        // Ideally a fifth of the clients move to the new target
+        assert!(moved > 100 && moved < 300, "{} clients moved", moved);
+    }
+
+    #[test]
+    fn unhealthy_targets_are_skipped() {
+        let targets = addrs(2);
+        let health_check = HealthCheckConfig {
+            rise: Some(2),
+            fall: Some(1),
+            ..Default::default()
+        };
+        let backend = Backend::new("test",
+                                   vec![(targets[0], 1), (targets[1], 1)],
+                                   None,
+                                   &BackendConfig {
+                                       health_check: Some(health_check),
+                                       ..Default::default()
+                                   });
+        let mut backend = backend.borrow_mut();
+
+        backend.report_health(targets[0], false);
+
+        for _ in 0..4 {
+            assert_eq!(backend.decide_target(&client()), Some(targets[1]));
+        }
+
+        backend.report_health(targets[0], true);
+        assert_eq!(backend.decide_target(&client()), Some(targets[1]));
+
+        backend.report_health(targets[0], true);
+        assert_eq!(backend.decide_target(&client()), Some(targets[0]));
+    }
+
+    #[test]
+    fn excluded_targets_are_skipped() {
+        let targets = addrs(3);
+
+        for strategy in &[BalancingStrategy::RoundRobin,
+                          BalancingStrategy::LeastConnections,
+                          BalancingStrategy::SourceHash] {
+            let backend = backend(&targets, &[1, 1, 1], *strategy);
+            let mut backend = backend.borrow_mut();
+
+            let first = backend.decide_target(&client()).unwrap();
+            let second = backend.decide_target_excluding(&client(), &[first]).unwrap();
+
+            assert!(second != first);
+            assert_eq!(backend.decide_target_excluding(&client(), &targets), None);
+        }
+    }
+
+    #[test]
+    fn drained_and_removed_targets_are_skipped() {
+        let targets = addrs(3);
+
+        for strategy in &[BalancingStrategy::RoundRobin,
+                          BalancingStrategy::LeastConnections,
+                          BalancingStrategy::SourceHash] {
+            let backend = backend(&targets[..2], &[1, 1], *strategy);
+            let mut backend = backend.borrow_mut();
+
+            assert!(backend.set_target_enabled(targets[0], false));
+            assert!(backend.add_target(targets[2], 1));
+            assert!(!backend.add_target(targets[2], 1));
+            assert!(backend.remove_target(targets[1]));
+
+            for _ in 0..6 {
+                assert_eq!(backend.decide_target(&client()), Some(targets[2]));
+            }
+
+            assert!(backend.set_target_enabled(targets[0], true));
+            assert!(backend.remove_target(targets[2]));
+            assert_eq!(backend.decide_target(&client()), Some(targets[0]));
+        }
+    }
+
+    #[test]
+    fn connections_are_closed_in_the_target_they_were_opened_to() {
+        let targets = addrs(1);
+        let backend = backend(&targets, &[1], BalancingStrategy::LeastConnections);
+        let mut backend = backend.borrow_mut();
+
+        let removed = backend.connection_opened(targets[0]).unwrap();
+        assert!(backend.remove_target(targets[0]));
+        assert!(backend.add_target(targets[0], 1));
+        let added = backend.connection_opened(targets[0]).unwrap();
+
+        removed.closed();
+        assert_eq!(backend.target_statuses()[0].active_connections, 1);
+
+        added.closed();
+        assert_eq!(backend.target_statuses()[0].active_connections, 0);
+    }
+
+    #[test]
+    fn reconfigured_backends_keep_the_state_of_their_targets() {
+        let targets = addrs(3);
+        let config = BackendConfig {
+            health_check: Some(HealthCheckConfig { fall: Some(1), ..Default::default() }),
+            ..Default::default()
+        };
+        let old = Backend::new("test", vec![(targets[0], 1), (targets[1], 1)], None, &config);
+        let new = Backend::new("test", vec![(targets[1], 1), (targets[2], 1)], None, &config);
+
+        let closed = old.borrow_mut().connection_opened(targets[1]).unwrap();
+        old.borrow_mut().connection_opened(targets[1]);
+        old.borrow_mut().set_target_enabled(targets[1], false);
+        old.borrow_mut().report_health(targets[1], false);
+
+        new.borrow_mut().carry_over(&old.borrow());
+        closed.closed();
+
+        let statuses = new.borrow().target_statuses();
 
-        target
+        assert_eq!(statuses[0].active_connections, 1);
+        assert!(!statuses[0].enabled);
+        assert!(!statuses[0].healthy);
+        assert_eq!(statuses[1].active_connections, 0);
+        assert!(statuses[1].enabled && statuses[1].healthy);
     }
 }
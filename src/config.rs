@@ -4,33 +4,166 @@ use std::io::{Read, Error as IOError};
 use std::result::Result;
 use std::default::Default;
 
-use rustc_serialize::Decodable;
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
 use toml;
 
-#[derive(Debug, RustcDecodable, Default, Clone)]
+#[derive(Debug, RustcDecodable, RustcEncodable, Default, Clone)]
 pub struct RootConfig {
     pub frontends: HashMap<String, FrontendConfig>,
     pub backends: HashMap<String, BackendConfig>,
     pub buffers: BufferConfig,
+    pub drain_timeout_ms: Option<u64>,
+    pub admin: Option<AdminConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub access_log: Option<AccessLogConfig>,
 }
 
-#[derive(Debug, RustcDecodable, Default, Clone)]
+#[derive(Debug, RustcDecodable, RustcEncodable, Default, Clone)]
 pub struct FrontendConfig {
     pub listen_addr: String,
+    pub backend: Option<String>,
+    pub backends: Vec<WeightedBackendConfig>,
+    pub connect_timeout_ms: Option<u64>,
+    pub idle_timeout_ms: Option<u64>,
+    pub max_lifetime_ms: Option<u64>,
+    pub accept_proxy_protocol: Option<bool>,
+    pub certificates: Vec<CertificateConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub mirror_backend: Option<String>,
+    pub mode: Option<FrontendMode>,
+    pub http_routes: Vec<HttpRouteConfig>,
+    pub forwarded_headers: Option<bool>,
+    pub request_headers: Option<HeaderRulesConfig>,
+    pub response_headers: Option<HeaderRulesConfig>,
+    pub websocket_idle_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone)]
+pub struct RouteConfig {
+    pub sni: String,
+    pub backend: String,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone)]
+pub struct HttpRouteConfig {
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
     pub backend: String,
 }
 
-#[derive(Debug, RustcDecodable, Default, Clone)]
+#[derive(Debug, RustcDecodable, RustcEncodable, Default, Clone)]
+pub struct HeaderRulesConfig {
+    pub remove: Vec<String>,
+    pub set: Vec<HeaderConfig>,
+    pub add: Vec<HeaderConfig>,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone)]
+pub struct HeaderConfig {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone)]
+pub struct WeightedBackendConfig {
+    pub name: String,
+    pub weight: Option<usize>,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone, PartialEq)]
+pub struct CertificateConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Default, Clone)]
 pub struct BackendConfig {
     pub target_addrs: Vec<String>,
+    pub targets: Vec<TargetConfig>,
+    pub strategy: Option<BalancingStrategy>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub connect_retries: Option<usize>,
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+    pub tls: Option<bool>,
+    pub tls_ca_path: Option<String>,
+    pub tls_server_name: Option<String>,
+    pub tls_client_certificate: Option<CertificateConfig>,
+    pub pool_max_idle: Option<usize>,
+    pub pool_idle_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone)]
+pub struct TargetConfig {
+    pub addr: String,
+    pub weight: Option<usize>,
 }
 
-#[derive(Debug, RustcDecodable, Clone)]
+#[derive(Debug, Default, Clone)]
+pub struct HealthCheckConfig {
+    pub check_type: Option<HealthCheckType>,
+    pub interval_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    pub rise: Option<usize>,
+    pub fall: Option<usize>,
+    pub path: Option<String>,
+    pub host: Option<String>,
+    pub expected_status: Option<(u16, u16)>,
+    pub body_contains: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrontendMode {
+    Tcp,
+    Http,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HealthCheckType {
+    Tcp,
+    Http,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BalancingStrategy {
+    RoundRobin,
+    LeastConnections,
+    SourceHash,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone)]
 pub struct BufferConfig {
     pub connections: usize,
     pub listeners: usize,
 }
 
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone)]
+pub struct AdminConfig {
+    pub listen_addr: String,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone)]
+pub struct MetricsConfig {
+    pub listen_addr: String,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable, Clone, PartialEq)]
+pub struct AccessLogConfig {
+    pub path: Option<String>,
+    pub format: Option<AccessLogFormat>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Debug)]
 pub enum ReadError {
     IOError(IOError),
@@ -76,6 +209,287 @@ impl From<Vec<toml::ParserError>> for ReadError {
     }
 }
 
+impl FrontendConfig {
+    pub fn all_backends(&self) -> Vec<WeightedBackendConfig> {
+        self.backend
+            .iter()
+            .map(|name| {
+                WeightedBackendConfig {
+                    name: name.clone(),
+                    weight: None,
+                }
+            })
+            .chain(self.backends.iter().cloned())
+            .collect()
+    }
+
+    pub fn mode(&self) -> FrontendMode {
+        self.mode.unwrap_or(FrontendMode::Tcp)
+    }
+}
+
+impl WeightedBackendConfig {
+    pub fn weight(&self) -> usize {
+        self.weight.unwrap_or(1)
+    }
+}
+
+impl BackendConfig {
+    pub fn all_targets(&self) -> Vec<TargetConfig> {
+        self.target_addrs
+            .iter()
+            .map(|addr| {
+                TargetConfig {
+                    addr: addr.clone(),
+                    weight: None,
+                }
+            })
+            .chain(self.targets.iter().cloned())
+            .collect()
+    }
+}
+
+impl TargetConfig {
+    pub fn weight(&self) -> usize {
+        self.weight.unwrap_or(1)
+    }
+}
+
+impl HealthCheckConfig {
+    pub fn check_type(&self) -> HealthCheckType {
+        self.check_type.unwrap_or(HealthCheckType::Tcp)
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.as_ref().map_or("/", |p| &p[..])
+    }
+
+    pub fn expected_status(&self) -> (u16, u16) {
+        self.expected_status.unwrap_or((200, 399))
+    }
+
+    pub fn interval_ms(&self) -> u64 {
+        self.interval_ms.unwrap_or(2000)
+    }
+
+    pub fn timeout_ms(&self) -> u64 {
+        self.timeout_ms.unwrap_or(1000)
+    }
+
+    pub fn rise(&self) -> usize {
+        self.rise.unwrap_or(2)
+    }
+
+    pub fn fall(&self) -> usize {
+        self.fall.unwrap_or(3)
+    }
+}
+
+impl Decodable for HealthCheckConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<HealthCheckConfig, D::Error> {
+        // Written out by hand since `type` can't be used as a field name
+        d.read_struct("HealthCheckConfig", 9, |d| {
+            Ok(HealthCheckConfig {
+                check_type: try!(d.read_struct_field("type", 0, Decodable::decode)),
+                interval_ms: try!(d.read_struct_field("interval_ms", 1, Decodable::decode)),
+                timeout_ms: try!(d.read_struct_field("timeout_ms", 2, Decodable::decode)),
+                rise: try!(d.read_struct_field("rise", 3, Decodable::decode)),
+                fall: try!(d.read_struct_field("fall", 4, Decodable::decode)),
+                path: try!(d.read_struct_field("path", 5, Decodable::decode)),
+                host: try!(d.read_struct_field("host", 6, Decodable::decode)),
+                expected_status: try!(d.read_struct_field("expected_status",
+                                                          7,
+                                                          decode_status_range)),
+                body_contains: try!(d.read_struct_field("body_contains", 8, Decodable::decode)),
+            })
+        })
+    }
+}
+
+/// Reads `[min, max]` as a list, since toml panics on tuples of the wrong
+/// length instead of returning an error
+fn decode_status_range<D: Decoder>(d: &mut D) -> Result<Option<(u16, u16)>, D::Error> {
+    let statuses: Option<Vec<u16>> = try!(Decodable::decode(d));
+
+    match statuses {
+        None => Ok(None),
+        Some(ref s) if s.len() == 2 && s[0] <= s[1] => Ok(Some((s[0], s[1]))),
+        Some(s) => {
+            Err(d.error(&format!("expected_status must be [min, max], not {:?}", s)))
+        }
+    }
+}
+
+impl Encodable for HealthCheckConfig {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_struct("HealthCheckConfig", 9, |e| {
+            try!(e.emit_struct_field("type", 0, |e| self.check_type.encode(e)));
+            try!(e.emit_struct_field("interval_ms", 1, |e| self.interval_ms.encode(e)));
+            try!(e.emit_struct_field("timeout_ms", 2, |e| self.timeout_ms.encode(e)));
+            try!(e.emit_struct_field("rise", 3, |e| self.rise.encode(e)));
+            try!(e.emit_struct_field("fall", 4, |e| self.fall.encode(e)));
+            try!(e.emit_struct_field("path", 5, |e| self.path.encode(e)));
+            try!(e.emit_struct_field("host", 6, |e| self.host.encode(e)));
+            try!(e.emit_struct_field("expected_status", 7, |e| self.expected_status.encode(e)));
+            e.emit_struct_field("body_contains", 8, |e| self.body_contains.encode(e))
+        })
+    }
+}
+
+impl AccessLogConfig {
+    pub fn format(&self) -> AccessLogFormat {
+        self.format.unwrap_or(AccessLogFormat::Text)
+    }
+
+    /// The file written to, or `-` for stdout
+    pub fn path(&self) -> &str {
+        self.path.as_ref().map_or("-", |p| &p[..])
+    }
+}
+
+impl HealthCheckType {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HealthCheckType::Tcp => "tcp",
+            HealthCheckType::Http => "http",
+        }
+    }
+}
+
+impl Decodable for HealthCheckType {
+    fn decode<D: Decoder>(d: &mut D) -> Result<HealthCheckType, D::Error> {
+        let name = try!(d.read_str());
+
+        match &name[..] {
+            "tcp" => Ok(HealthCheckType::Tcp),
+            "http" => Ok(HealthCheckType::Http),
+            _ => Err(d.error(&format!("Unknown health check type \"{}\"", name))),
+        }
+    }
+}
+
+impl Encodable for HealthCheckType {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_str(self.as_str())
+    }
+}
+
+impl BalancingStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BalancingStrategy::RoundRobin => "round_robin",
+            BalancingStrategy::LeastConnections => "least_conn",
+            BalancingStrategy::SourceHash => "source_hash",
+        }
+    }
+}
+
+impl Decodable for BalancingStrategy {
+    fn decode<D: Decoder>(d: &mut D) -> Result<BalancingStrategy, D::Error> {
+        let name = try!(d.read_str());
+
+        match &name[..] {
+            "round_robin" => Ok(BalancingStrategy::RoundRobin),
+            "least_conn" => Ok(BalancingStrategy::LeastConnections),
+            "source_hash" => Ok(BalancingStrategy::SourceHash),
+            _ => Err(d.error(&format!("Unknown balancing strategy \"{}\"", name))),
+        }
+    }
+}
+
+impl Encodable for BalancingStrategy {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_str(self.as_str())
+    }
+}
+
+impl ProxyProtocolVersion {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ProxyProtocolVersion::V1 => "v1",
+            ProxyProtocolVersion::V2 => "v2",
+        }
+    }
+}
+
+impl Decodable for ProxyProtocolVersion {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ProxyProtocolVersion, D::Error> {
+        let name = try!(d.read_str());
+
+        match &name[..] {
+            "v1" => Ok(ProxyProtocolVersion::V1),
+            "v2" => Ok(ProxyProtocolVersion::V2),
+            _ => Err(d.error(&format!("Unknown PROXY protocol version \"{}\"", name))),
+        }
+    }
+}
+
+impl Encodable for ProxyProtocolVersion {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_str(self.as_str())
+    }
+}
+
+impl FrontendMode {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FrontendMode::Tcp => "tcp",
+            FrontendMode::Http => "http",
+        }
+    }
+}
+
+impl Decodable for FrontendMode {
+    fn decode<D: Decoder>(d: &mut D) -> Result<FrontendMode, D::Error> {
+        let name = try!(d.read_str());
+
+        match &name[..] {
+            "tcp" => Ok(FrontendMode::Tcp),
+            "http" => Ok(FrontendMode::Http),
+            _ => Err(d.error(&format!("Unknown frontend mode \"{}\"", name))),
+        }
+    }
+}
+
+impl Encodable for FrontendMode {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_str(self.as_str())
+    }
+}
+
+impl AccessLogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AccessLogFormat::Text => "text",
+            AccessLogFormat::Json => "json",
+        }
+    }
+}
+
+impl Decodable for AccessLogFormat {
+    fn decode<D: Decoder>(d: &mut D) -> Result<AccessLogFormat, D::Error> {
+        let name = try!(d.read_str());
+
+        match &name[..] {
+            "text" => Ok(AccessLogFormat::Text),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(d.error(&format!("Unknown access log format \"{}\"", name))),
+        }
+    }
+}
+
+impl Encodable for AccessLogFormat {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_str(self.as_str())
+    }
+}
+
+impl Default for BalancingStrategy {
+    fn default() -> Self {
+        BalancingStrategy::RoundRobin
+    }
+}
+
 impl Default for BufferConfig {
     fn default() -> Self {
         BufferConfig {
@@ -84,3 +498,36 @@ impl Default for BufferConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::RootConfig;
+
+    fn expected_status(value: &str) -> Option<(u16, u16)> {
+        let config = RootConfig::from_str(&format!("
+[frontends]
+
+[backends.out]
+target_addrs = [\"127.0.0.1:8000\"]
+
+[backends.out.health_check]
+type = \"http\"
+expected_status = {}
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   value));
+
+        config.ok().and_then(|c| c.backends["out"].health_check.as_ref().unwrap().expected_status)
+    }
+
+    #[test]
+    fn status_ranges_are_checked_when_decoding() {
+        assert_eq!(expected_status("[200, 299]"), Some((200, 299)));
+        assert_eq!(expected_status("[200]"), None);
+        assert_eq!(expected_status("[200, 204, 299]"), None);
+        assert_eq!(expected_status("[299, 200]"), None);
+    }
+}
@@ -1,13 +1,31 @@
-use mio::{Token, EventSet, TryRead, TryWrite};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::{Duration, Instant, SystemTime};
+
+use mio::{Token, EventSet, Timeout, TryRead, TryWrite};
 use mio::tcp::TcpStream;
 
 use slab::Index;
 
+use backend::{Backend, ConnectionCount};
+use frontend::Frontend;
+use http::RequestHead;
+use http_relay::HttpRelay;
+use mirror::Mirror;
+use tls::Stream;
+
 #[derive(Debug, Copy, Clone)]
 pub enum TokenType {
     Listener(ListenerToken),
     Incoming(IncomingToken),
     Outgoing(OutgoingToken),
+    Probe(ProbeToken),
+    AdminListener,
+    MetricsListener,
+    Admin(AdminToken),
+    Pending(PendingToken),
 }
 
 #[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
@@ -19,72 +37,463 @@ pub struct IncomingToken(pub usize);
 #[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
 pub struct OutgoingToken(pub usize);
 
-type BufferArray = [u8; 4096];
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub struct ProbeToken(pub usize);
+
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub struct AdminToken(pub usize);
+
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub struct PendingToken(pub usize);
+
+pub const ADMIN_LISTENER_TOKEN: Token = Token(4);
+pub const METRICS_LISTENER_TOKEN: Token = Token(6);
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ConnectionTimeout {
+    Connect,
+    Idle,
+    Lifetime,
+    Drain,
+}
+
+/// Why a connection was closed, as reported in the access log
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum CloseReason {
+    ClientClosed,
+    ServerClosed,
+    Timeout(ConnectionTimeout),
+    Error,
+}
+
+/// Size of the buffers for each direction of a connection
+pub const BUFFER_SIZE: usize = 4096;
+
+type BufferArray = [u8; BUFFER_SIZE];
 
 pub struct Connection {
+    incoming_token: IncomingToken,
     incoming_state: EventSet,
-    incoming_stream: TcpStream,
+    incoming_stream: Stream,
     incoming_buffer: BufferArray,
     incoming_buffer_size: usize,
     incoming_total_transfer: usize,
 
     outgoing_state: EventSet,
-    outgoing_stream: TcpStream,
+    outgoing_stream: Option<Stream>,
     outgoing_token: OutgoingToken,
     outgoing_buffer: BufferArray,
     outgoing_buffer_size: usize,
     outgoing_total_transfer: usize,
+
+    frontend: Rc<Frontend>,
+    backend: Rc<RefCell<Backend>>,
+    target: SocketAddr,
+    target_count: Option<ConnectionCount>,
+    failed_targets: Vec<SocketAddr>,
+    client_addr: SocketAddr,
+    frontend_addr: SocketAddr,
+    target_transfer_start: (usize, usize),
+
+    connected: bool,
+    upgraded: bool,
+    draining: bool,
+    start_time: SystemTime,
+    last_activity: Instant,
+    timeouts: Vec<(ConnectionTimeout, Timeout)>,
+    close_reason: Option<CloseReason>,
+
+    outgoing_header: Vec<u8>,
+    outgoing_header_sent: usize,
+
+    mirror: Option<Mirror>,
+
+    http: Option<HttpRelay>,
 }
 
 impl Connection {
-    pub fn new(incoming_stream: TcpStream,
-               outgoing_stream: TcpStream,
-               outgoing_token: OutgoingToken)
+    pub fn new(incoming_token: IncomingToken,
+               incoming_stream: Stream,
+               outgoing_stream: Stream,
+               outgoing_token: OutgoingToken,
+               frontend: Rc<Frontend>,
+               backend: Rc<RefCell<Backend>>,
+               target: SocketAddr,
+               failed_targets: Vec<SocketAddr>,
+               client_addr: SocketAddr,
+               frontend_addr: SocketAddr)
                -> Connection {
+        let http = if frontend.is_http() {
+            Some(HttpRelay::new(frontend.clone(), client_addr))
+        } else {
+            None
+        };
+
         Connection {
+            incoming_token: incoming_token,
             incoming_state: EventSet::none(),
             incoming_stream: incoming_stream,
-            incoming_buffer: [0; 4096],
-            incoming_buffer_size: 4096,
+            incoming_buffer: [0; BUFFER_SIZE],
+            incoming_buffer_size: BUFFER_SIZE,
             incoming_total_transfer: 0,
 
             outgoing_state: EventSet::none(),
-            outgoing_stream: outgoing_stream,
+            outgoing_stream: Some(outgoing_stream),
             outgoing_token: outgoing_token,
-            outgoing_buffer: [0; 4096],
-            outgoing_buffer_size: 4096,
+            outgoing_buffer: [0; BUFFER_SIZE],
+            outgoing_buffer_size: BUFFER_SIZE,
             outgoing_total_transfer: 0,
+
+            frontend: frontend,
+            backend: backend,
+            target: target,
+            target_count: None,
+            failed_targets: failed_targets,
+            client_addr: client_addr,
+            frontend_addr: frontend_addr,
+            target_transfer_start: (0, 0),
+
+            connected: false,
+            upgraded: false,
+            draining: false,
+            start_time: SystemTime::now(),
+            last_activity: Instant::now(),
+            timeouts: Vec::new(),
+            close_reason: None,
+
+            outgoing_header: Vec::new(),
+            outgoing_header_sent: 0,
+
+            mirror: None,
+
+            http: http,
+        }
+    }
+
+    /// Queues data that was already read from the client, to be sent to the
+    /// target once it's connected
+    pub fn push_incoming_data(&mut self, data: &[u8]) {
+        if let Some(ref mut relay) = self.http {
+            relay.push_requests(data);
+        } else {
+            let start = self.incoming_buffer.len() - data.len();
+
+            self.incoming_buffer[start..].clone_from_slice(data);
+            self.incoming_buffer_size = start;
+        }
+
+        if let Some(ref mut mirror) = self.mirror {
+            mirror.push(data);
         }
     }
 
+    /// Sets the mirror that gets a copy of all data read from the client
+    pub fn set_mirror(&mut self, mirror: Mirror) {
+        self.mirror = Some(mirror);
+    }
+
+    pub fn mirror(&self) -> Option<&Mirror> {
+        self.mirror.as_ref()
+    }
+
+    pub fn mirror_mut(&mut self) -> Option<&mut Mirror> {
+        self.mirror.as_mut()
+    }
+
+    pub fn take_mirror(&mut self) -> Option<Mirror> {
+        self.mirror.take()
+    }
+
+    /// Sets bytes, like a PROXY protocol header, that are written to the
+    /// target before any data from the client. They are written again if
+    /// the connection is moved to another target.
+    pub fn set_outgoing_header(&mut self, header: Vec<u8>) {
+        self.outgoing_header = header;
+        self.outgoing_header_sent = 0;
+    }
+
     pub fn incoming_ready(&mut self, events: EventSet) {
         self.incoming_state.insert(events);
+        self.note_closed(events, CloseReason::ClientClosed);
     }
 
     pub fn outgoing_ready(&mut self, events: EventSet) {
         self.outgoing_state.insert(events);
+        self.note_closed(events, CloseReason::ServerClosed);
+    }
+
+    /// Remembers which side closed the connection first
+    fn note_closed(&mut self, events: EventSet, reason: CloseReason) {
+        if self.close_reason.is_none() {
+            if events.is_error() {
+                self.close_reason = Some(CloseReason::Error);
+            } else if events.is_hup() {
+                self.close_reason = Some(reason);
+            }
+        }
+    }
+
+    pub fn close_reason(&self) -> CloseReason {
+        self.close_reason.unwrap_or(CloseReason::Error)
     }
 
+    /// True if the outgoing stream went away. HTTP connections without an
+    /// outgoing stream, between requests, don't count as closed.
     pub fn is_outgoing_closed(&self) -> bool {
-        self.outgoing_state.is_error() || self.outgoing_state.is_hup()
+        match self.outgoing_stream {
+            Some(ref stream) => {
+                self.outgoing_state.is_error() || self.outgoing_state.is_hup() ||
+                stream.is_failed()
+            }
+            None => false,
+        }
     }
 
     pub fn is_incoming_closed(&self) -> bool {
-        self.incoming_state.is_error() || self.incoming_state.is_hup()
+        self.incoming_state.is_error() || self.incoming_state.is_hup() ||
+        self.incoming_stream.is_failed() ||
+        self.http.as_ref().map_or(false, |relay| relay.is_finished())
     }
 
     pub fn incoming_stream<'a>(&'a self) -> &'a TcpStream {
-        &self.incoming_stream
+        self.incoming_stream.get_ref()
+    }
+
+    pub fn outgoing_stream<'a>(&'a self) -> Option<&'a TcpStream> {
+        self.outgoing_stream.as_ref().map(|stream| stream.get_ref())
     }
 
-    pub fn outgoing_stream<'a>(&'a self) -> &'a TcpStream {
-        &self.outgoing_stream
+    pub fn has_outgoing(&self) -> bool {
+        self.outgoing_stream.is_some()
+    }
+
+    pub fn incoming_token(&self) -> IncomingToken {
+        self.incoming_token
     }
 
     pub fn outgoing_token(&self) -> OutgoingToken {
         self.outgoing_token
     }
 
+    pub fn frontend(&self) -> &Rc<Frontend> {
+        &self.frontend
+    }
+
+    pub fn backend(&self) -> &Rc<RefCell<Backend>> {
+        &self.backend
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    pub fn failed_targets(&self) -> &[SocketAddr] {
+        &self.failed_targets
+    }
+
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    pub fn frontend_addr(&self) -> SocketAddr {
+        self.frontend_addr
+    }
+
+    pub fn start_time(&self) -> SystemTime {
+        self.start_time
+    }
+
+    /// Bytes received from the client and sent on to the target
+    pub fn bytes_in(&self) -> u64 {
+        self.outgoing_total_transfer as u64
+    }
+
+    /// Bytes received from the target and sent back to the client
+    pub fn bytes_out(&self) -> u64 {
+        self.incoming_total_transfer as u64
+    }
+
+    /// Bytes in and out since the connection moved to its current target
+    pub fn target_bytes(&self) -> (u64, u64) {
+        let (bytes_in, bytes_out) = self.target_transfer_start;
+
+        ((self.outgoing_total_transfer - bytes_in) as u64,
+         (self.incoming_total_transfer - bytes_out) as u64)
+    }
+
+    /// Like `target_bytes`, but starts counting from zero again
+    pub fn take_target_bytes(&mut self) -> (u64, u64) {
+        let bytes = self.target_bytes();
+        self.target_transfer_start = (self.outgoing_total_transfer, self.incoming_total_transfer);
+
+        bytes
+    }
+
+    /// True once any byte has passed through the connection in either
+    /// direction since it moved to its current target, after which it can
+    /// no longer be moved to another one
+    pub fn has_relayed_data(&self) -> bool {
+        self.target_bytes() != (0, 0) ||
+        self.incoming_buffer_size != self.incoming_buffer.len() ||
+        self.outgoing_buffer_size != self.outgoing_buffer.len()
+    }
+
+    /// True once the outgoing stream has become writable without an error,
+    /// i.e. the connection to the target has been established
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn set_connected(&mut self) {
+        self.connected = true;
+    }
+
+    /// True if the frontend or target of this connection was removed by a
+    /// reconfiguration, and the connection is only kept until it finishes
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    pub fn set_draining(&mut self) {
+        self.draining = true;
+    }
+
+    /// True the first time it's called after an HTTP connection was
+    /// upgraded to another protocol
+    pub fn take_upgrade(&mut self) -> bool {
+        if self.upgraded || !self.http.as_ref().map_or(false, |relay| relay.is_upgraded()) {
+            return false;
+        }
+
+        self.upgraded = true;
+        true
+    }
+
+    /// The idle timeout of the frontend, or its WebSocket idle timeout
+    /// once the connection was upgraded
+    pub fn idle_timeout_ms(&self) -> Option<u64> {
+        match self.frontend.websocket_idle_timeout_ms() {
+            Some(timeout) if self.upgraded => Some(timeout),
+            _ => self.frontend.idle_timeout_ms(),
+        }
+    }
+
+    /// Time since data was last relayed in either direction
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    pub fn set_timeout(&mut self, kind: ConnectionTimeout, timeout: Timeout) {
+        self.timeouts.push((kind, timeout));
+    }
+
+    pub fn take_timeout(&mut self, kind: ConnectionTimeout) -> Option<Timeout> {
+        match self.timeouts.iter().position(|&(k, _)| k == kind) {
+            Some(i) => Some(self.timeouts.swap_remove(i).1),
+            None => None,
+        }
+    }
+
+    pub fn take_timeouts(&mut self) -> Vec<Timeout> {
+        self.timeouts.drain(..).map(|(_, timeout)| timeout).collect()
+    }
+
+    /// Swaps in a new outgoing stream after the connection to the current
+    /// target failed. Returns the old stream so it can be deregistered.
+    pub fn replace_outgoing(&mut self,
+                            outgoing_stream: Stream,
+                            target: SocketAddr,
+                            failed_targets: Vec<SocketAddr>)
+                            -> Option<Stream> {
+        let backend = self.backend.clone();
+        let old_stream = self.take_outgoing();
+        self.set_outgoing(outgoing_stream, backend, target, failed_targets);
+
+        old_stream
+    }
+
+    /// Counts the connection in its current target, instead of the one it
+    /// was counted in before
+    pub fn count_in_target(&mut self, count: Option<ConnectionCount>) {
+        self.uncount_target();
+        self.target_count = count;
+    }
+
+    /// Takes the connection off the count of its target, once the outgoing
+    /// stream is closed
+    pub fn uncount_target(&mut self) {
+        if let Some(count) = self.target_count.take() {
+            count.closed();
+        }
+    }
+
+    /// Detaches the outgoing stream from an HTTP connection between
+    /// requests, so that it can be closed while the client stays connected
+    pub fn take_outgoing(&mut self) -> Option<Stream> {
+        self.outgoing_state = EventSet::none();
+        self.connected = false;
+        self.close_reason = None;
+
+        self.outgoing_stream.take()
+    }
+
+    /// Attaches an outgoing stream to a target of `backend`
+    pub fn set_outgoing(&mut self,
+                        outgoing_stream: Stream,
+                        backend: Rc<RefCell<Backend>>,
+                        target: SocketAddr,
+                        failed_targets: Vec<SocketAddr>) {
+        self.outgoing_state = EventSet::none();
+        self.connected = false;
+        self.close_reason = None;
+        self.outgoing_header_sent = 0;
+        self.backend = backend;
+        self.target = target;
+        self.failed_targets = failed_targets;
+        self.outgoing_stream = Some(outgoing_stream);
+    }
+
+    /// The next request on an HTTP connection, if it is waiting for a
+    /// target to be picked
+    pub fn waiting_request(&self) -> Option<&RequestHead> {
+        self.http.as_ref().and_then(|relay| relay.waiting_request())
+    }
+
+    /// True if the next request may be sent over the current outgoing
+    /// stream
+    pub fn can_reuse_outgoing(&self) -> bool {
+        self.outgoing_stream.is_some() && !self.is_outgoing_closed() &&
+        self.http.as_ref().map_or(false, |relay| relay.is_reusable())
+    }
+
+    /// True if the outgoing stream can be closed without cutting off a
+    /// request or response
+    pub fn can_release_outgoing(&self) -> bool {
+        self.outgoing_stream.is_some() &&
+        self.http.as_ref().map_or(false, |relay| relay.is_between_requests())
+    }
+
+    /// Sends the waiting request once the outgoing stream for it is set,
+    /// with the headers of the running `frontend` it was routed by
+    pub fn dispatch_request(&mut self, frontend: Rc<Frontend>) {
+        if let Some(ref mut relay) = self.http {
+            relay.dispatch(frontend);
+        }
+    }
+
+    /// Answers the waiting request with an error and closes the connection
+    pub fn reject_request(&mut self, status: u16, reason: &str) {
+        if let Some(ref mut relay) = self.http {
+            relay.reject(status, reason);
+        }
+    }
+
+    /// Answers a request its target closed the outgoing stream on without
+    /// responding. Returns true if there was one.
+    pub fn fail_unanswered_request(&mut self) -> bool {
+        self.http.as_mut().map_or(false, |relay| relay.fail_unanswered())
+    }
+
     pub fn tick(&mut self) -> bool {
         trace!("Connection in state [incoming {:?}] [outgoing {:?}]",
                self.incoming_state,
@@ -93,57 +502,235 @@ impl Connection {
         let mut data_sent = false;
         let mut could_send = false;
 
-        if self.incoming_buffer.len() != self.incoming_buffer_size &&
-           self.outgoing_state.is_writable() {
-            could_send = true;
-            data_sent |= flush_buffer(&mut self.incoming_buffer,
-                                      &mut self.incoming_buffer_size,
-                                      &mut self.outgoing_stream,
-                                      &mut self.outgoing_total_transfer);
-            self.outgoing_state.remove(EventSet::writable());
+        // Client data is held back until the header has been written. It
+        // goes straight to the socket, ahead of any TLS handshake.
+        let header_pending = self.outgoing_header_sent < self.outgoing_header.len();
+
+        if let Some(ref mut outgoing_stream) = self.outgoing_stream {
+            if header_pending && self.outgoing_state.is_writable() {
+                could_send = true;
+                data_sent |= write_header(&self.outgoing_header,
+                                          &mut self.outgoing_header_sent,
+                                          outgoing_stream.get_mut());
+                self.outgoing_state.remove(EventSet::writable());
+            }
         }
 
-        if self.outgoing_buffer.len() != self.outgoing_buffer_size &&
-           self.incoming_state.is_writable() {
-            could_send = true;
-            data_sent |= flush_buffer(&mut self.outgoing_buffer,
+        // TLS handshakes have to make progress even while there is no data
+        // to relay in either direction
+        if !continue_handshake(&mut self.incoming_stream, &mut self.incoming_state) {
+            return false;
+        }
+
+        if let Some(ref mut outgoing_stream) = self.outgoing_stream {
+            if !header_pending &&
+               !continue_handshake(outgoing_stream, &mut self.outgoing_state) {
+                return false;
+            }
+        }
+
+        if self.http.is_some() {
+            if header_pending {
+                self.outgoing_state.remove(EventSet::writable());
+            }
+
+            return self.tick_http(could_send, data_sent);
+        }
+
+        {
+            let outgoing_stream = self.outgoing_stream
+                                      .as_mut()
+                                      .expect("TCP connection without outgoing stream");
+
+            if self.incoming_buffer.len() != self.incoming_buffer_size &&
+               self.outgoing_state.is_writable() {
+                could_send = true;
+                data_sent |= flush_buffer(&mut self.incoming_buffer,
+                                          &mut self.incoming_buffer_size,
+                                          outgoing_stream,
+                                          &mut self.outgoing_total_transfer);
+                self.outgoing_state.remove(EventSet::writable());
+            }
+
+            if self.outgoing_buffer.len() != self.outgoing_buffer_size &&
+               self.incoming_state.is_writable() {
+                could_send = true;
+                data_sent |= flush_buffer(&mut self.outgoing_buffer,
+                                          &mut self.outgoing_buffer_size,
+                                          &mut self.incoming_stream,
+                                          &mut self.incoming_total_transfer);
+                self.incoming_state.remove(EventSet::writable());
+            }
+
+            if self.outgoing_state.is_writable() && self.incoming_state.is_readable() {
+                could_send = true;
+                data_sent |= transfer(&mut self.incoming_buffer,
+                                      &mut self.incoming_buffer_size,
+                                      &mut self.incoming_stream,
+                                      outgoing_stream,
+                                      &mut self.outgoing_total_transfer,
+                                      self.mirror.as_mut());
+                self.incoming_state.remove(EventSet::readable());
+                self.outgoing_state.remove(EventSet::writable());
+            }
+
+            if self.incoming_state.is_writable() && self.outgoing_state.is_readable() {
+                could_send = true;
+                data_sent |= transfer(&mut self.outgoing_buffer,
                                       &mut self.outgoing_buffer_size,
+                                      outgoing_stream,
                                       &mut self.incoming_stream,
+                                      &mut self.incoming_total_transfer,
+                                      None);
+                self.incoming_state.remove(EventSet::writable());
+                self.outgoing_state.remove(EventSet::readable());
+            }
+        }
+
+        self.finish_tick(could_send, data_sent)
+    }
+
+    /// Relays data on HTTP frontends, where messages are read whole enough
+    /// to rewrite their heads before being passed on
+    fn tick_http(&mut self, mut could_send: bool, mut data_sent: bool) -> bool {
+        {
+            let relay = self.http.as_mut().expect("Connection is not HTTP");
+
+            if self.incoming_state.is_readable() && relay.wants_requests() {
+                could_send = true;
+                data_sent |= read_ok(relay.read_requests(&mut self.incoming_stream,
+                                                         self.mirror.as_mut()));
+                self.incoming_state.remove(EventSet::readable());
+            }
+
+            if let Some(ref mut outgoing_stream) = self.outgoing_stream {
+                if relay.has_requests() && self.outgoing_state.is_writable() {
+                    could_send = true;
+                    data_sent |= write_ok(relay.write_requests(outgoing_stream),
+                                          &mut self.outgoing_total_transfer);
+                    self.outgoing_state.remove(EventSet::writable());
+                }
+
+                if self.outgoing_state.is_readable() && relay.wants_responses() {
+                    could_send = true;
+                    data_sent |= read_ok(relay.read_responses(outgoing_stream));
+                    self.outgoing_state.remove(EventSet::readable());
+                }
+            }
+
+            if relay.has_responses() && self.incoming_state.is_writable() {
+                could_send = true;
+                data_sent |= write_ok(relay.write_responses(&mut self.incoming_stream),
                                       &mut self.incoming_total_transfer);
-            self.incoming_state.remove(EventSet::writable());
+                self.incoming_state.remove(EventSet::writable());
+            }
+
+            if relay.is_finished() {
+                return false;
+            }
+        }
+
+        self.finish_tick(could_send, data_sent)
+    }
+
+    fn finish_tick(&mut self, could_send: bool, data_sent: bool) -> bool {
+        // Decrypted data left in a TLS session has to be read on the next
+        // tick, the socket won't become readable for it again
+        if self.incoming_stream.has_pending() {
+            self.incoming_state.insert(EventSet::readable());
         }
 
-        if self.outgoing_state.is_writable() && self.incoming_state.is_readable() {
-            could_send = true;
-            data_sent |= transfer(&mut self.incoming_buffer,
-                                  &mut self.incoming_buffer_size,
-                                  &mut self.incoming_stream,
-                                  &mut self.outgoing_stream,
-                                  &mut self.outgoing_total_transfer);
-            self.incoming_state.remove(EventSet::readable());
-            self.outgoing_state.remove(EventSet::writable());
+        if self.outgoing_stream.as_ref().map_or(false, |stream| stream.has_pending()) {
+            self.outgoing_state.insert(EventSet::readable());
         }
 
-        if self.incoming_state.is_writable() && self.outgoing_state.is_readable() {
-            could_send = true;
-            data_sent |= transfer(&mut self.outgoing_buffer,
-                                  &mut self.outgoing_buffer_size,
-                                  &mut self.outgoing_stream,
-                                  &mut self.incoming_stream,
-                                  &mut self.incoming_total_transfer);
-            self.incoming_state.remove(EventSet::writable());
-            self.outgoing_state.remove(EventSet::readable());
+        if data_sent {
+            self.last_activity = Instant::now();
         }
 
         !could_send || data_sent
     }
 }
 
-fn flush_buffer(buf: &BufferArray,
-                buf_size: &mut usize,
-                dest: &mut TcpStream,
-                total: &mut usize)
-                -> bool {
+/// Logs the result of reading from one side of an HTTP connection.
+/// Returns true if anything was read.
+fn read_ok(result: io::Result<Option<usize>>) -> bool {
+    match result {
+        Ok(Some(n_read)) => {
+            trace!("Read {} bytes", n_read);
+            n_read > 0
+        }
+        Ok(None) => {
+            trace!("Reading would block");
+            false
+        }
+        Err(e) => {
+            error!("Reading caused error: {}", e);
+            false
+        }
+    }
+}
+
+fn write_ok(result: io::Result<Option<usize>>, total: &mut usize) -> bool {
+    match result {
+        Ok(Some(n_written)) => {
+            *total += n_written;
+            trace!("Wrote {} bytes, total {}", n_written, *total);
+
+            n_written > 0
+        }
+        Ok(None) => {
+            trace!("Writing would block");
+            false
+        }
+        Err(e) => {
+            error!("Writing caused error: {}", e);
+            false
+        }
+    }
+}
+
+/// Continues a TLS handshake when the socket is ready. Returns false if the
+/// handshake failed.
+fn continue_handshake(stream: &mut Stream, state: &mut EventSet) -> bool {
+    if !stream.is_handshaking() || !(state.is_readable() || state.is_writable()) {
+        return true;
+    }
+
+    match stream.handshake() {
+        Ok(()) => true,
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+            state.remove(EventSet::readable() | EventSet::writable());
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn write_header(header: &[u8], sent: &mut usize, dest: &mut TcpStream) -> bool {
+    match dest.try_write(&header[*sent..]) {
+        Ok(Some(n_written)) => {
+            trace!("Wrote {} header bytes", n_written);
+            *sent += n_written;
+
+            n_written > 0
+        }
+        Ok(None) => {
+            trace!("Writing header would block");
+            false
+        }
+        Err(e) => {
+            error!("Writing header caused error: {}", e);
+            false
+        }
+    }
+}
+
+fn flush_buffer<W: TryWrite>(buf: &BufferArray,
+                             buf_size: &mut usize,
+                             dest: &mut W,
+                             total: &mut usize)
+                             -> bool {
     let start_index = *buf_size;
     let bytes_to_write = buf.len() - start_index;
 
@@ -154,9 +741,7 @@ fn flush_buffer(buf: &BufferArray,
             *total += n_written;
             trace!("Flushed {} bytes, total {}", n_written, *total);
 
-            assert!(bytes_to_write == n_written, "Must flush entire buffer");
-
-            *buf_size = buf.len();
+            *buf_size += n_written;
 
             return n_written > 0;
         }
@@ -171,31 +756,35 @@ fn flush_buffer(buf: &BufferArray,
     return false;
 }
 
-fn transfer(buf: &mut BufferArray,
-            buf_size: &mut usize,
-            src: &mut TcpStream,
-            dest: &mut TcpStream,
-            total: &mut usize)
-            -> bool {
+/// Reads from `src` and writes to `dest`, copying what was read to
+/// `mirror` if there is one
+fn transfer<R: TryRead, W: TryWrite>(buf: &mut BufferArray,
+                                     buf_size: &mut usize,
+                                     src: &mut R,
+                                     dest: &mut W,
+                                     total: &mut usize,
+                                     mirror: Option<&mut Mirror>)
+                                     -> bool {
     match src.try_read(buf) {
         Ok(Some(n_read)) => {
             trace!("Read {} bytes", n_read);
 
+            if let Some(mirror) = mirror {
+                mirror.push(&buf[0..n_read]);
+            }
+
             match dest.try_write(&buf[0..n_read]) {
                 Ok(Some(n_written)) => {
                     *total += n_written;
                     trace!("Wrote {} bytes, total {}", n_written, *total);
 
-                    if n_written < n_read {
-                        *buf_size = buf.len() - (n_read - n_written);
-                    } else {
-                        *buf_size = buf.len();
-                    }
+                    keep_unwritten(buf, buf_size, n_written, n_read);
 
                     return n_written > 0;
                 }
                 Ok(None) => {
                     trace!("Writing would block");
+                    keep_unwritten(buf, buf_size, 0, n_read);
                 }
                 Err(e) => {
                     error!("Writing caused error: {}", e);
@@ -213,15 +802,47 @@ fn transfer(buf: &mut BufferArray,
     return false;
 }
 
+/// Moves the bytes in `buf[written..read]` that could not be written to the
+/// end of the buffer, where `flush_buffer` picks them up
+fn keep_unwritten(buf: &mut BufferArray, buf_size: &mut usize, written: usize, read: usize) {
+    let unwritten = read - written;
+    let start = buf.len() - unwritten;
+
+    for i in (0..unwritten).rev() {
+        buf[start + i] = buf[written + i];
+    }
+
+    *buf_size = start;
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CloseReason::ClientClosed => "client_close",
+            CloseReason::ServerClosed => "server_close",
+            CloseReason::Timeout(ConnectionTimeout::Connect) => "connect_timeout",
+            CloseReason::Timeout(ConnectionTimeout::Idle) => "idle_timeout",
+            CloseReason::Timeout(ConnectionTimeout::Lifetime) => "lifetime_timeout",
+            CloseReason::Timeout(ConnectionTimeout::Drain) => "drain_timeout",
+            CloseReason::Error => "error",
+        }
+    }
+}
+
 impl TokenType {
     pub fn from_raw_token(t: Token) -> TokenType {
         let i = t.as_usize();
 
-        match i & 3 {
-            0 => TokenType::Listener(ListenerToken(i >> 2)),
-            1 => TokenType::Incoming(IncomingToken(i >> 2)),
-            2 => TokenType::Outgoing(OutgoingToken(i >> 2)),
-            _ => unreachable!(),
+        match i & 7 {
+            0 => TokenType::Listener(ListenerToken(i >> 3)),
+            1 => TokenType::Incoming(IncomingToken(i >> 3)),
+            2 => TokenType::Outgoing(OutgoingToken(i >> 3)),
+            3 => TokenType::Probe(ProbeToken(i >> 3)),
+            4 => TokenType::AdminListener,
+            5 => TokenType::Admin(AdminToken(i >> 3)),
+            6 => TokenType::MetricsListener,
+            7 => TokenType::Pending(PendingToken(i >> 3)),
+            _ => panic!("Unknown token type in {:?}", t),
         }
     }
 }
@@ -229,19 +850,37 @@ impl TokenType {
 
 impl ListenerToken {
     pub fn as_raw_token(self) -> Token {
-        Token(self.0 << 2)
+        Token(self.0 << 3)
     }
 }
 
 impl IncomingToken {
     pub fn as_raw_token(self) -> Token {
-        Token((self.0 << 2) + 1)
+        Token((self.0 << 3) + 1)
     }
 }
 
 impl OutgoingToken {
     pub fn as_raw_token(self) -> Token {
-        Token((self.0 << 2) + 2)
+        Token((self.0 << 3) + 2)
+    }
+}
+
+impl ProbeToken {
+    pub fn as_raw_token(self) -> Token {
+        Token((self.0 << 3) + 3)
+    }
+}
+
+impl AdminToken {
+    pub fn as_raw_token(self) -> Token {
+        Token((self.0 << 3) + 5)
+    }
+}
+
+impl PendingToken {
+    pub fn as_raw_token(self) -> Token {
+        Token((self.0 << 3) + 7)
     }
 }
 
@@ -274,3 +913,33 @@ impl Index for OutgoingToken {
         self.0
     }
 }
+
+impl Index for ProbeToken {
+    fn from_usize(i: usize) -> ProbeToken {
+        ProbeToken(i)
+    }
+
+    fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+impl Index for AdminToken {
+    fn from_usize(i: usize) -> AdminToken {
+        AdminToken(i)
+    }
+
+    fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+impl Index for PendingToken {
+    fn from_usize(i: usize) -> PendingToken {
+        PendingToken(i)
+    }
+
+    fn as_usize(&self) -> usize {
+        self.0
+    }
+}
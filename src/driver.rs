@@ -1,4 +1,8 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
 
 use mio;
 use mio::{Token, Handler, EventSet, PollOpt};
@@ -6,9 +10,25 @@ use mio::tcp::TcpStream;
 
 use slab::Slab;
 
-use config::RootConfig;
-use connection::{TokenType, ListenerToken, IncomingToken, OutgoingToken, Connection};
-use driver_state::DriverState;
+use admin;
+use admin::{AdminConnection, AdminEvent, AdminRequest, AdminResponse, AdminRoute, FrontendStatus,
+            BackendStatus, ConnectionStatus};
+use backend::Backend;
+use config::{RootConfig, HealthCheckConfig, TargetConfig};
+use connection::{TokenType, ListenerToken, IncomingToken, OutgoingToken, ProbeToken, AdminToken,
+                 PendingToken, Connection, ConnectionTimeout, CloseReason, ADMIN_LISTENER_TOKEN,
+                 METRICS_LISTENER_TOKEN};
+use driver_state::{DriverState, ReconfigureError};
+use frontend::Frontend;
+use health_check::Probe;
+use pending;
+use pending::PendingConnection;
+use pool::{IdleStream, Pool};
+use proxy_protocol;
+use tls::Stream;
+use metrics::{Metrics, FrontendSample, TargetSample, SlabSample};
+use mirror::Mirror;
+use reload::Reloader;
 
 type EventLoop = mio::EventLoop<Driver>;
 
@@ -16,413 +36,4006 @@ pub struct Driver {
     to_reregister: HashSet<IncomingToken>,
     incoming_connections: Slab<Connection, IncomingToken>,
     outgoing_connections: Slab<Option<IncomingToken>, OutgoingToken>,
+    mirror_connections: Slab<IncomingToken, OutgoingToken>,
+    idle_connections: Slab<(), OutgoingToken>,
+    probes: Slab<Probe, ProbeToken>,
+    admin_connections: Slab<AdminConnection, AdminToken>,
+    pending_connections: Slab<PendingConnection, PendingToken>,
+    pool: Pool,
+    metrics: Metrics,
+    reloader: Option<Reloader>,
     state: DriverState,
 }
 
 pub enum DriverMessage {
     Shutdown,
     Reconfigure(RootConfig),
+    Reload,
+    AddTarget {
+        backend: String,
+        target: TargetConfig,
+    },
+    RemoveTarget {
+        backend: String,
+        addr: String,
+    },
+    SetTargetEnabled {
+        backend: String,
+        addr: String,
+        enabled: bool,
+    },
+}
+
+pub enum DriverTimeout {
+    HealthCheck {
+        backend: String,
+        generation: usize,
+    },
+    Probe(ProbeToken),
+    Connection(IncomingToken, ConnectionTimeout),
+    Pending(PendingToken),
+    Pooled(OutgoingToken),
 }
 
 impl Driver {
     pub fn new(state: DriverState) -> Driver {
+        let connections = state.config.buffers.connections;
+
         Driver {
             to_reregister: HashSet::new(),
-            incoming_connections: Slab::new_starting_at(IncomingToken(1),
-                                                        state.config.buffers.connections),
-            outgoing_connections: Slab::new_starting_at(OutgoingToken(1),
-                                                        state.config.buffers.connections),
+            incoming_connections: Slab::new_starting_at(IncomingToken(1), connections),
+            outgoing_connections: Slab::new_starting_at(OutgoingToken(1), connections),
+            // Mirrors get the tokens after those of the targets, so they
+            // never take the place of a client's connection
+            mirror_connections: Slab::new_starting_at(OutgoingToken(connections + 1),
+                                                      connections),
+            // The pool can hold as many idle connections as there are clients
+            idle_connections: Slab::new_starting_at(OutgoingToken(2 * connections + 1),
+                                                    connections),
+            probes: Slab::new_starting_at(ProbeToken(1), connections),
+            admin_connections: Slab::new_starting_at(AdminToken(1), admin::MAX_CONNECTIONS),
+            pending_connections: Slab::new_starting_at(PendingToken(1), connections),
+            pool: Pool::new(),
+            metrics: Metrics::new(),
+            reloader: None,
             state: state,
         }
     }
 
+    /// Lets `POST /reload` hand reloads to `reloader`. Without one, there
+    /// is no configuration file to reload and the request fails.
+    pub fn set_reloader(&mut self, reloader: Reloader) {
+        self.reloader = Some(reloader);
+    }
+
     fn listener_ready(&mut self,
                       event_loop: &mut EventLoop,
                       token: ListenerToken,
                       events: EventSet) {
         assert!(events.is_readable());
 
-        if let Some(listener) = self.state.listeners.get(token) {
-            info!("Accepting connection");
+        let (incoming, frontend, frontend_addr) = match self.state.listeners.get(token) {
+            Some(listener) => {
+                info!("Accepting connection");
 
-            let incoming = match listener.listener.accept() {
-                Ok(Some(client)) => client,
-                Ok(None) => {
-                    warn!("Accept would block");
-                    return;
+                let accepted = listener.listener.accept();
+
+                event_loop.reregister(&listener.listener,
+                                      token.as_raw_token(),
+                                      EventSet::readable(),
+                                      PollOpt::edge() | PollOpt::oneshot())
+                          .unwrap();
+
+                let incoming = match accepted {
+                    Ok(Some(client)) => client,
+                    Ok(None) => {
+                        warn!("Accept would block");
+                        return;
+                    }
+                    Err(e) => {
+                        error!("Accept error: {}", e);
+                        return;
+                    }
+                };
+
+                let frontend_addr = incoming.local_addr().unwrap_or(listener.listen_addr);
+
+                (incoming, listener.frontend.clone(), frontend_addr)
+            }
+            None => {
+                error!("Listener event on unknown token {:?}", token);
+                return;
+            }
+        };
+
+        let client_addr = match incoming.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Could not get peer address of incoming connection: {}", e);
+                return;
+            }
+        };
+
+        self.metrics.connection_accepted(frontend.name());
+
+        if frontend.accept_proxy_protocol() || frontend.has_routes() || frontend.is_http() {
+            self.wait_for_client_data(event_loop, incoming, frontend, client_addr, frontend_addr);
+            return;
+        }
+
+        let incoming = match frontend.tls() {
+            Some(acceptor) => {
+                match Stream::accept(acceptor, incoming, Vec::new()) {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        warn!("Closing TLS connection from {}: {}", client_addr, e);
+                        return;
+                    }
+                }
+            }
+            None => Stream::plain(incoming),
+        };
+        let backend = frontend.decide_backend(None, None);
+
+        self.open_connection(event_loop,
+                             incoming,
+                             frontend,
+                             backend,
+                             client_addr,
+                             frontend_addr,
+                             &[]);
+    }
+
+    /// Connects an accepted client to a target of `backend`. `data` was
+    /// already read from the client and is sent to the target first.
+    fn open_connection(&mut self,
+                       event_loop: &mut EventLoop,
+                       incoming: Stream,
+                       frontend: Rc<Frontend>,
+                       backend: Rc<RefCell<Backend>>,
+                       client_addr: SocketAddr,
+                       frontend_addr: SocketAddr,
+                       data: &[u8]) {
+        // Tokens are taken before connecting, so that full buffers only
+        // close the client
+        if !self.incoming_connections.has_remaining() {
+            error!("Incoming buffer full, closing connection from {}", client_addr);
+            return;
+        }
+
+        let outgoing_token = match self.outgoing_connections.insert(None) {
+            Ok(outgoing_token) => outgoing_token,
+            Err(_) => {
+                error!("Outgoing buffer full, closing connection from {}", client_addr);
+                return;
+            }
+        };
+
+        let mut failed_targets = Vec::new();
+
+        let connected = if frontend.is_http() {
+            let target = backend.borrow_mut().decide_target(&client_addr);
+
+            self.connect_for_request(event_loop,
+                                     &backend,
+                                     target,
+                                     &client_addr,
+                                     &mut failed_targets)
+        } else {
+            let connected = connect_to_backend(&backend,
+                                               &client_addr,
+                                               &mut failed_targets,
+                                               &mut self.metrics);
+
+            if let Some((_, target)) = connected {
+                self.metrics.connection_opened(backend.borrow().name(), target);
+            }
+
+            connected
+        };
+
+        let (outgoing, target) = match connected {
+            Some(connected) => connected,
+            None => {
+                self.outgoing_connections.remove(outgoing_token);
+                return;
+            }
+        };
+
+        let target_count = backend.borrow_mut().connection_opened(target);
+
+        let incoming_token = self.incoming_connections
+                                 .insert_with(|token| {
+                                     Connection::new(token,
+                                                     incoming,
+                                                     outgoing,
+                                                     outgoing_token,
+                                                     frontend.clone(),
+                                                     backend,
+                                                     target,
+                                                     failed_targets,
+                                                     client_addr,
+                                                     frontend_addr)
+                                 })
+                                 .expect("Incoming buffer full");
+
+        self.outgoing_connections[outgoing_token] = Some(incoming_token);
+
+        if let Some(mirror_backend) = frontend.mirror_backend() {
+            self.open_mirror(event_loop,
+                             incoming_token,
+                             mirror_backend.clone(),
+                             client_addr,
+                             frontend_addr);
+        }
+
+        let connection = self.incoming_connections.get_mut(incoming_token).unwrap();
+
+        connection.count_in_target(target_count);
+
+        let send_proxy_protocol = connection.backend().borrow().send_proxy_protocol();
+
+        if let Some(version) = send_proxy_protocol {
+            connection.set_outgoing_header(proxy_protocol::header(version,
+                                                                  client_addr,
+                                                                  frontend_addr));
+        }
+
+        if !data.is_empty() {
+            connection.push_incoming_data(data);
+        }
+
+        let timeouts = [(ConnectionTimeout::Connect, frontend.connect_timeout_ms()),
+                        (ConnectionTimeout::Idle, frontend.idle_timeout_ms()),
+                        (ConnectionTimeout::Lifetime, frontend.max_lifetime_ms())];
+
+        for &(kind, delay) in timeouts.iter() {
+            if let Some(delay) = delay {
+                schedule_timeout(event_loop, connection, incoming_token, kind, delay);
+            }
+        }
+
+        event_loop.register_opt(connection.incoming_stream(),
+                                incoming_token.as_raw_token(),
+                                EventSet::all(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+        event_loop.register_opt(connection.outgoing_stream().unwrap(),
+                                outgoing_token.as_raw_token(),
+                                EventSet::all(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+    }
+
+    /// Connects to a target of the frontend's mirror backend. Failing to do
+    /// so is only logged, and never affects the client connection.
+    fn open_mirror(&mut self,
+                   event_loop: &mut EventLoop,
+                   token: IncomingToken,
+                   backend: Rc<RefCell<Backend>>,
+                   client_addr: SocketAddr,
+                   frontend_addr: SocketAddr) {
+        let mut failed_targets = Vec::new();
+
+        let (stream, target) = match connect_to_backend(&backend,
+                                                        &client_addr,
+                                                        &mut failed_targets,
+                                                        &mut self.metrics) {
+            Some(connected) => connected,
+            None => {
+                warn!("Could not connect to mirror backend {}", backend.borrow().name());
+                return;
+            }
+        };
+
+        let mirror_token = match self.mirror_connections.insert(token) {
+            Ok(mirror_token) => mirror_token,
+            Err(_) => {
+                warn!("Mirror buffer full, not mirroring connection from {}", client_addr);
+                return;
+            }
+        };
+
+        let target_count = backend.borrow_mut().connection_opened(target);
+        self.metrics.connection_opened(backend.borrow().name(), target);
+
+        let header = match backend.borrow().send_proxy_protocol() {
+            Some(version) => proxy_protocol::header(version, client_addr, frontend_addr),
+            None => Vec::new(),
+        };
+
+        let mirror = Mirror::new(stream, mirror_token, target, target_count, header);
+
+        event_loop.register_opt(mirror.stream(),
+                                mirror_token.as_raw_token(),
+                                mirror.interest(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+
+        self.incoming_connections[token].set_mirror(mirror);
+    }
+
+    fn mirror_ready(&mut self,
+                    event_loop: &mut EventLoop,
+                    token: IncomingToken,
+                    events: EventSet) {
+        let closed = match self.incoming_connections.get_mut(token).and_then(|c| c.mirror_mut()) {
+            Some(mirror) => {
+                mirror.ready(events);
+
+                if !mirror.is_closed() {
+                    event_loop.reregister(mirror.stream(),
+                                          mirror.token().as_raw_token(),
+                                          mirror.interest(),
+                                          PollOpt::edge() | PollOpt::oneshot())
+                              .unwrap();
+                }
+
+                mirror.is_closed()
+            }
+            None => false,
+        };
+
+        if closed {
+            self.close_mirror(event_loop, token);
+        }
+    }
+
+    fn close_mirror(&mut self, event_loop: &mut EventLoop, token: IncomingToken) {
+        let connection = match self.incoming_connections.get_mut(token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        if let Some(mut mirror) = connection.take_mirror() {
+            debug!("Closing mirror to {}", mirror.target());
+
+            event_loop.deregister(mirror.stream()).unwrap_or(());
+            self.mirror_connections
+                .remove(mirror.token())
+                .expect("Can't remove already removed mirror connection");
+
+            mirror.uncount_target();
+            self.metrics.mirror_closed(connection.frontend().name(),
+                                       mirror.bytes_sent(),
+                                       mirror.bytes_dropped());
+        }
+    }
+
+    /// Reads the PROXY header, ClientHello or first request from a client
+    /// before a target is picked
+    fn wait_for_client_data(&mut self,
+                            event_loop: &mut EventLoop,
+                            incoming: TcpStream,
+                            frontend: Rc<Frontend>,
+                            client_addr: SocketAddr,
+                            frontend_addr: SocketAddr) {
+        let pending = PendingConnection::new(incoming, frontend, client_addr, frontend_addr);
+
+        let token = match self.pending_connections.insert(pending) {
+            Ok(token) => token,
+            Err(_) => {
+                warn!("Pending connection buffer full, dropping connection from {}",
+                      client_addr);
+                return;
+            }
+        };
+
+        event_loop.register_opt(self.pending_connections[token].stream(),
+                                token.as_raw_token(),
+                                EventSet::readable() | EventSet::error() | EventSet::hup(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+
+        match event_loop.timeout_ms(DriverTimeout::Pending(token), pending::TIMEOUT_MS) {
+            Ok(timeout) => self.pending_connections[token].set_timeout(timeout),
+            Err(e) => error!("Could not schedule pending connection timeout: {:?}", e),
+        }
+    }
+
+    fn pending_ready(&mut self, event_loop: &mut EventLoop, token: PendingToken) {
+        let result = match self.pending_connections.get_mut(token) {
+            Some(pending) => pending.ready(),
+            None => {
+                warn!("Could not find pending connection for {:?}", token);
+                return;
+            }
+        };
+
+        match result {
+            Ok(false) => {
+                event_loop.reregister(self.pending_connections[token].stream(),
+                                      token.as_raw_token(),
+                                      EventSet::readable() | EventSet::error() | EventSet::hup(),
+                                      PollOpt::edge() | PollOpt::oneshot())
+                          .unwrap();
+            }
+            Ok(true) => {
+                let pending = self.remove_pending(event_loop, token);
+                let peer_addr = pending.peer_addr();
+
+                match pending.finish() {
+                    Ok((incoming, frontend, backend, client_addr, frontend_addr, data)) => {
+                        self.open_connection(event_loop,
+                                             incoming,
+                                             frontend,
+                                             backend,
+                                             client_addr,
+                                             frontend_addr,
+                                             &data);
+                    }
+                    Err(reason) => warn!("Rejecting connection from {}: {}", peer_addr, reason),
                 }
-                Err(e) => {
-                    error!("Accept error: {}", e);
+            }
+            Err(reason) => {
+                let pending = self.remove_pending(event_loop, token);
+                warn!("Rejecting connection from {}: {}", pending.peer_addr(), reason);
+            }
+        }
+    }
+
+    fn remove_pending(&mut self,
+                      event_loop: &mut EventLoop,
+                      token: PendingToken)
+                      -> PendingConnection {
+        let mut pending = self.pending_connections
+                              .remove(token)
+                              .expect("Can't remove already removed pending connection");
+
+        if let Some(timeout) = pending.take_timeout() {
+            event_loop.clear_timeout(timeout);
+        }
+
+        event_loop.deregister(pending.stream()).unwrap_or(());
+
+        pending
+    }
+
+    fn incoming_ready(&mut self,
+                      event_loop: &mut EventLoop,
+                      token: IncomingToken,
+                      events: EventSet) {
+        let data_sent = match self.incoming_connections.get_mut(token) {
+            Some(connection) => {
+                connection.incoming_ready(events);
+                connection.tick()
+            }
+            None => {
+                warn!("Could not find incoming connection for {:?}", token);
+                return;
+            }
+        };
+
+        self.connection_ticked(event_loop, token, data_sent);
+    }
+
+    fn outgoing_ready(&mut self,
+                      event_loop: &mut EventLoop,
+                      token: OutgoingToken,
+                      events: EventSet) {
+        if let Some(&Some(incoming_token)) = self.outgoing_connections.get(token) {
+            let mut data_sent = None;
+
+            // Events can still arrive for a stream that was released
+            // earlier in the same poll
+            if !self.incoming_connections.get(incoming_token).map_or(true, |c| c.has_outgoing()) {
+                return;
+            }
+
+            // A hangup with readable data means the target answered and closed,
+            // which has to be relayed rather than retried
+            if events.is_error() || (events.is_hup() && !events.is_readable()) {
+                self.record_connect_failure(incoming_token);
+
+                if self.retry_connection(event_loop, incoming_token) {
                     return;
                 }
+            }
+
+            if let Some(mut connection) = self.incoming_connections.get_mut(incoming_token) {
+                if !connection.is_connected() && events.is_writable() && !events.is_error() &&
+                   !events.is_hup() {
+                    connection.set_connected();
+
+                    if let Some(timeout) = connection.take_timeout(ConnectionTimeout::Connect) {
+                        event_loop.clear_timeout(timeout);
+                    }
+                }
+
+                connection.outgoing_ready(events);
+                data_sent = Some(connection.tick());
+            } else {
+                warn!("Could not find corresponding incoming connection for {:?} -> {:?}",
+                      token,
+                      incoming_token);
+            }
+
+            if let Some(data_sent) = data_sent {
+                self.connection_ticked(event_loop, incoming_token, data_sent);
+            }
+        } else if let Some(&incoming_token) = self.mirror_connections.get(token) {
+            self.mirror_ready(event_loop, incoming_token, events);
+        } else if self.idle_connections.contains(token) {
+            // Idle connections only get events when the target closes them
+            if let Some(idle) = self.pool.remove(token) {
+                debug!("Idle connection to {} was closed", idle.target());
+                self.unpool(event_loop, idle);
+            }
+        } else {
+            warn!("Could not find outgoing connection for {:?}", token);
+        }
+    }
+
+    fn retry_connection(&mut self, event_loop: &mut EventLoop, token: IncomingToken) -> bool {
+        let (backend, client_addr, mut failed_targets) = {
+            let connection = match self.incoming_connections.get(token) {
+                Some(connection) if !connection.has_relayed_data() => connection,
+                _ => return false,
+            };
+
+            let mut failed_targets = connection.failed_targets().to_vec();
+            failed_targets.push(connection.target());
+
+            (connection.backend().clone(), connection.client_addr(), failed_targets)
+        };
+
+        if failed_targets.len() > backend.borrow().connect_retries() {
+            return false;
+        }
+
+        warn!("Connection to {} failed before any data was relayed",
+              failed_targets[failed_targets.len() - 1]);
+
+        let (outgoing, target) = match connect_to_backend(&backend,
+                                                          &client_addr,
+                                                          &mut failed_targets,
+                                                          &mut self.metrics) {
+            Some(connected) => connected,
+            None => return false,
+        };
+
+        self.metrics.connection_opened(backend.borrow().name(), target);
+
+        let connection = &mut self.incoming_connections[token];
+
+        connection.count_in_target(backend.borrow_mut().connection_opened(target));
+
+        if let Some(old_outgoing) = connection.replace_outgoing(outgoing, target, failed_targets) {
+            event_loop.deregister(old_outgoing.get_ref()).unwrap_or(());
+        }
+
+        if let Some(timeout) = connection.take_timeout(ConnectionTimeout::Connect) {
+            event_loop.clear_timeout(timeout);
+        }
+
+        if let Some(delay) = connection.frontend().connect_timeout_ms() {
+            schedule_timeout(event_loop, connection, token, ConnectionTimeout::Connect, delay);
+        }
+
+        event_loop.register_opt(connection.outgoing_stream().unwrap(),
+                                connection.outgoing_token().as_raw_token(),
+                                EventSet::all(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+
+        true
+    }
+
+    /// Decides what happens to a connection after it ticked: the next
+    /// request on an HTTP connection is sent to its target, the outgoing
+    /// stream is let go if the target closed it between requests, and the
+    /// connection is removed once either side is closed
+    fn connection_ticked(&mut self,
+                         event_loop: &mut EventLoop,
+                         token: IncomingToken,
+                         data_sent: bool) {
+        let (waiting, release, remove) = match self.incoming_connections.get_mut(token) {
+            Some(connection) => {
+                if connection.take_upgrade() {
+                    upgrade_idle_timeout(event_loop, connection, token);
+                }
+
+                let incoming_closed = connection.is_incoming_closed();
+                let outgoing_closed = connection.is_outgoing_closed();
+
+                // The client is told about a request that was cut off, and
+                // kept until it has been
+                let unanswered = outgoing_closed && !incoming_closed &&
+                                 connection.fail_unanswered_request();
+
+                (connection.waiting_request().is_some(),
+                 outgoing_closed && !incoming_closed &&
+                 (unanswered || connection.can_release_outgoing()),
+                 !data_sent && (incoming_closed || outgoing_closed))
+            }
+            None => return,
+        };
+
+        if waiting {
+            self.dispatch_request(event_loop, token);
+        } else if release {
+            self.release_outgoing(event_loop, token);
+        } else if remove {
+            let reason = self.incoming_connections[token].close_reason();
+            self.remove_connection(event_loop, token, reason);
+            return;
+        }
+
+        self.to_reregister.insert(token);
+    }
+
+    /// Sends the next request on an HTTP connection to a target picked by
+    /// the running configuration. The outgoing stream is kept if the
+    /// request goes to the same target and the target kept it open, and
+    /// replaced otherwise.
+    fn dispatch_request(&mut self, event_loop: &mut EventLoop, token: IncomingToken) {
+        let (frontend, backend, client_addr) = {
+            let connection = &self.incoming_connections[token];
+
+            let head = match connection.waiting_request() {
+                Some(head) => head,
+                None => return,
+            };
+
+            // Connections on frontends removed by a reconfiguration keep
+            // routing by the configuration they were opened with
+            let frontend = self.state
+                               .frontend(connection.frontend().name())
+                               .unwrap_or_else(|| connection.frontend().clone());
+
+            let backend = frontend.decide_backend(None, Some(head));
+
+            (frontend, backend, connection.client_addr())
+        };
+
+        let target = backend.borrow_mut().decide_target(&client_addr);
+
+        let reuse = {
+            let connection = &self.incoming_connections[token];
+
+            target == Some(connection.target()) &&
+            connection.backend().borrow().name() == backend.borrow().name() &&
+            connection.can_reuse_outgoing()
+        };
+
+        if reuse {
+            self.incoming_connections[token].dispatch_request(frontend);
+            return;
+        }
+
+        self.release_outgoing(event_loop, token);
+
+        let mut failed_targets = Vec::new();
+
+        let (outgoing, target) = match self.connect_for_request(event_loop,
+                                                                &backend,
+                                                                target,
+                                                                &client_addr,
+                                                                &mut failed_targets) {
+            Some(connected) => connected,
+            None => {
+                self.incoming_connections[token].reject_request(503, "Service Unavailable");
+                return;
+            }
+        };
+
+        debug!("Sending request from {} to {}", client_addr, target);
+
+        let target_count = backend.borrow_mut().connection_opened(target);
+
+        let connection = &mut self.incoming_connections[token];
+
+        let header = match backend.borrow().send_proxy_protocol() {
+            Some(version) => {
+                proxy_protocol::header(version, client_addr, connection.frontend_addr())
+            }
+            None => Vec::new(),
+        };
+
+        connection.set_outgoing(outgoing, backend, target, failed_targets);
+        connection.count_in_target(target_count);
+        connection.set_outgoing_header(header);
+        connection.dispatch_request(frontend);
+
+        if let Some(delay) = connection.frontend().connect_timeout_ms() {
+            schedule_timeout(event_loop, connection, token, ConnectionTimeout::Connect, delay);
+        }
+
+        event_loop.register_opt(connection.outgoing_stream().unwrap(),
+                                connection.outgoing_token().as_raw_token(),
+                                EventSet::all(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+    }
+
+    /// Connects to `target` of `backend` for a request on an HTTP
+    /// connection, reusing an idle connection from the pool if there is
+    /// one. Other targets are tried if the connect call fails right away.
+    fn connect_for_request(&mut self,
+                           event_loop: &mut EventLoop,
+                           backend: &Rc<RefCell<Backend>>,
+                           target: Option<SocketAddr>,
+                           client_addr: &SocketAddr,
+                           failed_targets: &mut Vec<SocketAddr>)
+                           -> Option<(Stream, SocketAddr)> {
+        let target = match target {
+            Some(target) => target,
+            None => {
+                error!("No target available in backend {}", backend.borrow().name());
+                return None;
+            }
+        };
+
+        let pooled = self.pool.take(backend.borrow().name(), target);
+
+        if let Some(idle) = pooled {
+            debug!("Reusing idle connection to {}", target);
+            return Some((self.unpool(event_loop, idle), target));
+        }
+
+        let connected = match connect_to_target(&backend.borrow(), target) {
+            Ok(stream) => Some((stream, target)),
+            Err(e) => {
+                error!("Connect error to {}: {}", target, e);
+                self.metrics.connect_failed(backend.borrow().name(), target);
+                failed_targets.push(target);
+
+                connect_to_backend(backend, client_addr, failed_targets, &mut self.metrics)
+            }
+        };
+
+        if let Some((_, target)) = connected {
+            self.metrics.connection_opened(backend.borrow().name(), target);
+        }
+
+        connected
+    }
+
+    /// Takes the outgoing stream from an HTTP connection between requests,
+    /// while the client stays connected. The stream is kept in the pool if
+    /// the target will take another request on it, and closed otherwise.
+    fn release_outgoing(&mut self, event_loop: &mut EventLoop, token: IncomingToken) {
+        let (outgoing, backend, target) = {
+            let connection = &mut self.incoming_connections[token];
+            let reusable = connection.can_reuse_outgoing();
+
+            let outgoing = match connection.take_outgoing() {
+                Some(outgoing) => outgoing,
+                None => return,
+            };
+
+            debug!("Releasing connection to {}", connection.target());
+            event_loop.deregister(outgoing.get_ref()).unwrap_or(());
+
+            if let Some(timeout) = connection.take_timeout(ConnectionTimeout::Connect) {
+                event_loop.clear_timeout(timeout);
+            }
+
+            let (bytes_in, bytes_out) = connection.take_target_bytes();
+
+            connection.uncount_target();
+            self.metrics.connection_closed(connection.frontend().name(),
+                                           connection.backend().borrow().name(),
+                                           connection.target(),
+                                           bytes_in,
+                                           bytes_out);
+
+            if !reusable {
+                return;
+            }
+
+            (outgoing, connection.backend().clone(), connection.target())
+        };
+
+        let backend = backend.borrow();
+        let max_idle = backend.pool_max_idle();
+
+        if max_idle == 0 {
+            return;
+        }
+
+        if !self.idle_connections.has_remaining() {
+            if let Some(evicted) = self.pool.remove_oldest() {
+                debug!("Pool full, closing idle connection to {}", evicted.target());
+                self.unpool(event_loop, evicted);
+            }
+        }
+
+        let pool_token = match self.idle_connections.insert(()) {
+            Ok(pool_token) => pool_token,
+            Err(_) => return,
+        };
+
+        let mut idle = IdleStream::new(outgoing, pool_token, target);
+
+        event_loop.register_opt(idle.stream(),
+                                pool_token.as_raw_token(),
+                                EventSet::readable() | EventSet::error() | EventSet::hup(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+
+        match event_loop.timeout_ms(DriverTimeout::Pooled(pool_token),
+                                    backend.pool_idle_timeout_ms()) {
+            Ok(timeout) => idle.set_timeout(timeout),
+            Err(e) => error!("Could not schedule idle connection timeout: {:?}", e),
+        }
+
+        if let Some(evicted) = self.pool.put(backend.name(), idle, max_idle) {
+            self.unpool(event_loop, evicted);
+        }
+    }
+
+    /// Frees the token and timeout of a connection taken from the pool.
+    /// The connection is closed when the returned stream is dropped.
+    fn unpool(&mut self, event_loop: &mut EventLoop, mut idle: IdleStream) -> Stream {
+        event_loop.deregister(idle.stream()).unwrap_or(());
+
+        if let Some(timeout) = idle.take_timeout() {
+            event_loop.clear_timeout(timeout);
+        }
+
+        self.idle_connections.remove(idle.token());
+
+        idle.into_stream()
+    }
+
+    /// Counts a failed connection attempt, unless the connection to the
+    /// target was already established
+    fn record_connect_failure(&mut self, token: IncomingToken) {
+        if let Some(connection) = self.incoming_connections.get(token) {
+            if !connection.is_connected() {
+                self.metrics.connect_failed(connection.backend().borrow().name(),
+                                            connection.target());
+            }
+        }
+    }
+
+    fn connection_timeout(&mut self,
+                          event_loop: &mut EventLoop,
+                          token: IncomingToken,
+                          kind: ConnectionTimeout) {
+        let (client_addr, target) = match self.incoming_connections.get_mut(token) {
+            Some(connection) => {
+                connection.take_timeout(kind);
+
+                if kind == ConnectionTimeout::Idle {
+                    let idle_timeout = connection.idle_timeout_ms().unwrap_or(0);
+                    let idle_time = as_millis(connection.idle_time());
+
+                    if idle_time < idle_timeout {
+                        schedule_timeout(event_loop,
+                                         connection,
+                                         token,
+                                         kind,
+                                         idle_timeout - idle_time);
+                        return;
+                    }
+                }
+
+                (connection.client_addr(), connection.target())
+            }
+            None => {
+                warn!("Timeout for unknown connection {:?}", token);
+                return;
+            }
+        };
+
+        if kind == ConnectionTimeout::Connect {
+            warn!("Connecting to {} timed out", target);
+            self.record_connect_failure(token);
+
+            if self.retry_connection(event_loop, token) {
+                return;
+            }
+        }
+
+        info!("Closing connection {} -> {}: {:?} timeout",
+              client_addr,
+              target,
+              kind);
+
+        self.remove_connection(event_loop, token, CloseReason::Timeout(kind));
+    }
+
+    /// Closes idle connections whose target was removed, or whose backend
+    /// changed its TLS or PROXY settings since they were opened
+    fn unpool_stale(&mut self,
+                    event_loop: &mut EventLoop,
+                    replaced: &HashMap<String, Rc<RefCell<Backend>>>) {
+        let backends = &self.state.backends;
+        let stale = self.pool.remove_unless(|name, target| {
+            let backend = match backends.get(name) {
+                Some(backend) => backend.borrow(),
+                None => return false,
             };
 
-            let backend = listener.frontend.decide_backend();
-            let target = backend.borrow_mut().decide_target();
+            backend.target_addrs().contains(&target) &&
+            replaced.get(name).map_or(true, |old| old.borrow().connects_like(&backend))
+        });
+
+        for idle in stale {
+            self.unpool(event_loop, idle);
+        }
+    }
+
+    /// Marks connections whose frontend or target was removed by the last
+    /// reconfiguration as draining. They keep running, but are closed once
+    /// `drain_timeout_ms` has passed.
+    fn start_draining(&mut self, event_loop: &mut EventLoop) {
+        let listen_addrs = self.state
+                               .listeners
+                               .iter()
+                               .filter(|l| !self.state.listeners_to_remove.contains(&l.token))
+                               .map(|l| l.listen_addr)
+                               .collect::<HashSet<_>>();
+        let drain_timeout = self.state.config.drain_timeout_ms;
+
+        for connection in self.incoming_connections.iter_mut() {
+            if connection.is_draining() {
+                continue;
+            }
+
+            let frontend_removed = !connection.frontend()
+                                              .listen_addrs()
+                                              .iter()
+                                              .any(|addr| listen_addrs.contains(addr));
+
+            let backend_name = connection.backend().borrow().name().to_owned();
+            let target_removed = match self.state.backends.get(&backend_name) {
+                Some(backend) => !backend.borrow().target_addrs().contains(&connection.target()),
+                None => true,
+            };
+
+            if frontend_removed || target_removed {
+                connection.set_draining();
+
+                if let Some(delay) = drain_timeout {
+                    let token = connection.incoming_token();
+                    schedule_timeout(event_loop,
+                                     connection,
+                                     token,
+                                     ConnectionTimeout::Drain,
+                                     delay);
+                }
+            }
+        }
+
+        let draining = self.draining_connections();
+
+        if draining > 0 {
+            info!("{} connections draining", draining);
+        }
+    }
+
+    pub fn draining_connections(&self) -> usize {
+        self.incoming_connections.iter().filter(|c| c.is_draining()).count()
+    }
+
+    fn handle_message(&mut self,
+                      event_loop: &mut EventLoop,
+                      msg: DriverMessage)
+                      -> Result<(), ReconfigureError> {
+        match msg {
+            DriverMessage::Shutdown => event_loop.shutdown(),
+            DriverMessage::Reconfigure(config) => {
+                let replaced = self.state.backends.clone();
+
+                try!(self.state.reconfigure(event_loop, &config));
+                self.unpool_stale(event_loop, &replaced);
+                self.start_draining(event_loop);
+            }
+            DriverMessage::Reload => {
+                match self.reloader {
+                    Some(ref reloader) => reloader.request_reload(),
+                    None => return Err(ReconfigureError::ReloadUnavailable),
+                }
+            }
+            DriverMessage::AddTarget { backend, target } => {
+                try!(self.state.add_target(&backend, &target));
+            }
+            DriverMessage::RemoveTarget { backend, addr } => {
+                try!(self.state.remove_target(&backend, &addr));
+                self.unpool_stale(event_loop, &HashMap::new());
+                self.start_draining(event_loop);
+            }
+            DriverMessage::SetTargetEnabled { backend, addr, enabled } => {
+                try!(self.state.set_target_enabled(&backend, &addr, enabled));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn service_listener_ready(&mut self, event_loop: &mut EventLoop, metrics_only: bool) {
+        let (listener, token) = if metrics_only {
+            (&self.state.metrics, METRICS_LISTENER_TOKEN)
+        } else {
+            (&self.state.admin, ADMIN_LISTENER_TOKEN)
+        };
+
+        let accepted = match *listener {
+            Some(ref listener) => {
+                let accepted = listener.listener.accept();
+
+                event_loop.reregister(&listener.listener,
+                                      token,
+                                      EventSet::readable(),
+                                      PollOpt::edge() | PollOpt::oneshot())
+                          .unwrap();
+
+                accepted
+            }
+            None => {
+                warn!("Listener event on {:?} without a listener", token);
+                return;
+            }
+        };
+
+        let stream = match accepted {
+            Ok(Some(stream)) => stream,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Admin accept error: {}", e);
+                return;
+            }
+        };
+
+        let token = match self.admin_connections
+                              .insert(AdminConnection::new(stream, metrics_only)) {
+            Ok(token) => token,
+            Err(_) => {
+                warn!("Admin connection buffer full, dropping connection");
+                return;
+            }
+        };
+
+        event_loop.register_opt(self.admin_connections[token].stream(),
+                                token.as_raw_token(),
+                                self.admin_connections[token].interest(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+    }
+
+    fn admin_ready(&mut self, event_loop: &mut EventLoop, token: AdminToken, events: EventSet) {
+        let event = match self.admin_connections.get_mut(token) {
+            Some(connection) => connection.ready(events),
+            None => {
+                warn!("Could not find admin connection for {:?}", token);
+                return;
+            }
+        };
+
+        match event {
+            AdminEvent::Pending => {}
+            AdminEvent::Request(request) => {
+                let metrics_only = self.admin_connections[token].is_metrics_only();
+                let response = self.handle_admin_request(event_loop, &request, metrics_only);
+                self.admin_connections[token].respond(response);
+            }
+            AdminEvent::Done => {
+                if let Some(connection) = self.admin_connections.remove(token) {
+                    event_loop.deregister(connection.stream()).unwrap_or(());
+                }
+
+                return;
+            }
+        }
+
+        event_loop.reregister(self.admin_connections[token].stream(),
+                              token.as_raw_token(),
+                              self.admin_connections[token].interest(),
+                              PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+    }
+
+    fn handle_admin_request(&mut self,
+                            event_loop: &mut EventLoop,
+                            request: &AdminRequest,
+                            metrics_only: bool)
+                            -> AdminResponse {
+        debug!("Admin request: {} {}", request.method, request.path);
+
+        match admin::route(request) {
+            AdminRoute::Metrics => admin::text_response(self.render_metrics()),
+            _ if metrics_only => {
+                admin::error_response(404, "Only /metrics is served on this listener")
+            }
+            AdminRoute::Frontends => admin::json_response(&self.frontend_statuses()),
+            AdminRoute::Backends => admin::json_response(&self.backend_statuses()),
+            AdminRoute::Connections => {
+                admin::json_response(&ConnectionStatus {
+                    active: self.incoming_connections.count(),
+                    draining: self.draining_connections(),
+                    capacity: self.state.config.buffers.connections,
+                })
+            }
+            AdminRoute::Config => admin::json_response(&self.state.config),
+            AdminRoute::Action(msg) => {
+                match self.handle_message(event_loop, msg) {
+                    Ok(()) => admin::ok_response(),
+                    Err(e) => {
+                        let status = match e {
+                            ReconfigureError::UnknownBackend(_) |
+                            ReconfigureError::UnknownTarget(_) => 404,
+                            ReconfigureError::ReloadUnavailable => 503,
+                            _ => 400,
+                        };
+
+                        admin::error_response(status, &format!("{:?}", e))
+                    }
+                }
+            }
+            AdminRoute::Error(status, message) => admin::error_response(status, &message),
+        }
+    }
+
+    fn render_metrics(&self) -> String {
+        let frontends = self.state
+                            .listeners
+                            .iter()
+                            .filter(|l| !self.state.listeners_to_remove.contains(&l.token))
+                            .map(|l| {
+                                let name = l.frontend.name();
+                                let connections = self.incoming_connections
+                                                      .iter()
+                                                      .filter(|c| c.frontend().name() == name)
+                                                      .collect::<Vec<_>>();
+
+                                FrontendSample {
+                                    name: name.to_owned(),
+                                    active_connections: connections.len(),
+                                    bytes_in: connections.iter()
+                                                         .map(|c| c.target_bytes().0)
+                                                         .sum(),
+                                    bytes_out: connections.iter()
+                                                          .map(|c| c.target_bytes().1)
+                                                          .sum(),
+                                    mirrored_bytes: connections.iter()
+                                                               .filter_map(|c| c.mirror())
+                                                               .map(|m| m.bytes_sent())
+                                                               .sum(),
+                                    mirror_dropped_bytes: connections.iter()
+                                                                     .filter_map(|c| c.mirror())
+                                                                     .map(|m| m.bytes_dropped())
+                                                                     .sum(),
+                                }
+                            })
+                            .collect::<Vec<_>>();
+
+        let mut targets = Vec::new();
+
+        for backend in self.state.backends.values() {
+            let backend = backend.borrow();
+
+            for target in backend.target_statuses() {
+                let addr = target.addr.parse().unwrap();
+                let connections = self.incoming_connections
+                                      .iter()
+                                      .filter(|c| {
+                                          c.target() == addr &&
+                                          c.backend().borrow().name() == backend.name()
+                                      })
+                                      .collect::<Vec<_>>();
+
+                targets.push(TargetSample {
+                    backend: backend.name().to_owned(),
+                    addr: addr,
+                    healthy: target.healthy,
+                    active_connections: target.active_connections,
+                    bytes_in: connections.iter().map(|c| c.target_bytes().0).sum(),
+                    bytes_out: connections.iter().map(|c| c.target_bytes().1).sum(),
+                });
+            }
+        }
+
+        let connections = self.state.config.buffers.connections;
+        let slabs = [SlabSample {
+                         name: "incoming",
+                         used: self.incoming_connections.count(),
+                         capacity: connections,
+                     },
+                     SlabSample {
+                         name: "outgoing",
+                         used: self.outgoing_connections.count(),
+                         capacity: connections,
+                     },
+                     SlabSample {
+                         name: "mirror",
+                         used: self.mirror_connections.count(),
+                         capacity: connections,
+                     },
+                     SlabSample {
+                         name: "idle",
+                         used: self.idle_connections.count(),
+                         capacity: connections,
+                     }];
+
+        self.metrics.render(&frontends, &targets, &slabs)
+    }
+
+    fn frontend_statuses(&self) -> Vec<FrontendStatus> {
+        let mut statuses = self.state
+                               .listeners
+                               .iter()
+                               .filter(|l| !self.state.listeners_to_remove.contains(&l.token))
+                               .map(|l| {
+                                   let name = l.frontend.name();
+
+                                   FrontendStatus {
+                                       name: name.to_owned(),
+                                       listen_addr: l.listen_addr.to_string(),
+                                       backends: l.frontend.backend_names(),
+                                       active_connections: self.incoming_connections
+                                                               .iter()
+                                                               .filter(|c| {
+                                                                   c.frontend().name() == name
+                                                               })
+                                                               .count(),
+                                   }
+                               })
+                               .collect::<Vec<_>>();
+
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+
+        statuses
+    }
+
+    fn backend_statuses(&self) -> Vec<BackendStatus> {
+        let mut statuses = self.state
+                               .backends
+                               .values()
+                               .map(|backend| {
+                                   let backend = backend.borrow();
+
+                                   BackendStatus {
+                                       name: backend.name().to_owned(),
+                                       strategy: backend.strategy().as_str().to_owned(),
+                                       targets: backend.target_statuses(),
+                                   }
+                               })
+                               .collect::<Vec<_>>();
+
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+
+        statuses
+    }
+
+    fn run_health_checks(&mut self,
+                         event_loop: &mut EventLoop,
+                         backend_name: String,
+                         generation: usize) {
+        if generation != self.state.generation {
+            debug!("Stopping health checks of replaced backend {}", backend_name);
+            return;
+        }
+
+        let backend = match self.state.backends.get(&backend_name) {
+            Some(backend) => backend.clone(),
+            None => return,
+        };
+
+        let config = match backend.borrow().health_check() {
+            Some(config) => config.clone(),
+            None => return,
+        };
+
+        let targets = backend.borrow().target_addrs();
+
+        for target in targets {
+            self.start_probe(event_loop, backend.clone(), target, &config);
+        }
+
+        let next_check = DriverTimeout::HealthCheck {
+            backend: backend_name,
+            generation: generation,
+        };
+
+        if let Err(e) = event_loop.timeout_ms(next_check, config.interval_ms()) {
+            error!("Could not schedule next health check: {:?}", e);
+        }
+    }
+
+    fn start_probe(&mut self,
+                   event_loop: &mut EventLoop,
+                   backend: Rc<RefCell<Backend>>,
+                   target: SocketAddr,
+                   config: &HealthCheckConfig) {
+        let connected = TcpStream::connect(&target)
+                            .map_err(|e| e.to_string())
+                            .and_then(|stream| backend.borrow().wrap_stream(stream, target));
+
+        let stream = match connected {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Health check of {} failed to connect: {}", target, e);
+                backend.borrow_mut().report_health(target, false);
+                return;
+            }
+        };
+
+        let token = match self.probes.insert(Probe::new(stream, backend, target, config)) {
+            Ok(token) => token,
+            Err(_) => {
+                warn!("Probe buffer full, skipping health check of {}", target);
+                return;
+            }
+        };
+
+        event_loop.register_opt(self.probes[token].stream(),
+                                token.as_raw_token(),
+                                self.probes[token].interest(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                  .unwrap();
+
+        match event_loop.timeout_ms(DriverTimeout::Probe(token), config.timeout_ms()) {
+            Ok(timeout) => self.probes[token].set_timeout(timeout),
+            Err(e) => error!("Could not schedule health check timeout: {:?}", e),
+        }
+    }
+
+    fn probe_ready(&mut self, event_loop: &mut EventLoop, token: ProbeToken, events: EventSet) {
+        let outcome = match self.probes.get_mut(token) {
+            Some(probe) => probe.ready(events),
+            None => {
+                warn!("Could not find probe for {:?}", token);
+                return;
+            }
+        };
+
+        match outcome {
+            Some(healthy) => self.finish_probe(event_loop, token, healthy),
+            None => {
+                event_loop.reregister(self.probes[token].stream(),
+                                      token.as_raw_token(),
+                                      self.probes[token].interest(),
+                                      PollOpt::edge() | PollOpt::oneshot())
+                          .unwrap();
+            }
+        }
+    }
+
+    fn finish_probe(&mut self, event_loop: &mut EventLoop, token: ProbeToken, healthy: bool) {
+        if let Some(mut probe) = self.probes.remove(token) {
+            if let Some(timeout) = probe.take_timeout() {
+                event_loop.clear_timeout(timeout);
+            }
+
+            trace!("Health check of {} finished: {}", probe.target(), healthy);
+
+            event_loop.deregister(probe.stream()).unwrap_or(());
+            probe.finish(healthy);
+        }
+    }
+
+    fn remove_connection(&mut self,
+                         event_loop: &mut EventLoop,
+                         token: IncomingToken,
+                         reason: CloseReason) {
+        debug!("Removing connection on incoming token {:?}: {}", token, reason.as_str());
+        self.close_mirror(event_loop, token);
+
+        if self.incoming_connections.get(token).map_or(false, |c| c.can_reuse_outgoing()) {
+            self.release_outgoing(event_loop, token);
+        }
+
+        let mut connection = self.incoming_connections
+                                 .remove(token)
+                                 .expect("Can't remove already removed incoming connection");
+
+        for timeout in connection.take_timeouts() {
+            event_loop.clear_timeout(timeout);
+        }
+
+        self.outgoing_connections
+            .remove(connection.outgoing_token())
+            .expect("Can't remove already removed outgoing connection");
+
+        connection.uncount_target();
+
+        let (bytes_in, bytes_out) = connection.take_target_bytes();
+
+        self.metrics.connection_closed(connection.frontend().name(),
+                                       connection.backend().borrow().name(),
+                                       connection.target(),
+                                       bytes_in,
+                                       bytes_out);
+
+        if let Some(ref mut access_log) = self.state.access_log {
+            access_log.log(&connection, reason);
+        }
+
+        if connection.is_draining() {
+            info!("{} connections still draining", self.draining_connections());
+        }
+    }
+}
+
+/// Connects to a target of `backend`, moving on to other targets if the
+/// connect call fails right away. Targets that failed are added to
+/// `failed_targets`, and no more than `connect_retries` extra attempts are
+/// made in total over the lifetime of a connection.
+fn connect_to_backend(backend: &Rc<RefCell<Backend>>,
+                      client_addr: &SocketAddr,
+                      failed_targets: &mut Vec<SocketAddr>,
+                      metrics: &mut Metrics)
+                      -> Option<(Stream, SocketAddr)> {
+    let mut backend = backend.borrow_mut();
+    let max_attempts = backend.connect_retries() + 1;
+
+    while failed_targets.len() < max_attempts {
+        let target = match backend.decide_target_excluding(client_addr, failed_targets) {
+            Some(target) => target,
+            None => {
+                error!("No target available in backend {}", backend.name());
+                return None;
+            }
+        };
+
+        match connect_to_target(&backend, target) {
+            Ok(stream) => return Some((stream, target)),
+            Err(e) => {
+                error!("Connect error to {}: {}", target, e);
+                metrics.connect_failed(backend.name(), target);
+                failed_targets.push(target);
+            }
+        }
+    }
+
+    error!("Giving up connecting to backend {} after {} attempts",
+           backend.name(),
+           failed_targets.len());
+
+    None
+}
+
+/// Restarts the idle timeout of a connection that was just upgraded, which
+/// may have its own
+fn upgrade_idle_timeout(event_loop: &mut EventLoop,
+                        connection: &mut Connection,
+                        token: IncomingToken) {
+    info!("Connection from {} to {} was upgraded",
+          connection.client_addr(),
+          connection.target());
+
+    if let Some(timeout) = connection.take_timeout(ConnectionTimeout::Idle) {
+        event_loop.clear_timeout(timeout);
+    }
+
+    if let Some(delay) = connection.idle_timeout_ms() {
+        schedule_timeout(event_loop, connection, token, ConnectionTimeout::Idle, delay);
+    }
+}
+
+fn connect_to_target(backend: &Backend, target: SocketAddr) -> Result<Stream, String> {
+    TcpStream::connect(&target)
+        .map_err(|e| e.to_string())
+        .and_then(|stream| backend.wrap_stream(stream, target))
+}
+
+fn schedule_timeout(event_loop: &mut EventLoop,
+                    connection: &mut Connection,
+                    token: IncomingToken,
+                    kind: ConnectionTimeout,
+                    delay: u64) {
+    match event_loop.timeout_ms(DriverTimeout::Connection(token, kind), delay) {
+        Ok(timeout) => connection.set_timeout(kind, timeout),
+        Err(e) => error!("Could not schedule {:?} timeout: {:?}", kind, e),
+    }
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+impl Handler for Driver {
+    type Timeout = DriverTimeout;
+    type Message = DriverMessage;
+
+    fn ready(&mut self, event_loop: &mut EventLoop, token: Token, events: EventSet) {
+        if token == Token(0) {
+            warn!("Should not receive events on Token zero");
+            return;
+        }
+
+        trace!("Events on token {:?}: {:?}", token, events);
+
+        match TokenType::from_raw_token(token) {
+            TokenType::Listener(token) => self.listener_ready(event_loop, token, events),
+            TokenType::Incoming(token) => self.incoming_ready(event_loop, token, events),
+            TokenType::Outgoing(token) => self.outgoing_ready(event_loop, token, events),
+            TokenType::Probe(token) => self.probe_ready(event_loop, token, events),
+            TokenType::AdminListener => self.service_listener_ready(event_loop, false),
+            TokenType::MetricsListener => self.service_listener_ready(event_loop, true),
+            TokenType::Admin(token) => self.admin_ready(event_loop, token, events),
+            TokenType::Pending(token) => self.pending_ready(event_loop, token),
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop, msg: DriverMessage) {
+        if let Err(e) = self.handle_message(event_loop, msg) {
+            error!("Could not apply driver message, keeping the running configuration: {:?}",
+                   e);
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop, timeout: DriverTimeout) {
+        match timeout {
+            DriverTimeout::HealthCheck { backend, generation } => {
+                self.run_health_checks(event_loop, backend, generation)
+            }
+            DriverTimeout::Probe(token) => {
+                if let Some(probe) = self.probes.get_mut(token) {
+                    debug!("Health check of {} timed out", probe.target());
+                    probe.take_timeout();
+                }
+
+                self.finish_probe(event_loop, token, false);
+            }
+            DriverTimeout::Connection(token, kind) => {
+                self.connection_timeout(event_loop, token, kind)
+            }
+            DriverTimeout::Pooled(token) => {
+                if let Some(mut idle) = self.pool.remove(token) {
+                    debug!("Closing idle connection to {}", idle.target());
+                    idle.take_timeout();
+                    self.unpool(event_loop, idle);
+                }
+            }
+            DriverTimeout::Pending(token) => {
+                let timed_out = match self.pending_connections.get_mut(token) {
+                    Some(pending) => pending.take_timeout().is_some(),
+                    None => false,
+                };
+
+                if timed_out {
+                    let pending = self.remove_pending(event_loop, token);
+                    warn!("Rejecting connection from {}: timed out waiting for data",
+                          pending.peer_addr());
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, event_loop: &mut EventLoop) {
+        let mut closed_mirrors = Vec::new();
+
+        for token in self.to_reregister.iter() {
+            if let Some(connection) = self.incoming_connections.get(*token) {
+                event_loop.reregister(connection.incoming_stream(),
+                                      token.as_raw_token(),
+                                      EventSet::all(),
+                                      PollOpt::edge() | PollOpt::oneshot())
+                          .unwrap();
+
+                if let Some(outgoing_stream) = connection.outgoing_stream() {
+                    event_loop.reregister(outgoing_stream,
+                                          connection.outgoing_token().as_raw_token(),
+                                          EventSet::all(),
+                                          PollOpt::edge() | PollOpt::oneshot())
+                              .unwrap();
+                }
+
+                // Data read from the client may have been queued for the
+                // mirror, or overflowed its buffer
+                match connection.mirror() {
+                    Some(mirror) if mirror.is_closed() => closed_mirrors.push(*token),
+                    Some(mirror) => {
+                        event_loop.reregister(mirror.stream(),
+                                              mirror.token().as_raw_token(),
+                                              mirror.interest(),
+                                              PollOpt::edge() | PollOpt::oneshot())
+                                  .unwrap();
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        self.to_reregister.clear();
+
+        for token in closed_mirrors {
+            self.close_mirror(event_loop, token);
+        }
+
+        for token in self.state.listeners_to_remove.iter() {
+            info!("Removing listener on token {:?}", token);
+
+            let listener = self.state
+                               .listeners
+                               .remove(*token)
+                               .unwrap();
+
+            event_loop.deregister(&listener.listener).unwrap();
+            drop(listener);
+        }
+
+        self.state.listeners_to_remove.clear();
+
+        let backends = self.state.health_checks_to_start.drain(..).collect::<Vec<_>>();
+        let generation = self.state.generation;
+
+        for backend in backends {
+            self.run_health_checks(event_loop, backend, generation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventLoop, Driver, DriverMessage};
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::thread;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::net::{TcpStream, TcpListener, SocketAddr};
+    use std::str::FromStr;
+    use std::io::{Read, Write, BufReader, BufRead, ErrorKind};
+    use std::time::Duration;
+    use std::collections::HashMap;
+    use std::default::Default;
+
+    use env_logger;
+
+    use openssl::nid::Nid;
+    use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+
+    use config::RootConfig;
+    use driver_state::DriverState;
+    use sni;
+    use tls;
+
+    static PORT_NUMBER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn next_port() -> u16 {
+        let first_port = option_env!("TEST_BASE_PORT")
+                             .map_or(32328, |v| v.parse::<usize>().unwrap());
+        PORT_NUMBER.compare_and_swap(0, first_port, Ordering::SeqCst);
+
+        PORT_NUMBER.fetch_add(1, Ordering::SeqCst) as u16
+    }
+
+    #[test]
+    fn start_stop_driver() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let t = thread::spawn(move || {
+            let mut driver = Driver::new(DriverState::new(&Default::default()));
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn single_backend() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let config = RootConfig::from_str(&format!("[frontends.in]
+listen_addr = \
+                                                    \"127.0.0.1:{}\"
+backend = \"out\"
+
+\
+                                                    [backends.out]
+target_addrs = \
+                                                    [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = \
+                                                    4096
+listeners = 128
+",
+                                                   next_port(),
+                                                   next_port()))
+                         .unwrap();
+
+        let backend_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
+                                           .unwrap();
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            debug!("Starting event loop");
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        let t2 = thread::spawn(move || {
+            debug!("Starting backend listener");
+
+            let listener = TcpListener::bind(backend_addr).unwrap();
+            let (mut client, _) = listener.accept().unwrap();
+
+            write!(client, "sent by backend\n").unwrap();
+            client.flush().unwrap();
+
+            debug!("Backend wrote data, waiting for data now");
+
+            let mut reader = BufReader::new(client);
+            let mut buffer = String::new();
+            reader.read_line(&mut buffer).unwrap();
+
+            debug!("Backend done");
+
+            assert_eq!(buffer, "sent by frontend\n");
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            debug!("Connecting to frontend...");
+            let client = TcpStream::connect(frontend_addr).unwrap();
+            debug!("Frontend connected, waiting for data...");
+
+            let mut reader = BufReader::new(client);
+            let mut buffer = String::new();
+            reader.read_line(&mut buffer).unwrap();
+
+            debug!("Frontend read data, sending response");
+
+            assert_eq!(buffer, "sent by backend\n");
+
+            write!(reader.get_mut(), "sent by frontend\n").unwrap();
+            reader.get_mut().flush().unwrap();
+
+            debug!("Frontend done");
+        }
+
+        t2.join().unwrap();
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconfigure_remove_listen() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let config = RootConfig::from_str(&format!("[frontends.in]
+listen_addr = \
+                                                    \"127.0.0.1:{}\"
+backend = \"out\"
+
+\
+                                                    [backends.out]
+target_addrs = \
+                                                    [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = \
+                                                    4096
+listeners = 128
+",
+                                                   next_port(),
+                                                   next_port()))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            debug!("Starting event loop");
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let client = TcpStream::connect(frontend_addr);
+
+            assert!(client.is_ok());
+        }
+
+        sender.send(DriverMessage::Reconfigure(RootConfig {
+                  frontends: HashMap::new(),
+                  backends: HashMap::new(),
+                  ..Default::default()
+              }))
+              .expect("Should be able to send reconfigure message");
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            debug!("Trying to connect, expecting refused connection");
+            let client = TcpStream::connect(frontend_addr);
+
+            assert!(client.is_err());
+        }
+
+        sender.send(DriverMessage::Shutdown).expect("Should be able to send shutdown message");
+
+        t1.join().expect("Event loop thread should have exited cleanly");
+    }
+
+    #[test]
+    fn failed_reconfigure_keeps_running_config() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let busy_port = next_port();
+        let backend_port = next_port();
+
+        let make_config = |frontend_port| {
+            RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                          frontend_port,
+                                          backend_port))
+                .unwrap()
+        };
+
+        let config = make_config(frontend_port);
+        let new_config = make_config(busy_port);
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+
+        let _busy_listener = TcpListener::bind(("127.0.0.1", busy_port)).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        sender.send(DriverMessage::Reconfigure(new_config)).unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(TcpStream::connect(frontend_addr).is_ok());
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn health_check_skips_dead_target() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let live_port = next_port();
+        let dead_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\", \"127.0.0.1:{}\"]
+
+[backends.out.health_check]
+interval_ms = 100
+timeout_ms = 100
+rise = 1
+fall = 1
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   live_port,
+                                                   dead_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let live_addr: SocketAddr = FromStr::from_str(&format!("127.0.0.1:{}", live_port))
+                                        .unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        let listener = TcpListener::bind(live_addr).unwrap();
+
+        // Health checks connect to the backend too, so keep accepting until
+        // the test process exits
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let mut client = client.unwrap();
+
+                write!(client, "sent by backend\n").unwrap_or(());
+            }
+        });
+
+        thread::sleep(Duration::from_millis(500));
+
+        for _ in 0..4 {
+            let client = TcpStream::connect(frontend_addr).unwrap();
+
+            let mut reader = BufReader::new(client);
+            let mut buffer = String::new();
+            reader.read_line(&mut buffer).unwrap();
+
+            assert_eq!(buffer, "sent by backend\n");
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    fn admin_request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        write!(stream, "{}", request).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        response
+    }
+
+    #[test]
+    fn admin_api_inspects_and_changes_targets() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let admin_port = next_port();
+        let frontend_port = next_port();
+        let target_port = next_port();
+        let new_target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[admin]
+listen_addr = \"127.0.0.1:{}\"
+
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   admin_port,
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let admin_addr: SocketAddr = FromStr::from_str(&config.admin.as_ref().unwrap().listen_addr)
+                                         .unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let response = admin_request(admin_addr, "GET /frontends HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("\"name\":\"in\""));
+        assert!(response.contains("\"backends\":[\"out\"]"));
+
+        let response = admin_request(admin_addr,
+                                     &format!("POST /backends/out/targets/127.0.0.1:{}/drain \
+                                               HTTP/1.0\r\n\r\n",
+                                              target_port));
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+
+        let response = admin_request(admin_addr, "GET /backends HTTP/1.0\r\n\r\n");
+        assert!(response.contains(&format!("\"addr\":\"127.0.0.1:{}\"", target_port)));
+        assert!(response.contains("\"enabled\":false"));
+
+        let body = format!("{{\"addr\": \"127.0.0.1:{}\", \"weight\": 2}}", new_target_port);
+        let response = admin_request(admin_addr,
+                                     &format!("POST /backends/out/targets \
+                                               HTTP/1.0\r\nContent-Length: {}\r\n\r\n{}",
+                                              body.len(),
+                                              body));
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+
+        let response = admin_request(admin_addr, "GET /config HTTP/1.0\r\n\r\n");
+        assert!(response.contains(&format!("127.0.0.1:{}", new_target_port)));
+
+        let response = admin_request(admin_addr,
+                                     &format!("POST /backends/out/targets/127.0.0.1:{}/remove \
+                                               HTTP/1.0\r\n\r\n",
+                                              target_port));
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+
+        let response = admin_request(admin_addr, "GET /config HTTP/1.0\r\n\r\n");
+        assert!(!response.contains(&format!("127.0.0.1:{}", target_port)));
+        assert!(response.contains(&format!("127.0.0.1:{}", new_target_port)));
+
+        let response = admin_request(admin_addr,
+                                     "POST /backends/missing/targets/127.0.0.1:1/drain \
+                                      HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        let response = admin_request(admin_addr, "DELETE /config HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 405 Method Not Allowed\r\n"));
+
+        let response = admin_request(admin_addr, "POST /reload HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 503 Service Unavailable\r\n"));
+
+        let response = admin_request(admin_addr,
+                                     &format!("POST /backends/out/targets HTTP/1.0\r\n\
+                                               Content-Length: {}\r\n\r\n",
+                                              usize::max_value()));
+        assert!(response.starts_with("HTTP/1.0 400 Bad Request\r\n"));
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn metrics_count_relayed_connections() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let metrics_port = next_port();
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[metrics]
+listen_addr = \"127.0.0.1:{}\"
+
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   metrics_port,
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let metrics_addr: SocketAddr =
+            FromStr::from_str(&config.metrics.as_ref().unwrap().listen_addr).unwrap();
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
+                                          .unwrap();
+
+        let target = TcpListener::bind(target_addr).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        let (mut server, _) = target.accept().unwrap();
+
+        write!(client, "ping\n").unwrap();
+        let mut buffer = [0; 5];
+        server.read_exact(&mut buffer).unwrap();
+
+        drop(client);
+        drop(server);
+        thread::sleep(Duration::from_millis(100));
+
+        let response = admin_request(metrics_addr, "GET /metrics HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("loadbalancer_frontend_connections_accepted_total\
+                                   {frontend=\"in\"} 1\n"));
+        assert!(response.contains("loadbalancer_frontend_bytes_received_total\
+                                   {frontend=\"in\"} 5\n"));
+        assert!(response.contains(&format!("loadbalancer_target_connections_total{{backend=\
+                                            \"out\",target=\"{}\"}} 1\n",
+                                           target_addr)));
+
+        let response = admin_request(metrics_addr, "GET /config HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn closed_connections_are_access_logged() {
+        env_logger::init().unwrap_or(());
+
+        let log_path = env::temp_dir().join("loadbalancer-access-log-test.log");
+        let log_path = log_path.to_str().unwrap().to_owned();
+        fs::remove_file(&log_path).unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[access_log]
+path = \"{}\"
+format = \"json\"
+
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   log_path,
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
+                                          .unwrap();
+
+        let target = TcpListener::bind(target_addr).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        let (mut server, _) = target.accept().unwrap();
+
+        write!(client, "ping\n").unwrap();
+        let mut buffer = [0; 5];
+        server.read_exact(&mut buffer).unwrap();
+
+        // The target only sees EOF once the record has been written
+        drop(client);
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        server.read_to_end(&mut Vec::new()).unwrap();
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+        t1.join().unwrap();
+
+        let mut log = String::new();
+        File::open(&log_path).unwrap().read_to_string(&mut log).unwrap();
+
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("\"frontend\":\"in\",\"backend\":\"out\""));
+        assert!(lines[0].contains(&format!("\"target\":\"{}\"", target_addr)));
+        assert!(lines[0].contains("\"bytes_in\":5,\"bytes_out\":0"));
+        assert!(lines[0].contains("\"reason\":\"client_close\""));
+    }
+
+    #[test]
+    fn proxy_protocol_header_precedes_client_data() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+send_proxy_protocol = \"v1\"
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
+                                          .unwrap();
+
+        let target = TcpListener::bind(target_addr).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        let client_port = client.local_addr().unwrap().port();
+        write!(client, "ping\n").unwrap();
+
+        let (server, _) = target.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(server);
+
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line,
+                   format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\n",
+                           client_port,
+                           frontend_port));
+
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ping\n");
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn tls_is_terminated_with_certificate_picked_by_sni() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let first = tls::test::write_certificate("a.example.com");
+        let second = tls::test::write_certificate("b.example.com");
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+certificates = [{{cert_path = \"{}\", key_path = \"{}\"}},
+                {{cert_path = \"{}\", key_path = \"{}\"}}]
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   first.cert_path,
+                                                   first.key_path,
+                                                   second.cert_path,
+                                                   second.key_path,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
+                                          .unwrap();
+
+        let target = TcpListener::bind(target_addr).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let connector = connector.build();
+
+        for &(server_name, expected_name) in &[(Some("b.example.com"), "b.example.com"),
+                                               (Some("c.example.com"), "a.example.com"),
+                                               (None, "a.example.com")] {
+            let stream = TcpStream::connect(frontend_addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut client = connector.configure()
+                                      .unwrap()
+                                      .use_server_name_indication(server_name.is_some())
+                                      .connect(server_name.unwrap_or("unused"), stream)
+                                      .unwrap();
+
+            let certificate = client.ssl().peer_certificate().unwrap();
+            let name = certificate.subject_name()
+                                  .entries_by_nid(Nid::COMMONNAME)
+                                  .next()
+                                  .unwrap()
+                                  .data()
+                                  .as_slice()
+                                  .to_vec();
+            assert_eq!(String::from_utf8(name).unwrap(), expected_name);
+
+            write!(client, "ping\n").unwrap();
+            client.flush().unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut buffer = [0; 5];
+            server.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"ping\n");
+
+            write!(server, "pong\n").unwrap();
+            client.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"pong\n");
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn accepted_proxy_protocol_header_is_passed_on() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+accept_proxy_protocol = true
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+send_proxy_protocol = \"v1\"
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
+                                          .unwrap();
+
+        let target = TcpListener::bind(target_addr).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        write!(client, "PROXY TCP4 203.0.113.7 127.0.0.1 4000 80\r\nping\n").unwrap();
+
+        let (server, _) = target.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(server);
+
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PROXY TCP4 203.0.113.7 127.0.0.1 4000 80\r\n");
+
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ping\n");
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(client, "GET / HTTP/1.0\r\n\r\n").unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn connections_to_tls_targets_are_verified() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let server_certificate = tls::test::write_certificate("backend.internal");
+        let client_certificate = tls::test::write_certificate("balancer.internal");
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+tls = true
+tls_ca_path = \"{}\"
+tls_server_name = \"backend.internal\"
+tls_client_certificate = {{cert_path = \"{}\", key_path = \"{}\"}}
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port,
+                                                   server_certificate.cert_path,
+                                                   client_certificate.cert_path,
+                                                   client_certificate.key_path))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
+                                          .unwrap();
+
+        let target = TcpListener::bind(target_addr).unwrap();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate_chain_file(&server_certificate.cert_path).unwrap();
+        acceptor.set_private_key_file(&server_certificate.key_path, SslFiletype::PEM).unwrap();
+        acceptor.set_ca_file(&client_certificate.cert_path).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = acceptor.build();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(client, "ping\n").unwrap();
+
+        let (server, _) = target.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut server = acceptor.accept(server).unwrap();
+
+        let certificate = server.ssl().peer_certificate().unwrap();
+        let name = certificate.subject_name()
+                              .entries_by_nid(Nid::COMMONNAME)
+                              .next()
+                              .unwrap()
+                              .data()
+                              .as_slice()
+                              .to_vec();
+        assert_eq!(String::from_utf8(name).unwrap(), "balancer.internal");
+
+        let mut buffer = [0; 5];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping\n");
+
+        write!(server, "pong\n").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"pong\n");
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn client_data_is_mirrored_without_affecting_the_client() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let dead_mirror_frontend_port = next_port();
+        let target_port = next_port();
+        let mirror_port = next_port();
+        let dead_mirror_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mirror_backend = \"shadow\"
+
+[frontends.in_dead_mirror]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mirror_backend = \"dead\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[backends.shadow]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[backends.dead]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   dead_mirror_frontend_port,
+                                                   target_port,
+                                                   mirror_port,
+                                                   dead_mirror_port))
+                         .unwrap();
+
+        let frontend_addrs = [config.frontends["in"].listen_addr.parse::<SocketAddr>().unwrap(),
+                              config.frontends["in_dead_mirror"]
+                                  .listen_addr
+                                  .parse::<SocketAddr>()
+                                  .unwrap()];
+        let target_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
+                                          .unwrap();
+        let mirror_addr: SocketAddr =
+            FromStr::from_str(&config.backends["shadow"].target_addrs[0]).unwrap();
+
+        let target = TcpListener::bind(target_addr).unwrap();
+        let mirror = TcpListener::bind(mirror_addr).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        for (i, frontend_addr) in frontend_addrs.iter().enumerate() {
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            write!(client, "ping\n").unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut buffer = [0; 5];
+            server.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"ping\n");
+
+            if i == 0 {
+                let (mut shadow, _) = mirror.accept().unwrap();
+                shadow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+                shadow.read_exact(&mut buffer).unwrap();
+                assert_eq!(&buffer, b"ping\n");
+
+                write!(shadow, "mirror response\n").unwrap();
+            }
+
+            write!(server, "pong\n").unwrap();
+            drop(server);
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert_eq!(response, "pong\n");
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn full_buffers_close_new_clients() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+        let mirror_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mirror_backend = \"shadow\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[backends.shadow]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 2
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port,
+                                                   mirror_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target = TcpListener::bind(&config.backends["out"].target_addrs[0][..]).unwrap();
+        let mirror = TcpListener::bind(&config.backends["shadow"].target_addrs[0][..]).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        // Both clients are relayed even though their mirrors are open too
+        let mut relayed = Vec::new();
+
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            write!(client, "ping\n").unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let (shadow, _) = mirror.accept().unwrap();
+
+            let mut buffer = [0; 5];
+            server.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"ping\n");
+
+            relayed.push((client, server, shadow));
+        }
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut buffer = [0; 5];
+        assert_eq!(client.read(&mut buffer).unwrap_or(0), 0);
+
+        for &mut (ref mut client, ref mut server, _) in relayed.iter_mut() {
+            write!(server, "pong\n").unwrap();
+            client.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"pong\n");
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn backend_is_picked_by_server_name() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let api_port = next_port();
+        let web_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"web\"
+routes = [{{sni = \"*.api.example.com\", backend = \"api\"}}]
+
+[backends.api]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[backends.web]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   api_port,
+                                                   web_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let api_addr: SocketAddr = FromStr::from_str(&config.backends["api"].target_addrs[0])
+                                       .unwrap();
+        let web_addr: SocketAddr = FromStr::from_str(&config.backends["web"].target_addrs[0])
+                                       .unwrap();
+
+        let api = TcpListener::bind(api_addr).unwrap();
+        let web = TcpListener::bind(web_addr).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let cases = [(sni::test::client_hello(Some("v1.api.example.com")), &api),
+                     (sni::test::client_hello(Some("www.example.com")), &web),
+                     (sni::test::client_hello(None), &web),
+                     (b"ping\n".to_vec(), &web)];
+
+        for &(ref data, target) in cases.iter() {
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            client.write_all(data).unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut received = vec![0; data.len()];
+            server.read_exact(&mut received).unwrap();
+            assert_eq!(&received, data);
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn requests_are_routed_by_host_and_path() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let secure_port = next_port();
+        let api_port = next_port();
+        let static_port = next_port();
+        let web_port = next_port();
+
+        let certificate = tls::test::write_certificate("www.example.com");
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"web\"
+mode = \"http\"
+forwarded_headers = false
+http_routes = [{{host = \"api.example.com\", backend = \"api\"}},
+               {{path_prefix = \"/static/\", backend = \"static\"}}]
+
+[frontends.secure]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"web\"
+mode = \"http\"
+forwarded_headers = false
+certificates = [{{cert_path = \"{}\", key_path = \"{}\"}}]
+http_routes = [{{host = \"api.example.com\", backend = \"api\"}}]
+
+[backends.api]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[backends.static]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[backends.web]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   secure_port,
+                                                   certificate.cert_path,
+                                                   certificate.key_path,
+                                                   api_port,
+                                                   static_port,
+                                                   web_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let secure_addr: SocketAddr = FromStr::from_str(&config.frontends["secure"].listen_addr)
+                                          .unwrap();
+        let listen = |name: &str| {
+            TcpListener::bind(&config.backends[name].target_addrs[0][..]).unwrap()
+        };
+
+        let api = listen("api");
+        let assets = listen("static");
+        let web = listen("web");
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let cases = [(&b"GET /users HTTP/1.1\r\nHost: API.example.com\r\n\r\n"[..], &api),
+                     (&b"GET /static/app.js HTTP/1.1\r\nHost: www.example.com\r\n\r\n"[..],
+                      &assets),
+                     (&b"POST / HTTP/1.1\r\nHost: www.example.com\r\nContent-Length: 4\r\n\r\n\
+                         body"[..],
+                      &web)];
+
+        for &(request, target) in cases.iter() {
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            client.write_all(request).unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut received = vec![0; request.len()];
+            server.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], request);
+        }
+
+        // Requests are also read from behind TLS
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let connector = connector.build();
+
+        let stream = TcpStream::connect(secure_addr).unwrap();
+        let mut client = connector.connect("api.example.com", stream).unwrap();
+        let request = b"GET /users HTTP/1.1\r\nHost: api.example.com\r\n\r\n";
+        client.write_all(request).unwrap();
+
+        let (mut server, _) = api.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut received = vec![0; request.len()];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], &request[..]);
+
+        // Other protocols are turned away without reaching a target
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"ping\r\n").unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 "));
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    /// Reads up to and including the blank line that ends a message head
+    fn read_head<R: Read>(stream: &mut R) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0];
+
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn http_headers_are_rewritten_on_every_message() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mode = \"http\"
+
+[frontends.in.request_headers]
+remove = [\"X-Debug\"]
+set = [{{name = \"X-Env\", value = \"prod\"}}]
+
+[frontends.in.response_headers]
+remove = [\"X-Powered-By\"]
+add = [{{name = \"Strict-Transport-Security\", value = \"max-age=60\"}}]
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target = TcpListener::bind(&config.backends["out"].target_addrs[0][..]).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // A chunked request followed by a second one on the same connection
+        client.write_all(b"POST /a HTTP/1.1\r\nHost: example.com\r\nX-Debug: 1\r\n\
+                           X-Env: dev\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           4\r\nbody\r\n0\r\n\r\n\
+                           GET /b HTTP/1.1\r\nHost: example.com\r\n\
+                           X-Forwarded-For: 10.0.0.1\r\n\r\n")
+              .unwrap();
+
+        let (mut server, _) = target.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let first = read_head(&mut server);
+        assert!(first.starts_with("POST /a HTTP/1.1\r\n"));
+        assert!(!first.contains("X-Debug"));
+        assert!(first.contains("\r\nX-Env: prod\r\n"));
+        assert!(!first.contains("X-Env: dev"));
+        assert!(first.contains("\r\nX-Forwarded-For: 127.0.0.1\r\n"));
+        assert!(first.contains("\r\nX-Forwarded-Proto: http\r\n"));
+        assert!(first.contains("\r\nX-Request-Id: "));
+
+        let mut body = [0; 14];
+        server.read_exact(&mut body).unwrap();
+        assert_eq!(&body, b"4\r\nbody\r\n0\r\n\r\n");
+
+        // The second request is held back until the first is answered
+        for i in 0..2 {
+            if i == 1 {
+                let second = read_head(&mut server);
+                assert!(second.starts_with("GET /b HTTP/1.1\r\n"));
+                assert!(second.contains("\r\nX-Forwarded-For: 10.0.0.1, 127.0.0.1\r\n"));
+            }
+
+            server.write_all(b"HTTP/1.1 200 OK\r\nX-Powered-By: PHP\r\n\
+                                Content-Length: 2\r\n\r\nok")
+                  .unwrap();
+
+            let response = read_head(&mut client);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(!response.contains("X-Powered-By"));
+            assert!(response.contains("\r\nStrict-Transport-Security: max-age=60\r\n"));
+
+            let mut body = [0; 2];
+            client.read_exact(&mut body).unwrap();
+            assert_eq!(&body, b"ok");
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn keep_alive_requests_are_balanced_one_by_one() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let first_port = next_port();
+        let second_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mode = \"http\"
+forwarded_headers = false
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\", \"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   first_port,
+                                                   second_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let first = TcpListener::bind(&config.backends["out"].target_addrs[0][..]).unwrap();
+        let second = TcpListener::bind(&config.backends["out"].target_addrs[1][..]).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Each target closes its connection after answering, which leaves
+        // the client connected for the next request
+        let exchanges = [(&b"GET /1 HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+                          &first,
+                          &b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n1"[..]),
+                         (&b"POST /2 HTTP/1.1\r\nHost: example.com\r\n\
+                             Content-Length: 3\r\n\r\nabc"[..],
+                          &second,
+                          &b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n2"[..]),
+                         (&b"GET /3 HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+                          &first,
+                          &b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n3"[..])];
+
+        for &(request, target, response) in exchanges.iter() {
+            client.write_all(request).unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut received = vec![0; request.len()];
+            server.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], request);
+
+            server.write_all(response).unwrap();
+
+            let mut answer = vec![0; response.len()];
+            client.read_exact(&mut answer).unwrap();
+            assert_eq!(&answer[..], response);
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn exchanges_that_are_not_kept_alive_close_the_client() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mode = \"http\"
+forwarded_headers = false
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target = TcpListener::bind(&config.backends["out"].target_addrs[0][..]).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        // First the client asks to close, and pipelines a request that must
+        // not be sent. Then the target does.
+        let exchanges = [(&b"GET /1 HTTP/1.1\r\nHost: example.com\r\n\
+                             Connection: close\r\n\r\n"[..],
+                          &b"GET /2 HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+                          &b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n1"[..]),
+                         (&b"GET /3 HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+                          &b""[..],
+                          &b"HTTP/1.1 200 OK\r\nConnection: close\r\n\
+                             Content-Length: 1\r\n\r\n3"[..])];
+
+        for &(request, pipelined, response) in exchanges.iter() {
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client.write_all(request).unwrap();
+            client.write_all(pipelined).unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut received = vec![0; request.len()];
+            server.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], request);
+
+            // The target keeps its end open, so only the balancer can end
+            // the client's connection
+            server.write_all(response).unwrap();
+
+            let mut answer = Vec::new();
+            client.read_to_end(&mut answer).unwrap();
+            assert_eq!(&answer[..], response);
+
+            let mut rest = Vec::new();
+            server.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty());
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn keep_alive_requests_follow_reloads() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let old_port = next_port();
+        let new_port = next_port();
+
+        let make_config = |backend: &str, port: u16| {
+            RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"{}\"
+mode = \"http\"
+forwarded_headers = false
+
+[frontends.in.request_headers]
+set = [{{name = \"X-Env\", value = \"{}\"}}]
+
+[frontends.in.response_headers]
+set = [{{name = \"X-Env\", value = \"{}\"}}]
+
+[backends.{}]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                          frontend_port,
+                                          backend,
+                                          backend,
+                                          backend,
+                                          backend,
+                                          port))
+                .unwrap()
+        };
+
+        let config = make_config("old", old_port);
+        let reloaded = make_config("new", new_port);
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let old = TcpListener::bind(&config.backends["old"].target_addrs[0][..]).unwrap();
+        let new = TcpListener::bind(&reloaded.backends["new"].target_addrs[0][..]).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+        // The request after the reload goes to the backend that replaced
+        // the one the connection was opened with, and gets the headers of
+        // the reloaded frontend
+        for (i, &(target, env)) in [(&old, "old"), (&new, "new")].iter().enumerate() {
+            if i == 1 {
+                sender.send(DriverMessage::Reconfigure(reloaded.clone())).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+
+            client.write_all(request).unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let expected = format!("GET / HTTP/1.1\r\nHost: example.com\r\nX-Env: {}\r\n\r\n",
+                                   env);
+            let mut received = vec![0; expected.len()];
+            server.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], expected.as_bytes());
+
+            server.write_all(response).unwrap();
+
+            let expected = format!("HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Env: {}\r\n\r\nok",
+                                   env);
+            let mut answer = vec![0; expected.len()];
+            client.read_exact(&mut answer).unwrap();
+            assert_eq!(&answer[..], expected.as_bytes());
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn idle_target_connections_are_reused_until_timeout() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mode = \"http\"
+forwarded_headers = false
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+pool_max_idle = 2
+pool_idle_timeout_ms = 300
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target = TcpListener::bind(&config.backends["out"].target_addrs[0][..]).unwrap();
+        let reloaded = config.clone();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        let mut server = None;
+
+        // Both clients are served over the connection opened for the first,
+        // which a reload that keeps its target doesn't close
+        for i in 0..2 {
+            if i == 1 {
+                sender.send(DriverMessage::Reconfigure(reloaded.clone())).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client.write_all(request).unwrap();
+
+            if server.is_none() {
+                let (stream, _) = target.accept().unwrap();
+                stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                server = Some(stream);
+            }
+
+            let server = server.as_mut().unwrap();
+
+            let mut received = vec![0; request.len()];
+            server.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], &request[..]);
+
+            server.write_all(response).unwrap();
+
+            let mut answer = vec![0; response.len()];
+            client.read_exact(&mut answer).unwrap();
+            assert_eq!(&answer[..], &response[..]);
+
+            drop(client);
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        target.set_nonblocking(true).unwrap();
+        assert_eq!(target.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        // The idle connection is closed after pool_idle_timeout_ms
+        let mut buf = [0; 1];
+        assert_eq!(server.unwrap().read(&mut buf).unwrap(), 0);
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn requests_cut_off_on_pooled_connections_get_a_bad_gateway() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mode = \"http\"
+forwarded_headers = false
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+pool_max_idle = 1
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target = TcpListener::bind(&config.backends["out"].target_addrs[0][..]).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(request).unwrap();
+
+        let (mut server, _) = target.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut received = vec![0; request.len()];
+        server.read_exact(&mut received).unwrap();
+        server.write_all(response).unwrap();
+
+        let mut answer = vec![0; response.len()];
+        client.read_exact(&mut answer).unwrap();
+        drop(client);
+        thread::sleep(Duration::from_millis(100));
+
+        // The next client gets the pooled connection, which the target
+        // closes without answering
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(request).unwrap();
+
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], &request[..]);
+        drop(server);
+
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn idle_target_connections_leave_room_for_clients() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let http_port = next_port();
+        let tcp_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.http]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mode = \"http\"
+forwarded_headers = false
+
+[frontends.tcp]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+pool_max_idle = 2
+
+[buffers]
+connections = 2
+listeners = 128
+",
+                                                   http_port,
+                                                   tcp_port,
+                                                   target_port))
+                         .unwrap();
+
+        let http_addr: SocketAddr = FromStr::from_str(&config.frontends["http"].listen_addr)
+                                        .unwrap();
+        let tcp_addr: SocketAddr = FromStr::from_str(&config.frontends["tcp"].listen_addr)
+                                       .unwrap();
+        let target = TcpListener::bind(&config.backends["out"].target_addrs[0][..]).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+        let mut client = TcpStream::connect(http_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(request).unwrap();
+
+        let (mut idle, _) = target.accept().unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut received = vec![0; request.len()];
+        idle.read_exact(&mut received).unwrap();
+        idle.write_all(response).unwrap();
+
+        let mut answer = vec![0; response.len()];
+        client.read_exact(&mut answer).unwrap();
+        drop(client);
+        thread::sleep(Duration::from_millis(100));
+
+        // The pooled connection doesn't count against the two clients
+        let mut relayed = Vec::new();
+
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(tcp_addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            write!(client, "ping\n").unwrap();
+
+            let (mut server, _) = target.accept().unwrap();
+            server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut buffer = [0; 5];
+            server.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"ping\n");
+
+            relayed.push((client, server));
+        }
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
+    }
+
+    #[test]
+    fn upgraded_connections_are_relayed_unchanged() {
+        env_logger::init().unwrap_or(());
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
+
+        let frontend_port = next_port();
+        let target_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+mode = \"http\"
+forwarded_headers = false
+websocket_idle_timeout_ms = 300
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   target_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+        let target = TcpListener::bind(&config.backends["out"].target_addrs[0][..]).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
+            event_loop.run(&mut driver).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\n\
+                           Upgrade: websocket\r\n\r\n")
+              .unwrap();
 
-            let outgoing = match TcpStream::connect(&target) {
-                Ok(client) => client,
-                Err(e) => {
-                    error!("Connect error: {}", e);
-                    return;
-                }
-            };
+        let (mut server, _) = target.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
 
-            let outgoing_token = self.outgoing_connections
-                                     .insert(None)
-                                     .expect("Outgoing buffer full");
-
-            let incoming_token = self.incoming_connections
-                                     .insert(Connection::new(incoming, outgoing, outgoing_token))
-                                     .map_err(|_| "Incoming buffer full")
-                                     .unwrap();
-
-            self.outgoing_connections[outgoing_token] = Some(incoming_token);
-
-            let connection = self.incoming_connections.get(incoming_token).unwrap();
-
-            event_loop.register_opt(connection.incoming_stream(),
-                                    incoming_token.as_raw_token(),
-                                    EventSet::all(),
-                                    PollOpt::edge() | PollOpt::oneshot())
-                      .unwrap();
-            event_loop.register_opt(connection.outgoing_stream(),
-                                    outgoing_token.as_raw_token(),
-                                    EventSet::all(),
-                                    PollOpt::edge() | PollOpt::oneshot())
-                      .unwrap();
-
-            event_loop.reregister(&listener.listener,
-                                  token.as_raw_token(),
-                                  EventSet::readable(),
-                                  PollOpt::edge() | PollOpt::oneshot())
-                      .unwrap();
-        } else {
-            error!("Listener event on unknown token {:?}", token);
-        }
-    }
+        let request = read_head(&mut server);
+        assert!(request.contains("\r\nUpgrade: websocket\r\n"));
 
-    fn incoming_ready(&mut self, token: IncomingToken, events: EventSet) {
-        let mut remove = false;
+        // Frames that follow the 101 response are not HTTP
+        server.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+                           Upgrade: websocket\r\n\r\n\x81\x02hi")
+              .unwrap();
 
-        if let Some(mut connection) = self.incoming_connections.get_mut(token) {
-            connection.incoming_ready(events);
-            let data_sent = connection.tick();
+        let response = read_head(&mut client);
+        assert!(response.starts_with("HTTP/1.1 101 "));
 
-            if !data_sent && (connection.is_incoming_closed() || connection.is_outgoing_closed()) {
-                remove = true;
-            } else {
-                self.to_reregister.insert(token);
-            }
-        } else {
-            warn!("Could not find incoming connection for {:?}", token);
-        }
+        let mut frame = [0; 4];
+        client.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, b"\x81\x02hi");
 
-        if remove {
-            self.remove_connection(token);
-        }
-    }
+        client.write_all(b"\x81\x05hello").unwrap();
 
-    fn outgoing_ready(&mut self, token: OutgoingToken, events: EventSet) {
-        if let Some(&Some(incoming_token)) = self.outgoing_connections.get(token) {
-            let mut remove = false;
+        let mut frame = [0; 7];
+        server.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, b"\x81\x05hello");
 
-            if let Some(mut connection) = self.incoming_connections.get_mut(incoming_token) {
-                connection.outgoing_ready(events);
-                let data_sent = connection.tick();
+        // Closed after websocket_idle_timeout_ms without any frames
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
 
-                if !data_sent && connection.is_outgoing_closed() {
-                    remove = true;
-                } else {
-                    self.to_reregister.insert(incoming_token);
-                }
-            } else {
-                warn!("Could not find corresponding incoming connection for {:?} -> {:?}",
-                      token,
-                      incoming_token);
-            }
+        sender.send(DriverMessage::Shutdown).unwrap();
 
-            if remove {
-                debug!("Clearing connection from {:?} -> {:?}",
-                       token,
-                       incoming_token);
-                self.outgoing_connections[token] = None
-            }
-        } else {
-            warn!("Could not find outgoing connection for {:?}", token);
-        }
+        t1.join().unwrap();
     }
 
-    fn remove_connection(&mut self, token: IncomingToken) {
-        debug!("Removing connection on incoming token {:?}", token);
-        let connection = self.incoming_connections
-                             .remove(token)
-                             .expect("Can't remove already removed incoming connection");
-        self.outgoing_connections
-            .remove(connection.outgoing_token())
-            .expect("Can't remove already removed outgoing connection");
-    }
-}
+    fn serve_http(listener: TcpListener, status_line: &'static str) {
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let mut reader = BufReader::new(client.unwrap());
+                let mut line = String::new();
 
-impl Handler for Driver {
-    type Timeout = ();
-    type Message = DriverMessage;
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    line.clear();
+                }
 
-    fn ready(&mut self, event_loop: &mut EventLoop, token: Token, events: EventSet) {
-        if token == Token(0) {
-            warn!("Should not receive events on Token zero");
-            return;
-        }
+                write!(reader.get_mut(), "{}\r\n\r\nstatus page\n", status_line).unwrap_or(());
+            }
+        });
+    }
 
-        trace!("Events on token {:?}: {:?}", token, events);
+    #[test]
+    fn http_health_check_skips_failing_target() {
+        env_logger::init().unwrap_or(());
 
-        match TokenType::from_raw_token(token) {
-            TokenType::Listener(token) => self.listener_ready(event_loop, token, events),
-            TokenType::Incoming(token) => self.incoming_ready(token, events),
-            TokenType::Outgoing(token) => self.outgoing_ready(token, events),
-        }
-    }
+        let mut event_loop = EventLoop::new().unwrap();
+        let sender = event_loop.channel();
 
-    fn notify(&mut self, event_loop: &mut EventLoop, msg: DriverMessage) {
-        match msg {
-            DriverMessage::Shutdown => event_loop.shutdown(),
-            DriverMessage::Reconfigure(config) =>
-                self.state.reconfigure(event_loop, &config).unwrap(),
-        }
-    }
+        let frontend_port = next_port();
+        let ok_port = next_port();
+        let failing_port = next_port();
 
-    fn tick(&mut self, event_loop: &mut EventLoop) {
-        for token in self.to_reregister.iter() {
-            if let Some(connection) = self.incoming_connections.get(*token) {
-                event_loop.reregister(connection.incoming_stream(),
-                                      token.as_raw_token(),
-                                      EventSet::all(),
-                                      PollOpt::edge() | PollOpt::oneshot())
-                          .unwrap();
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
 
-                event_loop.reregister(connection.outgoing_stream(),
-                                      connection.outgoing_token().as_raw_token(),
-                                      EventSet::all(),
-                                      PollOpt::edge() | PollOpt::oneshot())
-                          .unwrap();
-            }
-        }
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\", \"127.0.0.1:{}\"]
 
-        self.to_reregister.clear();
+[backends.out.health_check]
+type = \"http\"
+path = \"/status\"
+expected_status = [200, 299]
+body_contains = \"status page\"
+interval_ms = 100
+timeout_ms = 100
+rise = 1
+fall = 1
 
-        for token in self.state.listeners_to_remove.iter() {
-            info!("Removing listener on token {:?}", token);
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   ok_port,
+                                                   failing_port))
+                         .unwrap();
 
-            let listener = self.state
-                               .listeners
-                               .remove(*token)
-                               .unwrap();
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
 
-            event_loop.deregister(&listener.listener).unwrap();
-            drop(listener);
-        }
+        serve_http(TcpListener::bind(("127.0.0.1", ok_port)).unwrap(),
+                   "HTTP/1.0 200 OK");
+        serve_http(TcpListener::bind(("127.0.0.1", failing_port)).unwrap(),
+                   "HTTP/1.0 503 Service Unavailable");
 
-        self.state.listeners_to_remove.clear();
-    }
-}
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
 
-#[cfg(test)]
-mod test {
-    use super::{EventLoop, Driver, DriverMessage};
+            event_loop.run(&mut driver).unwrap();
+        });
 
-    use std::thread;
-    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
-    use std::net::{TcpStream, TcpListener, SocketAddr};
-    use std::str::FromStr;
-    use std::io::{Write, BufReader, BufRead};
-    use std::time::Duration;
-    use std::collections::HashMap;
-    use std::default::Default;
+        thread::sleep(Duration::from_millis(500));
 
-    use env_logger;
+        for _ in 0..4 {
+            let mut client = TcpStream::connect(frontend_addr).unwrap();
+            write!(client, "GET / HTTP/1.0\r\n\r\n").unwrap();
 
-    use config::RootConfig;
-    use driver_state::DriverState;
+            let mut reader = BufReader::new(client);
+            let mut buffer = String::new();
+            reader.read_line(&mut buffer).unwrap();
 
-    static PORT_NUMBER: AtomicUsize = ATOMIC_USIZE_INIT;
+            assert_eq!(buffer, "HTTP/1.0 200 OK\r\n");
+        }
 
-    fn next_port() -> u16 {
-        let first_port = option_env!("TEST_BASE_PORT")
-                             .map_or(32328, |v| v.parse::<usize>().unwrap());
-        PORT_NUMBER.compare_and_swap(0, first_port, Ordering::SeqCst);
+        sender.send(DriverMessage::Shutdown).unwrap();
 
-        PORT_NUMBER.fetch_add(1, Ordering::SeqCst) as u16
+        t1.join().unwrap();
     }
 
     #[test]
-    fn start_stop_driver() {
+    fn connect_retries_skip_dead_target() {
         env_logger::init().unwrap_or(());
 
         let mut event_loop = EventLoop::new().unwrap();
         let sender = event_loop.channel();
 
-        let t = thread::spawn(move || {
-            let mut driver = Driver::new(DriverState::new(&Default::default()));
+        let frontend_port = next_port();
+        let dead_port = next_port();
+        let live_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
+backend = \"out\"
+
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\", \"127.0.0.1:{}\"]
+connect_retries = 1
+
+[buffers]
+connections = 4096
+listeners = 128
+",
+                                                   frontend_port,
+                                                   dead_port,
+                                                   live_port))
+                         .unwrap();
+
+        let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
+                                            .unwrap();
+
+        let listener = TcpListener::bind(("127.0.0.1", live_port)).unwrap();
+
+        let t1 = thread::spawn(move || {
+            let mut driver_state = DriverState::new(&Default::default());
+            driver_state.reconfigure(&mut event_loop, &config).unwrap();
+            let mut driver = Driver::new(driver_state);
+
             event_loop.run(&mut driver).unwrap();
         });
 
+        let t2 = thread::spawn(move || {
+            for _ in 0..4 {
+                let (mut client, _) = listener.accept().unwrap();
+
+                write!(client, "sent by backend\n").unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        for _ in 0..4 {
+            let client = TcpStream::connect(frontend_addr).unwrap();
+
+            let mut reader = BufReader::new(client);
+            let mut buffer = String::new();
+            reader.read_line(&mut buffer).unwrap();
+
+            assert_eq!(buffer, "sent by backend\n");
+        }
+
+        t2.join().unwrap();
+
         sender.send(DriverMessage::Shutdown).unwrap();
-        t.join().unwrap();
+
+        t1.join().unwrap();
     }
 
     #[test]
-    fn single_backend() {
+    fn idle_timeout_closes_both_sides() {
         env_logger::init().unwrap_or(());
 
         let mut event_loop = EventLoop::new().unwrap();
         let sender = event_loop.channel();
 
-        let config = RootConfig::from_str(&format!("[frontends.in]
-listen_addr = \
-                                                    \"127.0.0.1:{}\"
+        let frontend_port = next_port();
+        let backend_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
 backend = \"out\"
+idle_timeout_ms = 300
 
-\
-                                                    [backends.out]
-target_addrs = \
-                                                    [\"127.0.0.1:{}\"]
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
 
 [buffers]
-connections = \
-                                                    4096
+connections = 4096
 listeners = 128
 ",
-                                                   next_port(),
-                                                   next_port()))
+                                                   frontend_port,
+                                                   backend_port))
                          .unwrap();
 
-        let backend_addr: SocketAddr = FromStr::from_str(&config.backends["out"].target_addrs[0])
-                                           .unwrap();
         let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
                                             .unwrap();
 
+        let listener = TcpListener::bind(("127.0.0.1", backend_port)).unwrap();
+
         let t1 = thread::spawn(move || {
             let mut driver_state = DriverState::new(&Default::default());
             driver_state.reconfigure(&mut event_loop, &config).unwrap();
             let mut driver = Driver::new(driver_state);
 
-            debug!("Starting event loop");
-
             event_loop.run(&mut driver).unwrap();
         });
 
         let t2 = thread::spawn(move || {
-            debug!("Starting backend listener");
-
-            let listener = TcpListener::bind(backend_addr).unwrap();
             let (mut client, _) = listener.accept().unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
 
             write!(client, "sent by backend\n").unwrap();
-            client.flush().unwrap();
-
-            debug!("Backend wrote data, waiting for data now");
-
-            let mut reader = BufReader::new(client);
-            let mut buffer = String::new();
-            reader.read_line(&mut buffer).unwrap();
-
-            debug!("Backend done");
 
-            assert_eq!(buffer, "sent by frontend\n");
+            // Stay silent until the balancer gives up on the connection
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).unwrap();
         });
 
         thread::sleep(Duration::from_millis(100));
 
-        {
-            debug!("Connecting to frontend...");
-            let client = TcpStream::connect(frontend_addr).unwrap();
-            debug!("Frontend connected, waiting for data...");
-
-            let mut reader = BufReader::new(client);
-            let mut buffer = String::new();
-            reader.read_line(&mut buffer).unwrap();
+        let client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
 
-            debug!("Frontend read data, sending response");
+        let mut reader = BufReader::new(client);
+        let mut buffer = String::new();
+        reader.read_line(&mut buffer).unwrap();
 
-            assert_eq!(buffer, "sent by backend\n");
+        assert_eq!(buffer, "sent by backend\n");
 
-            write!(reader.get_mut(), "sent by frontend\n").unwrap();
-            reader.get_mut().flush().unwrap();
+        buffer.clear();
+        assert_eq!(reader.read_line(&mut buffer).unwrap(), 0);
 
-            debug!("Frontend done");
-        }
+        t2.join().unwrap();
 
         sender.send(DriverMessage::Shutdown).unwrap();
 
         t1.join().unwrap();
-        t2.join().unwrap();
     }
 
     #[test]
-    fn test_reconfigure_remove_listen() {
+    fn removed_frontend_drains_connections() {
         env_logger::init().unwrap_or(());
 
         let mut event_loop = EventLoop::new().unwrap();
         let sender = event_loop.channel();
 
-        let config = RootConfig::from_str(&format!("[frontends.in]
-listen_addr = \
-                                                    \"127.0.0.1:{}\"
+        let frontend_port = next_port();
+        let backend_port = next_port();
+
+        let config = RootConfig::from_str(&format!("
+drain_timeout_ms = 500
+
+[frontends.in]
+listen_addr = \"127.0.0.1:{}\"
 backend = \"out\"
 
-\
-                                                    [backends.out]
-target_addrs = \
-                                                    [\"127.0.0.1:{}\"]
+[backends.out]
+target_addrs = [\"127.0.0.1:{}\"]
 
 [buffers]
-connections = \
-                                                    4096
+connections = 4096
 listeners = 128
 ",
-                                                   next_port(),
-                                                   next_port()))
+                                                   frontend_port,
+                                                   backend_port))
                          .unwrap();
 
         let frontend_addr: SocketAddr = FromStr::from_str(&config.frontends["in"].listen_addr)
                                             .unwrap();
 
+        let listener = TcpListener::bind(("127.0.0.1", backend_port)).unwrap();
+
         let t1 = thread::spawn(move || {
             let mut driver_state = DriverState::new(&Default::default());
             driver_state.reconfigure(&mut event_loop, &config).unwrap();
             let mut driver = Driver::new(driver_state);
 
-            debug!("Starting event loop");
-
             event_loop.run(&mut driver).unwrap();
         });
 
+        // Echo lines back until the balancer closes the connection
+        let t2 = thread::spawn(move || {
+            let (client, _) = listener.accept().unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut reader = BufReader::new(client);
+            let mut line = String::new();
+
+            while reader.read_line(&mut line).unwrap() > 0 {
+                write!(reader.get_mut(), "{}", line).unwrap();
+                line.clear();
+            }
+        });
+
         thread::sleep(Duration::from_millis(100));
 
-        {
-            let client = TcpStream::connect(frontend_addr);
+        let client = TcpStream::connect(frontend_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
 
-            assert!(client.is_ok());
-        }
+        let mut reader = BufReader::new(client);
+        let mut buffer = String::new();
+
+        thread::sleep(Duration::from_millis(100));
 
         sender.send(DriverMessage::Reconfigure(RootConfig {
-                  frontends: HashMap::new(),
-                  backends: HashMap::new(),
+                  drain_timeout_ms: Some(500),
                   ..Default::default()
               }))
-              .expect("Should be able to send reconfigure message");
+              .unwrap();
 
         thread::sleep(Duration::from_millis(100));
 
-        {
-            debug!("Trying to connect, expecting refused connection");
-            let client = TcpStream::connect(frontend_addr);
+        assert!(TcpStream::connect(frontend_addr).is_err());
 
-            assert!(client.is_err());
-        }
+        write!(reader.get_mut(), "still relayed\n").unwrap();
+        reader.read_line(&mut buffer).unwrap();
 
-        sender.send(DriverMessage::Shutdown).expect("Should be able to send shutdown message");
+        assert_eq!(buffer, "still relayed\n");
 
-        t1.join().expect("Event loop thread should have exited cleanly");
+        buffer.clear();
+        assert_eq!(reader.read_line(&mut buffer).unwrap(), 0);
+
+        t2.join().unwrap();
+
+        sender.send(DriverMessage::Shutdown).unwrap();
+
+        t1.join().unwrap();
     }
 }
@@ -2,18 +2,20 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::net::{ToSocketAddrs, SocketAddr};
 use std::collections::{HashMap, HashSet};
-use std::collections::hash_map::Entry::{Occupied, Vacant};
-use std::io::{ErrorKind, Result as IOResult, Error as IOError};
+use std::io::{ErrorKind, Error as IOError};
 
 use mio::{PollOpt, EventSet, Handler, EventLoop};
 use mio::tcp::TcpListener;
 
 use slab::Slab;
 
+use access_log::AccessLog;
 use backend::Backend;
 use frontend::Frontend;
-use connection::ListenerToken;
-use config::{RootConfig, BackendConfig, FrontendConfig, BufferConfig};
+use connection::{ListenerToken, ADMIN_LISTENER_TOKEN, METRICS_LISTENER_TOKEN};
+use config::{RootConfig, BackendConfig, FrontendConfig, FrontendMode, BufferConfig,
+             TargetConfig};
+use tls;
 
 pub struct Listener {
     pub listener: TcpListener,
@@ -22,9 +24,37 @@ pub struct Listener {
     pub token: ListenerToken,
 }
 
+/// Listener for the admin API or the metrics endpoint
+pub struct ServiceListener {
+    pub listener: TcpListener,
+    pub listen_addr: SocketAddr,
+}
+
+#[derive(Debug)]
+pub enum ReconfigureError {
+    ResolveError(String, IOError),
+    UnknownBackend(String),
+    NoBackend(String),
+    HttpOptionsWithoutHttpMode(String),
+    BindError(SocketAddr, IOError),
+    RegisterError(SocketAddr, IOError),
+    ListenerBufferFull,
+    UnknownTarget(SocketAddr),
+    DuplicateTarget(SocketAddr),
+    AccessLogError(String, IOError),
+    TlsError(String, String),
+    ReloadUnavailable,
+}
+
 pub struct DriverState {
     pub listeners: Slab<Listener, ListenerToken>,
     pub listeners_to_remove: HashSet<ListenerToken>,
+    pub backends: HashMap<String, Rc<RefCell<Backend>>>,
+    pub admin: Option<ServiceListener>,
+    pub metrics: Option<ServiceListener>,
+    pub access_log: Option<AccessLog>,
+    pub health_checks_to_start: Vec<String>,
+    pub generation: usize,
     pub config: RootConfig,
 }
 
@@ -33,14 +63,23 @@ impl DriverState {
         DriverState {
             listeners: Slab::new_starting_at(ListenerToken(1), buffers.listeners),
             listeners_to_remove: HashSet::new(),
+            backends: HashMap::new(),
+            admin: None,
+            metrics: None,
+            access_log: None,
+            health_checks_to_start: Vec::new(),
+            generation: 0,
             config: RootConfig { buffers: (*buffers).clone(), ..Default::default() },
         }
     }
 
+    /// Applies a new configuration. Everything is resolved and bound before
+    /// the running state is touched, so on error the old configuration stays
+    /// in effect.
     pub fn reconfigure<T>(&mut self,
                           event_loop: &mut EventLoop<T>,
                           config: &RootConfig)
-                          -> IOResult<()>
+                          -> Result<(), ReconfigureError>
         where T: Handler
     {
         info!("Reconfiguring driver state: {:#?}", config);
@@ -49,100 +88,384 @@ impl DriverState {
         let mut frontends = HashMap::new();
 
         for (name, config) in config.backends.iter() {
-            backends.insert(name, try!(make_backend(config)));
+            let backend = try!(make_backend(name, config));
+
+            if let Some(old) = self.backends.get(name) {
+                backend.borrow_mut().carry_over(&old.borrow());
+            }
+
+            backends.insert(name.clone(), backend);
         }
 
         for (name, config) in config.frontends.iter() {
-            frontends.insert(name, try!(make_frontend(config, &backends)));
+            frontends.insert(name, try!(make_frontend(name, config, &backends)));
         }
 
-        let mut listeners_to_add: HashMap<SocketAddr, Rc<Frontend>> = HashMap::new();
+        let existing_listeners = self.listeners
+                                     .iter()
+                                     .map(|l| (l.listen_addr, l.token))
+                                     .collect::<HashMap<SocketAddr, ListenerToken>>();
+        let mut kept_listeners: HashMap<ListenerToken, Rc<Frontend>> = HashMap::new();
+        let mut new_listeners = Vec::new();
 
-        {
-            let mut listeners_by_addr = self.listeners
-                                            .iter_mut()
-                                            .map(|l| (l.listen_addr, l))
-                                            .collect::<HashMap<SocketAddr, &mut Listener>>();
-
-            for (_, frontend) in frontends {
-                for listen_addr in frontend.listen_addrs() {
-                    match listeners_by_addr.entry(listen_addr) {
-                        Occupied(mut e) => {
-                            e.get_mut().frontend = frontend.clone();
-                            e.remove();
-                        }
-                        Vacant(_) => {
-                            listeners_to_add.insert(listen_addr, frontend.clone());
-                        }
+        for (_, frontend) in frontends {
+            for listen_addr in frontend.listen_addrs() {
+                match existing_listeners.get(&listen_addr) {
+                    Some(token) => {
+                        kept_listeners.insert(*token, frontend.clone());
+                    }
+                    None => {
+                        let tcp_listener = try!(TcpListener::bind(&listen_addr).map_err(|e| {
+                            ReconfigureError::BindError(listen_addr, e)
+                        }));
+                        new_listeners.push((listen_addr, tcp_listener, frontend.clone()));
                     }
                 }
             }
+        }
 
-            for (_, listener) in listeners_by_addr.into_iter() {
-                self.listeners_to_remove.insert(listener.token);
+        let admin_addr = try!(resolve_optional(config.admin.as_ref().map(|a| &a.listen_addr)));
+        let metrics_addr = try!(resolve_optional(config.metrics
+                                                       .as_ref()
+                                                       .map(|m| &m.listen_addr)));
+
+        let new_admin = try!(bind_service_listener(admin_addr, &self.admin));
+        let new_metrics = try!(bind_service_listener(metrics_addr, &self.metrics));
+
+        let reopen_access_log = config.access_log != self.config.access_log;
+        let access_log = match config.access_log {
+            Some(ref log_config) if reopen_access_log => {
+                Some(try!(AccessLog::new(log_config).map_err(|e| {
+                    ReconfigureError::AccessLogError(log_config.path().to_owned(), e)
+                })))
             }
+            _ => None,
+        };
+
+        if self.listeners.remaining() < new_listeners.len() {
+            return Err(ReconfigureError::ListenerBufferFull);
         }
 
-        for (addr, frontend) in listeners_to_add.into_iter() {
-            let tcp_listener = try!(TcpListener::bind(&addr));
-            let token = try!(self.listeners
-                                 .insert_with(|token| {
-                                     Listener {
-                                         listener: tcp_listener,
-                                         listen_addr: addr,
-                                         token: token,
-                                         frontend: frontend,
-                                     }
-                                 })
-                                 .ok_or(IOError::new(ErrorKind::Other, "Listener buffer full")));
+        let mut added_tokens = Vec::new();
+
+        for (addr, tcp_listener, frontend) in new_listeners.into_iter() {
+            let token = self.listeners
+                            .insert_with(|token| {
+                                Listener {
+                                    listener: tcp_listener,
+                                    listen_addr: addr,
+                                    token: token,
+                                    frontend: frontend,
+                                }
+                            })
+                            .expect("Listener buffer space was checked before binding");
+            added_tokens.push(token);
+
             let listener = &self.listeners[token];
 
+            if let Err(e) = event_loop.register_opt(&listener.listener,
+                                                    listener.token.as_raw_token(),
+                                                    EventSet::readable(),
+                                                    PollOpt::edge() | PollOpt::oneshot()) {
+                self.remove_listeners(event_loop, added_tokens);
+
+                return Err(ReconfigureError::RegisterError(addr, e));
+            }
+
             info!("Added listener with token {:?}", token);
+        }
+
+        let services = [(&new_admin, ADMIN_LISTENER_TOKEN, "Admin API"),
+                        (&new_metrics, METRICS_LISTENER_TOKEN, "Metrics")];
+
+        for (i, &(service, token, description)) in services.iter().enumerate() {
+            if let Some(ref service) = *service {
+                if let Err(e) = event_loop.register_opt(&service.listener,
+                                                        token,
+                                                        EventSet::readable(),
+                                                        PollOpt::edge() | PollOpt::oneshot()) {
+                    for &(registered, _, _) in services[..i].iter() {
+                        if let Some(ref registered) = *registered {
+                            event_loop.deregister(&registered.listener).unwrap_or(());
+                        }
+                    }
 
-            try!(event_loop.register_opt(&listener.listener,
-                                         listener.token.as_raw_token(),
-                                         EventSet::readable(),
-                                         PollOpt::edge() | PollOpt::oneshot()));
+                    self.remove_listeners(event_loop, added_tokens);
+
+                    return Err(ReconfigureError::RegisterError(service.listen_addr, e));
+                }
+
+                info!("{} listening on {}", description, service.listen_addr);
+            }
+        }
+
+        replace_service_listener(event_loop, &mut self.admin, new_admin, admin_addr.is_some());
+        replace_service_listener(event_loop,
+                                 &mut self.metrics,
+                                 new_metrics,
+                                 metrics_addr.is_some());
+
+        if reopen_access_log {
+            self.access_log = access_log;
         }
 
+        for (_, token) in existing_listeners {
+            match kept_listeners.remove(&token) {
+                Some(frontend) => {
+                    self.listeners[token].frontend = frontend;
+                    self.listeners_to_remove.remove(&token);
+                }
+                None => {
+                    self.listeners_to_remove.insert(token);
+                }
+            }
+        }
+
+        self.health_checks_to_start = backends.iter()
+                                              .filter(|&(_, backend)| {
+                                                  backend.borrow().health_check().is_some()
+                                              })
+                                              .map(|(name, _)| name.clone())
+                                              .collect();
+        self.backends = backends;
+        self.generation += 1;
         self.config = (*config).clone();
 
         Ok(())
     }
+
+    fn remove_listeners<T>(&mut self, event_loop: &mut EventLoop<T>, tokens: Vec<ListenerToken>)
+        where T: Handler
+    {
+        for token in tokens {
+            if let Some(listener) = self.listeners.remove(token) {
+                event_loop.deregister(&listener.listener).unwrap_or(());
+            }
+        }
+    }
+
+    /// Adds a target to a running backend, and to the current configuration
+    pub fn add_target(&mut self,
+                      backend_name: &str,
+                      target: &TargetConfig)
+                      -> Result<(), ReconfigureError> {
+        let addr = try!(resolve_name(&target.addr));
+        let backend = try!(self.backend(backend_name));
+
+        if !backend.borrow_mut().add_target(addr, target.weight()) {
+            return Err(ReconfigureError::DuplicateTarget(addr));
+        }
+
+        if let Some(config) = self.config.backends.get_mut(backend_name) {
+            config.targets.push(target.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Removes a target from a running backend and from the current
+    /// configuration. Connections to it are left to drain.
+    pub fn remove_target(&mut self,
+                         backend_name: &str,
+                         addr: &str)
+                         -> Result<(), ReconfigureError> {
+        let addr = try!(resolve_name(addr));
+        let backend = try!(self.backend(backend_name));
+
+        if !backend.borrow_mut().remove_target(addr) {
+            return Err(ReconfigureError::UnknownTarget(addr));
+        }
+
+        // Configured names may have resolved to other addresses since the
+        // backend was made, so entries are matched by what they resolve to now
+        if let Some(config) = self.config.backends.get_mut(backend_name) {
+            config.target_addrs.retain(|a| resolve_name(a).ok() != Some(addr));
+            config.targets.retain(|t| resolve_name(&t.addr).ok() != Some(addr));
+        }
+
+        Ok(())
+    }
+
+    /// Enables or drains a target. This only affects the running backend,
+    /// and lasts across reconfigurations for as long as the target does.
+    pub fn set_target_enabled(&mut self,
+                              backend_name: &str,
+                              addr: &str,
+                              enabled: bool)
+                              -> Result<(), ReconfigureError> {
+        let addr = try!(resolve_name(addr));
+        let backend = try!(self.backend(backend_name));
+
+        if backend.borrow_mut().set_target_enabled(addr, enabled) {
+            Ok(())
+        } else {
+            Err(ReconfigureError::UnknownTarget(addr))
+        }
+    }
+
+    /// The running frontend called `name`, unless a reconfiguration removed
+    /// it
+    pub fn frontend(&self, name: &str) -> Option<Rc<Frontend>> {
+        self.listeners
+            .iter()
+            .filter(|l| !self.listeners_to_remove.contains(&l.token))
+            .find(|l| l.frontend.name() == name)
+            .map(|l| l.frontend.clone())
+    }
+
+    fn backend(&self, name: &str) -> Result<Rc<RefCell<Backend>>, ReconfigureError> {
+        self.backends
+            .get(name)
+            .cloned()
+            .ok_or(ReconfigureError::UnknownBackend(name.to_owned()))
+    }
 }
 
-fn resolve_name(s: &str) -> IOResult<SocketAddr> {
-    let addrs: Vec<SocketAddr> = try!(s.to_socket_addrs()).collect();
+fn resolve_optional(s: Option<&String>) -> Result<Option<SocketAddr>, ReconfigureError> {
+    match s {
+        Some(s) => resolve_name(s).map(Some),
+        None => Ok(None),
+    }
+}
 
-    assert_eq!(addrs.len(), 1);
+/// Binds a new admin or metrics listener, unless the running one already
+/// listens on the configured address
+fn bind_service_listener(addr: Option<SocketAddr>,
+                         current: &Option<ServiceListener>)
+                         -> Result<Option<ServiceListener>, ReconfigureError> {
+    match addr {
+        Some(addr) if current.as_ref().map(|l| l.listen_addr) != Some(addr) => {
+            let listener = try!(TcpListener::bind(&addr)
+                                    .map_err(|e| ReconfigureError::BindError(addr, e)));
 
-    Ok(addrs[0])
+            Ok(Some(ServiceListener {
+                listener: listener,
+                listen_addr: addr,
+            }))
+        }
+        _ => Ok(None),
+    }
 }
 
-fn make_backend(config: &BackendConfig) -> IOResult<Rc<RefCell<Backend>>> {
-    let target_addrs = config.target_addrs
-                             .iter()
-                             .flat_map(|s| {
-                                 match resolve_name(s) {
-                                     Ok(a) => Ok(a),
-                                     Err(e) => {
-                                         println!("Could not resolve TARGET argument {}: {}", s, e);
-                                         Err(e)
-                                     }
-                                 }
-                             })
-                             .collect::<Vec<SocketAddr>>();
-
-    if target_addrs.len() != config.target_addrs.len() {
-        Err(IOError::new(ErrorKind::NotFound, "Could not resolve target address"))
-    } else {
-        Ok(Backend::new(target_addrs))
+fn replace_service_listener<T>(event_loop: &mut EventLoop<T>,
+                               current: &mut Option<ServiceListener>,
+                               new: Option<ServiceListener>,
+                               enabled: bool)
+    where T: Handler
+{
+    if new.is_some() || !enabled {
+        if let Some(old) = current.take() {
+            event_loop.deregister(&old.listener).unwrap_or(());
+        }
+
+        *current = new;
     }
 }
 
-fn make_frontend(config: &FrontendConfig,
-                 backends: &HashMap<&String, Rc<RefCell<Backend>>>)
-                 -> IOResult<Rc<Frontend>> {
-    Ok(Frontend::new(try!(resolve_name(&config.listen_addr)),
-                     vec![backends[&config.backend].clone()]))
+fn resolve_name(s: &str) -> Result<SocketAddr, ReconfigureError> {
+    let mut addrs = try!(s.to_socket_addrs()
+                          .map_err(|e| ReconfigureError::ResolveError(s.to_owned(), e)));
+
+    addrs.next().ok_or_else(|| {
+        ReconfigureError::ResolveError(s.to_owned(),
+                                       IOError::new(ErrorKind::NotFound, "No address found"))
+    })
+}
+
+fn make_backend(name: &str,
+                config: &BackendConfig)
+                -> Result<Rc<RefCell<Backend>>, ReconfigureError> {
+    let mut targets = Vec::new();
+
+    for target in config.all_targets() {
+        targets.push((try!(resolve_name(&target.addr)), target.weight()));
+    }
+
+    // Like frontend certificates, the CA bundle and client certificate are
+    // read again on every reconfiguration
+    let tls = if config.tls.unwrap_or(false) {
+        Some(try!(tls::make_connector(config.tls_ca_path.as_ref().map(|p| &p[..]),
+                                      config.tls_client_certificate.as_ref())
+                      .map_err(|e| ReconfigureError::TlsError(name.to_owned(), e))))
+    } else {
+        None
+    };
+
+    Ok(Backend::new(name, targets, tls, config))
+}
+
+fn make_frontend(name: &str,
+                 config: &FrontendConfig,
+                 backends: &HashMap<String, Rc<RefCell<Backend>>>)
+                 -> Result<Rc<Frontend>, ReconfigureError> {
+    let mut weighted_backends = Vec::new();
+
+    for backend in config.all_backends() {
+        let weight = backend.weight();
+        let backend = try!(backends.get(&backend.name)
+                                   .ok_or(ReconfigureError::UnknownBackend(backend.name)));
+
+        weighted_backends.push((backend.clone(), weight));
+    }
+
+    if weighted_backends.is_empty() {
+        return Err(ReconfigureError::NoBackend(name.to_owned()));
+    }
+
+    // Certificates are read again on every reconfiguration, so replaced
+    // files are picked up by a reload
+    let tls = if config.certificates.is_empty() {
+        None
+    } else {
+        Some(try!(tls::make_acceptor(&config.certificates)
+                      .map_err(|e| ReconfigureError::TlsError(name.to_owned(), e))))
+    };
+
+    let mut routes = Vec::new();
+
+    for route in config.routes.iter() {
+        let backend = try!(backends.get(&route.backend)
+                                   .ok_or(ReconfigureError::UnknownBackend(route.backend
+                                                                                .clone())));
+
+        routes.push((route.sni.clone(), backend.clone()));
+    }
+
+    let has_http_options = !config.http_routes.is_empty() ||
+                           config.forwarded_headers.is_some() ||
+                           config.request_headers.is_some() ||
+                           config.response_headers.is_some() ||
+                           config.websocket_idle_timeout_ms.is_some();
+
+    if has_http_options && config.mode() != FrontendMode::Http {
+        return Err(ReconfigureError::HttpOptionsWithoutHttpMode(name.to_owned()));
+    }
+
+    let mut http_routes = Vec::new();
+
+    for route in config.http_routes.iter() {
+        let backend = try!(backends.get(&route.backend)
+                                   .ok_or(ReconfigureError::UnknownBackend(route.backend
+                                                                                .clone())));
+
+        http_routes.push((route.clone(), backend.clone()));
+    }
+
+    let mirror_backend = match config.mirror_backend {
+        Some(ref name) => {
+            Some(try!(backends.get(name)
+                              .cloned()
+                              .ok_or(ReconfigureError::UnknownBackend(name.clone()))))
+        }
+        None => None,
+    };
+
+    Ok(Frontend::new(name,
+                     try!(resolve_name(&config.listen_addr)),
+                     weighted_backends,
+                     routes,
+                     http_routes,
+                     mirror_backend,
+                     tls,
+                     config))
 }
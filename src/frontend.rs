@@ -1,27 +1,275 @@
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+
+use openssl::ssl::SslAcceptor;
 
 use backend::Backend;
+use config::{FrontendConfig, FrontendMode, HeaderRulesConfig, HttpRouteConfig};
+use http::RequestHead;
+use tls;
+
+struct WeightedBackend {
+    backend: Rc<RefCell<Backend>>,
+    weight: usize,
+    current_weight: Cell<isize>,
+}
 
 pub struct Frontend {
+    name: String,
     listen_addr: SocketAddr,
-    backends: Vec<Rc<RefCell<Backend>>>,
+    backends: Vec<WeightedBackend>,
+    routes: Vec<(String, Rc<RefCell<Backend>>)>,
+    http_routes: Vec<(HttpRouteConfig, Rc<RefCell<Backend>>)>,
+    mirror_backend: Option<Rc<RefCell<Backend>>>,
+    tls: Option<SslAcceptor>,
+    config: FrontendConfig,
 }
 
 impl Frontend {
-    pub fn new(listen_addr: SocketAddr, backends: Vec<Rc<RefCell<Backend>>>) -> Rc<Frontend> {
+    pub fn new(name: &str,
+               listen_addr: SocketAddr,
+               backends: Vec<(Rc<RefCell<Backend>>, usize)>,
+               routes: Vec<(String, Rc<RefCell<Backend>>)>,
+               http_routes: Vec<(HttpRouteConfig, Rc<RefCell<Backend>>)>,
+               mirror_backend: Option<Rc<RefCell<Backend>>>,
+               tls: Option<SslAcceptor>,
+               config: &FrontendConfig)
+               -> Rc<Frontend> {
         Rc::new(Frontend {
+            name: name.to_owned(),
             listen_addr: listen_addr,
-            backends: backends,
+            backends: backends.into_iter()
+                              .map(|(backend, weight)| {
+                                  WeightedBackend {
+                                      backend: backend,
+                                      weight: weight,
+                                      current_weight: Cell::new(0),
+                                  }
+                              })
+                              .collect(),
+            routes: routes,
+            http_routes: http_routes,
+            mirror_backend: mirror_backend,
+            tls: tls,
+            config: config.clone(),
         })
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn listen_addrs(&self) -> Vec<SocketAddr> {
         vec![self.listen_addr]
     }
 
-    pub fn decide_backend(&self) -> Rc<RefCell<Backend>> {
-        self.backends[0].clone()
+    /// Picks the backend of the first route matching the server name sent
+    /// by the client, then of the first HTTP route matching the request, or
+    /// one of the default backends by their weights
+    pub fn decide_backend(&self,
+                          server_name: Option<&str>,
+                          request: Option<&RequestHead>)
+                          -> Rc<RefCell<Backend>> {
+        if let Some(server_name) = server_name {
+            for &(ref pattern, ref backend) in self.routes.iter() {
+                if tls::matches_server_name(pattern, server_name) {
+                    return backend.clone();
+                }
+            }
+        }
+
+        if let Some(request) = request {
+            for &(ref route, ref backend) in self.http_routes.iter() {
+                if matches_http_route(route, request) {
+                    return backend.clone();
+                }
+            }
+        }
+
+        self.weighted_backend()
+    }
+
+    fn weighted_backend(&self) -> Rc<RefCell<Backend>> {
+        // Smooth weighted round-robin, like the one used to pick targets
+        let mut total_weight = 0;
+        let mut best: Option<&WeightedBackend> = None;
+
+        for backend in self.backends.iter().filter(|b| b.weight > 0) {
+            backend.current_weight.set(backend.current_weight.get() + backend.weight as isize);
+            total_weight += backend.weight as isize;
+
+            best = match best {
+                Some(b) if b.current_weight.get() >= backend.current_weight.get() => Some(b),
+                _ => Some(backend),
+            };
+        }
+
+        match best {
+            Some(b) => {
+                b.current_weight.set(b.current_weight.get() - total_weight);
+                b.backend.clone()
+            }
+            None => self.backends[0].backend.clone(),
+        }
+    }
+
+    pub fn backend_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let backends = self.backends.iter().map(|b| &b.backend);
+
+        let routed = self.routes
+                         .iter()
+                         .map(|&(_, ref b)| b)
+                         .chain(self.http_routes.iter().map(|&(_, ref b)| b));
+
+        for backend in backends.chain(routed) {
+            let name = backend.borrow().name().to_owned();
+
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        names
+    }
+
+    /// The backend that gets a copy of the traffic from clients
+    pub fn mirror_backend(&self) -> Option<&Rc<RefCell<Backend>>> {
+        self.mirror_backend.as_ref()
+    }
+
+    /// True if the backend is picked by the server name in the ClientHello
+    pub fn has_routes(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    /// True if clients are expected to speak HTTP/1.x, and the backend is
+    /// picked by the first request
+    pub fn is_http(&self) -> bool {
+        self.config.mode() == FrontendMode::Http
+    }
+
+    /// True if X-Forwarded-For, X-Forwarded-Proto and X-Request-Id are
+    /// added to requests on HTTP frontends
+    pub fn forwarded_headers(&self) -> bool {
+        self.config.forwarded_headers.unwrap_or(true)
+    }
+
+    pub fn request_headers(&self) -> Option<&HeaderRulesConfig> {
+        self.config.request_headers.as_ref()
+    }
+
+    pub fn response_headers(&self) -> Option<&HeaderRulesConfig> {
+        self.config.response_headers.as_ref()
+    }
+
+    pub fn connect_timeout_ms(&self) -> Option<u64> {
+        self.config.connect_timeout_ms
+    }
+
+    pub fn idle_timeout_ms(&self) -> Option<u64> {
+        self.config.idle_timeout_ms
+    }
+
+    pub fn max_lifetime_ms(&self) -> Option<u64> {
+        self.config.max_lifetime_ms
+    }
+
+    /// The idle timeout for HTTP connections that were upgraded to
+    /// WebSocket or another protocol, used instead of `idle_timeout_ms`
+    pub fn websocket_idle_timeout_ms(&self) -> Option<u64> {
+        self.config.websocket_idle_timeout_ms
+    }
+
+    /// The TLS acceptor for frontends that terminate TLS
+    pub fn tls(&self) -> Option<&SslAcceptor> {
+        self.tls.as_ref()
+    }
+
+    pub fn accept_proxy_protocol(&self) -> bool {
+        self.config.accept_proxy_protocol.unwrap_or(false)
+    }
+}
+
+fn matches_http_route(route: &HttpRouteConfig, request: &RequestHead) -> bool {
+    let host_matches = match (route.host.as_ref(), request.host()) {
+        (Some(pattern), Some(host)) => tls::matches_server_name(pattern, host),
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+
+    host_matches && route.path_prefix.as_ref().map_or(true, |p| request.path().starts_with(&p[..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Frontend;
+
+    use std::str::FromStr;
+
+    use backend::Backend;
+    use config::{BackendConfig, FrontendConfig, HttpRouteConfig};
+    use http::parse_request_head;
+
+    #[test]
+    fn backends_are_split_by_weight() {
+        let backend = |name| Backend::new(name, Vec::new(), None, &BackendConfig::default());
+        let backends = vec![(backend("v1"), 3), (backend("v2"), 1), (backend("v3"), 0)];
+        let frontend = Frontend::new("in",
+                                     FromStr::from_str("127.0.0.1:3000").unwrap(),
+                                     backends,
+                                     vec![("*.example.com".to_owned(), backend("routed"))],
+                                     Vec::new(),
+                                     None,
+                                     None,
+                                     &FrontendConfig::default());
+
+        let picked = (0..8)
+                         .map(|_| frontend.decide_backend(None, None).borrow().name().to_owned())
+                         .collect::<Vec<_>>();
+
+        assert_eq!(picked, vec!["v1", "v1", "v2", "v1", "v1", "v1", "v2", "v1"]);
+        assert_eq!(frontend.decide_backend(Some("www.example.com"), None).borrow().name(),
+                   "routed");
+    }
+
+    #[test]
+    fn requests_are_routed_by_host_and_path() {
+        let backend = |name: &str| Backend::new(name, Vec::new(), None, &BackendConfig::default());
+        let route = |host: Option<&str>, path_prefix: Option<&str>, name: &str| {
+            (HttpRouteConfig {
+                host: host.map(|h| h.to_owned()),
+                path_prefix: path_prefix.map(|p| p.to_owned()),
+                backend: name.to_owned(),
+            },
+             backend(name))
+        };
+        let frontend = Frontend::new("in",
+                                     FromStr::from_str("127.0.0.1:3000").unwrap(),
+                                     vec![(backend("default"), 1)],
+                                     Vec::new(),
+                                     vec![route(Some("api.example.com"), Some("/v2/"), "v2"),
+                                          route(Some("*.example.com"), None, "www"),
+                                          route(None, Some("/static/"), "static")],
+                                     None,
+                                     None,
+                                     &FrontendConfig::default());
+
+        let picked = |request: &[u8]| {
+            let head = parse_request_head(request).unwrap().unwrap();
+            let backend = frontend.decide_backend(None, Some(&head));
+            let name = backend.borrow().name().to_owned();
+
+            name
+        };
+
+        assert_eq!(picked(b"GET /v2/users HTTP/1.1\r\nHost: API.example.com:80\r\n\r\n"),
+                   "v2");
+        assert_eq!(picked(b"GET /v1/users HTTP/1.1\r\nHost: api.example.com\r\n\r\n"),
+                   "www");
+        assert_eq!(picked(b"GET /static/a.css HTTP/1.1\r\nHost: other.com\r\n\r\n"),
+                   "static");
+        assert_eq!(picked(b"GET / HTTP/1.0\r\n\r\n"), "default");
     }
 }
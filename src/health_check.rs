@@ -0,0 +1,251 @@
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use mio::{EventSet, Timeout, TryRead, TryWrite};
+use mio::tcp::TcpStream;
+
+use backend::Backend;
+use config::{HealthCheckConfig, HealthCheckType};
+use proxy_protocol;
+use tls::Stream;
+
+// Responses are only inspected up to this size, anything after it is ignored
+const MAX_RESPONSE_SIZE: usize = 16384;
+
+enum ProbeState {
+    Connecting,
+    SendingHeader(Vec<u8>, usize),
+    Handshaking,
+    Sending(Vec<u8>, usize),
+    Receiving(Vec<u8>),
+}
+
+pub struct Probe {
+    stream: Stream,
+    backend: Rc<RefCell<Backend>>,
+    target: SocketAddr,
+    config: HealthCheckConfig,
+    state: ProbeState,
+    timeout: Option<Timeout>,
+}
+
+impl Probe {
+    pub fn new(stream: Stream,
+               backend: Rc<RefCell<Backend>>,
+               target: SocketAddr,
+               config: &HealthCheckConfig)
+               -> Probe {
+        Probe {
+            stream: stream,
+            backend: backend,
+            target: target,
+            config: config.clone(),
+            state: ProbeState::Connecting,
+            timeout: None,
+        }
+    }
+
+    pub fn stream<'a>(&'a self) -> &'a TcpStream {
+        self.stream.get_ref()
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn take_timeout(&mut self) -> Option<Timeout> {
+        self.timeout.take()
+    }
+
+    pub fn interest(&self) -> EventSet {
+        let interest = match self.state {
+            ProbeState::Connecting |
+            ProbeState::SendingHeader(..) |
+            ProbeState::Sending(..) => EventSet::writable(),
+            ProbeState::Handshaking => EventSet::readable() | EventSet::writable(),
+            ProbeState::Receiving(_) => EventSet::readable(),
+        };
+
+        interest | EventSet::error() | EventSet::hup()
+    }
+
+    /// Returns the outcome of the probe once it is known
+    pub fn ready(&mut self, events: EventSet) -> Option<bool> {
+        if let ProbeState::Connecting = self.state {
+            if !(events.is_writable() || events.is_error() || events.is_hup()) {
+                return None;
+            }
+
+            if self.stream.get_ref().take_socket_error().is_err() {
+                return Some(false);
+            }
+
+            if self.stream.is_handshaking() {
+                // The PROXY header goes to the socket ahead of the handshake
+                self.state = ProbeState::SendingHeader(self.proxy_header(), 0);
+            } else {
+                match self.config.check_type() {
+                    HealthCheckType::Tcp => return Some(true),
+                    HealthCheckType::Http => {
+                        let mut request = self.proxy_header();
+                        request.extend(self.http_request());
+
+                        self.state = ProbeState::Sending(request, 0);
+                    }
+                }
+            }
+        }
+
+        if let ProbeState::SendingHeader(ref header, ref mut offset) = self.state {
+            if events.is_error() {
+                return Some(false);
+            }
+
+            match send(self.stream.get_mut(), header, offset) {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => {
+                    debug!("Health check of {} failed to send header: {}", self.target, e);
+                    return Some(false);
+                }
+            }
+        }
+
+        if let ProbeState::SendingHeader(..) = self.state {
+            self.state = ProbeState::Handshaking;
+        }
+
+        if let ProbeState::Handshaking = self.state {
+            match self.stream.handshake() {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) => {
+                    debug!("Health check of {} failed TLS handshake: {}", self.target, e);
+                    return Some(false);
+                }
+            }
+
+            match self.config.check_type() {
+                HealthCheckType::Tcp => return Some(true),
+                HealthCheckType::Http => {
+                    self.state = ProbeState::Sending(self.http_request(), 0);
+                }
+            }
+        }
+
+        if let ProbeState::Sending(ref request, ref mut offset) = self.state {
+            if events.is_error() {
+                return Some(false);
+            }
+
+            match send(&mut self.stream, request, offset) {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => {
+                    debug!("Health check of {} failed to send request: {}", self.target, e);
+                    return Some(false);
+                }
+            }
+        }
+
+        if let ProbeState::Sending(..) = self.state {
+            self.state = ProbeState::Receiving(Vec::new());
+            return None;
+        }
+
+        if let ProbeState::Receiving(ref mut response) = self.state {
+            let mut buf = [0; 4096];
+
+            loop {
+                match self.stream.try_read(&mut buf) {
+                    Ok(Some(0)) => break,
+                    Ok(Some(n)) => {
+                        response.extend(buf[..n].iter().cloned());
+
+                        if response.len() >= MAX_RESPONSE_SIZE {
+                            break;
+                        }
+                    }
+                    Ok(None) => return None,
+                    Err(e) => {
+                        debug!("Health check of {} failed to read response: {}",
+                               self.target,
+                               e);
+                        return Some(false);
+                    }
+                }
+            }
+
+            return Some(check_http_response(&self.config, response));
+        }
+
+        None
+    }
+
+    pub fn finish(self, healthy: bool) {
+        self.backend.borrow_mut().report_health(self.target, healthy);
+    }
+
+    fn proxy_header(&self) -> Vec<u8> {
+        match self.backend.borrow().send_proxy_protocol() {
+            Some(version) => proxy_protocol::local_header(version),
+            None => Vec::new(),
+        }
+    }
+
+    fn http_request(&self) -> Vec<u8> {
+        let host = match self.config.host {
+            Some(ref host) => host.clone(),
+            None => format!("{}", self.target),
+        };
+
+        format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\
+                 User-Agent: loadbalancer-health-check\r\n\r\n",
+                self.config.path(),
+                host)
+            .into_bytes()
+    }
+}
+
+/// Writes as much of `data` as the socket takes. Returns true once all of
+/// it has been sent.
+fn send<W: TryWrite>(dest: &mut W, data: &[u8], offset: &mut usize) -> io::Result<bool> {
+    if let Some(n) = try!(dest.try_write(&data[*offset..])) {
+        *offset += n;
+    }
+
+    Ok(*offset == data.len())
+}
+
+fn check_http_response(config: &HealthCheckConfig, response: &[u8]) -> bool {
+    // Status line looks like "HTTP/1.1 200 OK"
+    let status = response.split(|&b| b == b' ')
+                         .nth(1)
+                         .and_then(|s| String::from_utf8_lossy(s).parse::<u16>().ok());
+
+    let (min_status, max_status) = config.expected_status();
+
+    match status {
+        Some(status) if status >= min_status && status <= max_status => {}
+        _ => return false,
+    }
+
+    match config.body_contains {
+        Some(ref needle) => {
+            let needle = needle.as_bytes();
+            let body = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(i) => &response[i + 4..],
+                None => return false,
+            };
+
+            needle.is_empty() || body.windows(needle.len()).any(|w| w == needle)
+        }
+        None => true,
+    }
+}
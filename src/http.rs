@@ -0,0 +1,593 @@
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config::HeaderRulesConfig;
+
+/// Request and response heads larger than this are rejected
+pub const MAX_HEAD_SIZE: usize = 16384;
+
+static REQUEST_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The request line and headers of an HTTP/1.x request
+#[derive(Debug, PartialEq, Clone)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub length: usize,
+}
+
+/// The status line and headers of an HTTP/1.x response
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub length: usize,
+}
+
+/// How the end of a message body is found
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BodyLength {
+    Empty,
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// Follows a message body through the bytes that come after its head
+#[derive(Debug)]
+pub struct BodyReader {
+    state: BodyState,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum BodyState {
+    Length(u64),
+    UntilClose,
+    ChunkSize(u64, usize),
+    ChunkExtension(u64),
+    ChunkSizeLf(u64),
+    ChunkData(u64),
+    ChunkDataCr,
+    ChunkDataLf,
+    Trailer(usize),
+    TrailerLf(usize),
+    Done,
+}
+
+impl RequestHead {
+    /// The value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// The host the request is for, without a port. Absolute-form targets
+    /// take precedence over the `Host` header.
+    pub fn host(&self) -> Option<&str> {
+        let host = match split_absolute_target(&self.target) {
+            Some((authority, _)) => Some(authority),
+            None => self.header("Host"),
+        };
+
+        host.map(strip_port).and_then(|h| if h.is_empty() { None } else { Some(h) })
+    }
+
+    /// The path and query of the request target
+    pub fn path(&self) -> &str {
+        match split_absolute_target(&self.target) {
+            Some((_, path)) => path,
+            None => &self.target,
+        }
+    }
+
+    pub fn body_length(&self) -> Result<BodyLength, String> {
+        // Requests with both are a classic way of smuggling a second
+        // request past a proxy, since not every server picks the same one
+        if self.header("Transfer-Encoding").is_some() && self.header("Content-Length").is_some() {
+            return Err("Request has both Transfer-Encoding and Content-Length".to_owned());
+        }
+
+        match try!(framing_length(&self.headers)) {
+            Some(BodyLength::UntilClose) => {
+                Err("Request body is not chunked or of known length".to_owned())
+            }
+            Some(length) => Ok(length),
+            None => Ok(BodyLength::Empty),
+        }
+    }
+
+    /// True if the client wants to send more requests on the connection
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    /// The protocol the client asks to switch to, like `websocket`
+    pub fn upgrade(&self) -> Option<&str> {
+        if has_connection_option(&self.headers, "upgrade") {
+            self.header("Upgrade")
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let start_line = format!("{} {} {}", self.method, self.target, self.version);
+
+        head_bytes(&start_line, &self.headers)
+    }
+}
+
+impl ResponseHead {
+    /// True for 1xx responses, which are followed by another response to
+    /// the same request
+    pub fn is_interim(&self) -> bool {
+        self.status >= 100 && self.status < 200
+    }
+
+    /// The length of the body, which also depends on the request that was
+    /// answered
+    pub fn body_length(&self, request_method: &str) -> Result<BodyLength, String> {
+        if request_method == "HEAD" || self.is_interim() || self.status == 204 ||
+           self.status == 304 {
+            return Ok(BodyLength::Empty);
+        }
+
+        Ok(try!(framing_length(&self.headers)).unwrap_or(BodyLength::UntilClose))
+    }
+
+    /// True if the server will take more requests on the connection
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let start_line = format!("{} {} {}", self.version, self.status, self.reason);
+
+        head_bytes(&start_line, &self.headers)
+    }
+}
+
+impl BodyReader {
+    pub fn new(length: BodyLength) -> BodyReader {
+        let state = match length {
+            BodyLength::Empty | BodyLength::Length(0) => BodyState::Done,
+            BodyLength::Length(n) => BodyState::Length(n),
+            BodyLength::Chunked => BodyState::ChunkSize(0, 0),
+            BodyLength::UntilClose => BodyState::UntilClose,
+        };
+
+        BodyReader { state: state }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == BodyState::Done
+    }
+
+    /// Returns how many bytes at the start of `buf` belong to the body
+    pub fn consume(&mut self, buf: &[u8]) -> Result<usize, String> {
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            self.state = match self.state {
+                BodyState::Done => break,
+                BodyState::UntilClose => return Ok(buf.len()),
+                BodyState::Length(n) | BodyState::ChunkData(n) => {
+                    let taken = n.min((buf.len() - pos) as u64);
+                    pos += taken as usize;
+
+                    match self.state {
+                        BodyState::Length(_) if taken == n => BodyState::Done,
+                        BodyState::Length(_) => BodyState::Length(n - taken),
+                        _ if taken == n => BodyState::ChunkDataCr,
+                        _ => BodyState::ChunkData(n - taken),
+                    }
+                }
+                state => {
+                    pos += 1;
+                    try!(next_chunk_state(state, buf[pos - 1]))
+                }
+            };
+        }
+
+        Ok(pos)
+    }
+}
+
+/// Parses a request head at the start of `buf`. Returns `None` while it is
+/// still incomplete, and an error if the client isn't speaking HTTP/1.x.
+pub fn parse_request_head(buf: &[u8]) -> Result<Option<RequestHead>, String> {
+    let head = try!(parse_head(buf, parse_request_line));
+
+    Ok(head.map(|((method, target, version), headers, length)| {
+        RequestHead {
+            method: method,
+            target: target,
+            version: version,
+            headers: headers,
+            length: length,
+        }
+    }))
+}
+
+/// Parses a response head at the start of `buf`, like
+/// `parse_request_head`
+pub fn parse_response_head(buf: &[u8]) -> Result<Option<ResponseHead>, String> {
+    let head = try!(parse_head(buf, parse_status_line));
+
+    Ok(head.map(|((version, status, reason), headers, length)| {
+        ResponseHead {
+            version: version,
+            status: status,
+            reason: reason,
+            headers: headers,
+            length: length,
+        }
+    }))
+}
+
+/// Removes, sets and adds headers, in that order, as configured on a
+/// frontend
+pub fn apply_header_rules(rules: &HeaderRulesConfig, headers: &mut Vec<(String, String)>) {
+    for name in rules.remove.iter() {
+        remove_header(headers, name);
+    }
+
+    for header in rules.set.iter() {
+        set_header(headers, &header.name, &header.value);
+    }
+
+    for header in rules.add.iter() {
+        headers.push((header.name.clone(), header.value.clone()));
+    }
+}
+
+/// Replaces all headers called `name` with a single one
+pub fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    remove_header(headers, name);
+    headers.push((name.to_owned(), value.to_owned()));
+}
+
+pub fn remove_header(headers: &mut Vec<(String, String)>, name: &str) {
+    headers.retain(|&(ref n, _)| !n.eq_ignore_ascii_case(name));
+}
+
+/// Makes an ID for a request, unique as long as the clock doesn't go
+/// backwards between restarts
+pub fn request_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let count = REQUEST_COUNTER.fetch_add(1, Ordering::SeqCst);
+
+    format!("{:08x}{:08x}-{:08x}",
+            now.as_secs(),
+            now.subsec_nanos(),
+            count as u32)
+}
+
+/// A minimal response for requests that are rejected before reaching a
+/// target
+pub fn error_response(status: u16, reason: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status,
+            reason)
+        .into_bytes()
+}
+
+fn parse_head<T, F>(buf: &[u8],
+                    parse_start_line: F)
+                    -> Result<Option<(T, Vec<(String, String)>, usize)>, String>
+    where F: Fn(&str) -> Result<T, String>
+{
+    let line_end = match find(buf, b"\r\n") {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+
+    // The start line is checked on its own first, so that other protocols
+    // are rejected without waiting for a complete head
+    let line = try!(str::from_utf8(&buf[..line_end])
+                        .map_err(|_| "Start line is not valid UTF-8".to_owned()));
+    let start = try!(parse_start_line(line));
+
+    let end = match find(buf, b"\r\n\r\n") {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+
+    let mut headers = Vec::new();
+
+    if end > line_end {
+        let lines = try!(str::from_utf8(&buf[line_end + 2..end])
+                             .map_err(|_| "Headers are not valid UTF-8".to_owned()));
+
+        for line in lines.split("\r\n") {
+            headers.push(try!(parse_header_line(line)));
+        }
+    }
+
+    Ok(Some((start, headers, end + 4)))
+}
+
+fn parse_request_line(line: &str) -> Result<(String, String, String), String> {
+    let parts = line.split(' ').collect::<Vec<_>>();
+
+    if parts.len() != 3 || parts[0].is_empty() || parts[1].is_empty() ||
+       !parts[2].starts_with("HTTP/1.") {
+        return Err(format!("Invalid request line \"{}\"", line));
+    }
+
+    Ok((parts[0].to_owned(), parts[1].to_owned(), parts[2].to_owned()))
+}
+
+fn parse_status_line(line: &str) -> Result<(String, u16, String), String> {
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let status = parts.next().and_then(|s| s.parse::<u16>().ok());
+
+    match status {
+        Some(status) if version.starts_with("HTTP/1.") && status >= 100 && status < 1000 => {
+            Ok((version.to_owned(), status, parts.next().unwrap_or("").to_owned()))
+        }
+        _ => Err(format!("Invalid status line \"{}\"", line)),
+    }
+}
+
+/// The body length given by the Transfer-Encoding or Content-Length
+/// headers, if there are any
+fn framing_length(headers: &[(String, String)]) -> Result<Option<BodyLength>, String> {
+    if let Some(encoding) = header(headers, "Transfer-Encoding") {
+        let last = encoding.rsplit(',').next().unwrap_or("").trim();
+
+        // Other encodings only end when the connection closes
+        return Ok(Some(if last.eq_ignore_ascii_case("chunked") {
+            BodyLength::Chunked
+        } else {
+            BodyLength::UntilClose
+        }));
+    }
+
+    let mut length = None;
+
+    for &(ref name, ref value) in headers.iter() {
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let value = try!(value.parse::<u64>()
+                                  .map_err(|_| format!("Invalid Content-Length \"{}\"", value)));
+
+            if length.map_or(false, |l| l != value) {
+                return Err("Conflicting Content-Length headers".to_owned());
+            }
+
+            length = Some(value);
+        }
+    }
+
+    Ok(length.map(BodyLength::Length))
+}
+
+fn next_chunk_state(state: BodyState, byte: u8) -> Result<BodyState, String> {
+    let next = match (state, byte) {
+        (BodyState::ChunkSize(size, digits), _) if (byte as char).is_digit(16) && digits < 15 => {
+            let digit = (byte as char).to_digit(16).unwrap() as u64;
+            Some(BodyState::ChunkSize(size * 16 + digit, digits + 1))
+        }
+        (BodyState::ChunkSize(size, digits), b';') |
+        (BodyState::ChunkSize(size, digits), b' ') |
+        (BodyState::ChunkSize(size, digits), b'\t') if digits > 0 => {
+            Some(BodyState::ChunkExtension(size))
+        }
+        (BodyState::ChunkSize(size, digits), b'\r') if digits > 0 => {
+            Some(BodyState::ChunkSizeLf(size))
+        }
+        (BodyState::ChunkExtension(size), b'\r') => Some(BodyState::ChunkSizeLf(size)),
+        (BodyState::ChunkExtension(size), _) => Some(BodyState::ChunkExtension(size)),
+        (BodyState::ChunkSizeLf(0), b'\n') => Some(BodyState::Trailer(0)),
+        (BodyState::ChunkSizeLf(size), b'\n') => Some(BodyState::ChunkData(size)),
+        (BodyState::ChunkDataCr, b'\r') => Some(BodyState::ChunkDataLf),
+        (BodyState::ChunkDataLf, b'\n') => Some(BodyState::ChunkSize(0, 0)),
+        (BodyState::Trailer(length), b'\r') => Some(BodyState::TrailerLf(length)),
+        (BodyState::Trailer(length), _) => Some(BodyState::Trailer(length + 1)),
+        (BodyState::TrailerLf(0), b'\n') => Some(BodyState::Done),
+        (BodyState::TrailerLf(_), b'\n') => Some(BodyState::Trailer(0)),
+        _ => None,
+    };
+
+    next.ok_or_else(|| "Malformed chunked body".to_owned())
+}
+
+/// The value of the first header called `name`, ignoring case
+pub fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+           .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+           .map(|&(_, ref v)| &v[..])
+}
+
+/// Persistent connections are the default from HTTP/1.1, unless either
+/// side asks otherwise in the Connection header
+fn keep_alive(version: &str, headers: &[(String, String)]) -> bool {
+    if has_connection_option(headers, "close") {
+        false
+    } else {
+        version != "HTTP/1.0" || has_connection_option(headers, "keep-alive")
+    }
+}
+
+fn has_connection_option(headers: &[(String, String)], option: &str) -> bool {
+    header(headers, "Connection").map_or(false, |options| {
+        options.split(',').any(|o| o.trim().to_lowercase() == option)
+    })
+}
+
+fn head_bytes(start_line: &str, headers: &[(String, String)]) -> Vec<u8> {
+    let mut head = format!("{}\r\n", start_line);
+
+    for &(ref name, ref value) in headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+fn parse_header_line(line: &str) -> Result<(String, String), String> {
+    let colon = match line.find(':') {
+        Some(i) => i,
+        None => return Err(format!("Invalid header line \"{}\"", line)),
+    };
+
+    let name = &line[..colon];
+
+    // Folded header lines are obsolete and rejected, like names with
+    // whitespace, which could be read differently by the target
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace()) {
+        return Err(format!("Invalid header line \"{}\"", line));
+    }
+
+    Ok((name.to_owned(), line[colon + 1..].trim().to_owned()))
+}
+
+/// Splits targets like `http://example.com/path` into their authority and
+/// path
+fn split_absolute_target(target: &str) -> Option<(&str, &str)> {
+    let rest = match target.find("://") {
+        Some(i) if !target.starts_with('/') => &target[i + 3..],
+        _ => return None,
+    };
+
+    match rest.find('/') {
+        Some(i) => Some((&rest[..i], &rest[i..])),
+        None => Some((rest, "/")),
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    // IPv6 literals like [::1]:80 contain colons of their own
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(i) => &host[..i + 1],
+            None => host,
+        };
+    }
+
+    match host.rfind(':') {
+        Some(i) => &host[..i],
+        None => host,
+    }
+}
+
+fn find(buf: &[u8], needle: &[u8]) -> Option<usize> {
+    buf.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_request_head, parse_response_head, BodyLength, BodyReader};
+
+    #[test]
+    fn request_heads_are_parsed() {
+        let buf = b"GET /api/users?id=1 HTTP/1.1\r\nHost: Example.com:8080\r\n\
+                    Accept:  */*  \r\n\r\nbody";
+
+        assert_eq!(parse_request_head(&buf[..30]), Ok(None));
+
+        let head = parse_request_head(buf).unwrap().unwrap();
+
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.headers,
+                   vec![("Host".to_owned(), "Example.com:8080".to_owned()),
+                        ("Accept".to_owned(), "*/*".to_owned())]);
+        assert_eq!(head.length, buf.len() - 4);
+        assert_eq!(head.header("accept"), Some("*/*"));
+        assert_eq!(head.host(), Some("Example.com"));
+        assert_eq!(head.path(), "/api/users?id=1");
+    }
+
+    #[test]
+    fn absolute_targets_override_host() {
+        let head = parse_request_head(b"GET http://[::1]:80/a HTTP/1.0\r\nHost: other\r\n\r\n")
+                       .unwrap()
+                       .unwrap();
+
+        assert_eq!(head.host(), Some("[::1]"));
+        assert_eq!(head.path(), "/a");
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        assert!(parse_request_head(b"\x16\x03\x01\x02\x00\x01\r\n").is_err());
+        assert!(parse_request_head(b"GET /\r\n").is_err());
+        assert!(parse_request_head(b"GET / HTTP/1.1\r\nHost example.com\r\n\r\n").is_err());
+        assert!(parse_request_head(b"GET / HTTP/1.1\r\nA: b\r\n  c\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn body_lengths_follow_the_headers() {
+        let request = |head: &[u8]| parse_request_head(head).unwrap().unwrap().body_length();
+        let response = |head: &[u8], method| {
+            parse_response_head(head).unwrap().unwrap().body_length(method)
+        };
+
+        assert_eq!(request(b"GET / HTTP/1.1\r\n\r\n"), Ok(BodyLength::Empty));
+        assert_eq!(request(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n"),
+                   Ok(BodyLength::Length(5)));
+        assert_eq!(request(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+                   Ok(BodyLength::Chunked));
+        assert!(request(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                          Content-Length: 5\r\n\r\n")
+                    .is_err());
+
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", "HEAD"),
+                   Ok(BodyLength::Empty));
+        assert_eq!(response(b"HTTP/1.1 304 Not Modified\r\n\r\n", "GET"),
+                   Ok(BodyLength::Empty));
+        assert_eq!(response(b"HTTP/1.0 200 OK\r\n\r\n", "GET"),
+                   Ok(BodyLength::UntilClose));
+        assert!(parse_response_head(b"SSH-2.0-OpenSSH\r\n").is_err());
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version_and_connection_header() {
+        let keep_alive = |head: &[u8]| parse_request_head(head).unwrap().unwrap().keep_alive();
+
+        assert!(keep_alive(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.1\r\nConnection: Upgrade, Close\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"));
+    }
+
+    #[test]
+    fn upgrades_need_both_headers() {
+        let upgrade = |head: &[u8]| {
+            parse_request_head(head).unwrap().unwrap().upgrade().map(|p| p.to_owned())
+        };
+
+        assert_eq!(upgrade(b"GET / HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\n\
+                             Upgrade: websocket\r\n\r\n"),
+                   Some("websocket".to_owned()));
+        assert_eq!(upgrade(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n"), None);
+        assert_eq!(upgrade(b"GET / HTTP/1.1\r\nConnection: upgrade\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn chunked_bodies_are_followed_across_reads() {
+        let body = b"4;ext=1\r\nWiki\r\n10\r\n0123456789abcdef\r\n0\r\nTrailer: x\r\n\r\nNEXT";
+        let mut reader = BodyReader::new(BodyLength::Chunked);
+        let mut consumed = 0;
+
+        for piece in body.chunks(3) {
+            consumed += reader.consume(piece).unwrap();
+
+            if reader.is_done() {
+                break;
+            }
+        }
+
+        assert!(reader.is_done());
+        assert_eq!(consumed, body.len() - 4);
+
+        assert!(BodyReader::new(BodyLength::Chunked).consume(b"x\r\n").is_err());
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use mio::{TryRead, TryWrite};
+
+use connection::BUFFER_SIZE;
+use frontend::Frontend;
+use http::{self, BodyLength, BodyReader, RequestHead, ResponseHead, MAX_HEAD_SIZE};
+use mirror::Mirror;
+
+/// The bytes going one way through an HTTP connection
+struct Flow {
+    input: Vec<u8>,
+    output: Vec<u8>,
+    body: Option<BodyReader>,
+}
+
+/// A request that was sent on, waiting for its response
+struct Exchange {
+    method: String,
+    keep_alive: bool,
+    upgrade: bool,
+}
+
+/// Relays requests and responses on an HTTP frontend, following each
+/// message so that its head can be rewritten on the way through.
+///
+/// Every request after the first is held until the response to the one
+/// before it has been read, and until the driver has connected to the
+/// target picked for it. Once a target accepts an upgrade to another
+/// protocol, all data is passed through unchanged. A request or response
+/// that doesn't keep the connection alive is the last one relayed.
+pub struct HttpRelay {
+    frontend: Rc<Frontend>,
+    client_addr: SocketAddr,
+    requests: Flow,
+    responses: Flow,
+    exchanges: VecDeque<Exchange>,
+    waiting: Option<(RequestHead, BodyLength)>,
+    started: bool,
+    reusable: bool,
+    upgraded: bool,
+    closing: bool,
+    failed: bool,
+}
+
+impl HttpRelay {
+    pub fn new(frontend: Rc<Frontend>, client_addr: SocketAddr) -> HttpRelay {
+        HttpRelay {
+            frontend: frontend,
+            client_addr: client_addr,
+            requests: Flow::new(),
+            responses: Flow::new(),
+            exchanges: VecDeque::new(),
+            waiting: None,
+            started: false,
+            reusable: true,
+            upgraded: false,
+            closing: false,
+            failed: false,
+        }
+    }
+
+    /// Queues request data that was already read from the client
+    pub fn push_requests(&mut self, data: &[u8]) {
+        self.requests.input.extend(data.iter().cloned());
+        self.process_requests();
+    }
+
+    /// True if there is room for more data from the client
+    pub fn wants_requests(&self) -> bool {
+        !self.failed && self.requests.has_room()
+    }
+
+    pub fn wants_responses(&self) -> bool {
+        !self.failed && self.responses.has_room()
+    }
+
+    pub fn has_requests(&self) -> bool {
+        !self.requests.output.is_empty()
+    }
+
+    pub fn has_responses(&self) -> bool {
+        !self.responses.output.is_empty()
+    }
+
+    /// True once a malformed message has been met, and the response
+    /// explaining it has been sent. Also true once the response to a
+    /// request that wasn't kept alive, by the client or the target, has
+    /// been sent in full.
+    pub fn is_finished(&self) -> bool {
+        self.responses.output.is_empty() &&
+        (self.failed || self.closing && self.exchanges.is_empty() && self.responses.body.is_none())
+    }
+
+    /// True if every request sent to the target has been answered, so that
+    /// the next one may go to another target
+    pub fn is_between_requests(&self) -> bool {
+        !self.upgraded && self.exchanges.is_empty() && self.requests.body.is_none() &&
+        self.requests.output.is_empty() && self.responses.body.is_none()
+    }
+
+    /// True if the target said it will take another request
+    pub fn is_reusable(&self) -> bool {
+        self.reusable && self.is_between_requests()
+    }
+
+    /// True once the target switched to the protocol the client asked for
+    pub fn is_upgraded(&self) -> bool {
+        self.upgraded
+    }
+
+    /// The request waiting for the driver to pick its target
+    pub fn waiting_request(&self) -> Option<&RequestHead> {
+        self.waiting.as_ref().map(|&(ref head, _)| head)
+    }
+
+    /// Sends the waiting request on, once the target it goes to is
+    /// connected. The request and its response get the headers of
+    /// `frontend`, which the request was routed by.
+    pub fn dispatch(&mut self, frontend: Rc<Frontend>) {
+        if let Some((head, length)) = self.waiting.take() {
+            self.frontend = frontend;
+            self.send_request(head, length);
+            self.reusable = true;
+            self.process_requests();
+        }
+    }
+
+    /// Turns away the waiting request, when no target could take it
+    pub fn reject(&mut self, status: u16, reason: &str) {
+        self.fail(status, reason, "No target available for request");
+    }
+
+    /// Answers the request sent on with a 502, when the target closed the
+    /// connection before any of the response was read. Idle connections
+    /// taken from the pool may have been given up on by their target just
+    /// as they were reused. Returns true if there was such a request.
+    pub fn fail_unanswered(&mut self) -> bool {
+        if self.exchanges.is_empty() || !self.responses.input.is_empty() {
+            return false;
+        }
+
+        self.fail(502, "Bad Gateway", "Target closed the connection without responding");
+        true
+    }
+
+    /// Reads from the client, copying what was read to `mirror` if there is
+    /// one
+    pub fn read_requests<R: TryRead>(&mut self,
+                                     src: &mut R,
+                                     mirror: Option<&mut Mirror>)
+                                     -> io::Result<Option<usize>> {
+        let result = self.requests.read_from(src, mirror);
+        self.process_requests();
+
+        result
+    }
+
+    pub fn read_responses<R: TryRead>(&mut self, src: &mut R) -> io::Result<Option<usize>> {
+        let result = self.responses.read_from(src, None);
+        self.process_responses();
+
+        result
+    }
+
+    pub fn write_requests<W: TryWrite>(&mut self, dest: &mut W) -> io::Result<Option<usize>> {
+        self.requests.write_to(dest)
+    }
+
+    pub fn write_responses<W: TryWrite>(&mut self, dest: &mut W) -> io::Result<Option<usize>> {
+        self.responses.write_to(dest)
+    }
+
+    fn process_requests(&mut self) {
+        if self.upgraded {
+            return self.requests.pass_all();
+        }
+
+        while !self.failed && self.waiting.is_none() {
+            if self.requests.body.is_some() {
+                match self.requests.pass_body() {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(e) => return self.fail(400, "Bad Request", &e),
+                }
+            }
+
+            // Nothing after a request that closes the connection is read
+            if self.closing || self.started && !self.is_between_requests() {
+                break;
+            }
+
+            let head = match http::parse_request_head(&self.requests.input) {
+                Ok(Some(head)) => head,
+                Ok(None) if self.requests.input.len() < MAX_HEAD_SIZE => break,
+                Ok(None) => {
+                    return self.fail(431,
+                                     "Request Header Fields Too Large",
+                                     "Request head is too large")
+                }
+                Err(e) => return self.fail(400, "Bad Request", &e),
+            };
+
+            let length = match head.body_length() {
+                Ok(length) => length,
+                Err(e) => return self.fail(400, "Bad Request", &e),
+            };
+
+            self.requests.input.drain(..head.length);
+
+            // The target of the first request was picked before the
+            // connection was opened
+            if self.started {
+                self.waiting = Some((head, length));
+            } else {
+                self.started = true;
+                self.send_request(head, length);
+            }
+        }
+    }
+
+    fn send_request(&mut self, mut head: RequestHead, length: BodyLength) {
+        if self.frontend.forwarded_headers() {
+            self.add_forwarded_headers(&mut head.headers);
+        }
+
+        if let Some(rules) = self.frontend.request_headers() {
+            http::apply_header_rules(rules, &mut head.headers);
+        }
+
+        self.closing = !head.keep_alive();
+        self.requests.output.extend(head.to_bytes());
+        self.requests.body = Some(BodyReader::new(length));
+        self.exchanges.push_back(Exchange {
+            keep_alive: head.keep_alive(),
+            upgrade: head.upgrade().is_some(),
+            method: head.method,
+        });
+    }
+
+    fn process_responses(&mut self) {
+        if self.upgraded {
+            return self.responses.pass_all();
+        }
+
+        while !self.failed {
+            if self.responses.body.is_some() {
+                match self.responses.pass_body() {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(e) => return self.fail(502, "Bad Gateway", &e),
+                }
+            }
+
+            let mut head = match http::parse_response_head(&self.responses.input) {
+                Ok(Some(head)) => head,
+                Ok(None) if self.responses.input.len() < MAX_HEAD_SIZE => break,
+                Ok(None) => return self.fail(502, "Bad Gateway", "Response head is too large"),
+                Err(e) => return self.fail(502, "Bad Gateway", &e),
+            };
+
+            let (method, request_keep_alive, upgrade) = match self.exchanges.front() {
+                Some(exchange) => (exchange.method.clone(), exchange.keep_alive, exchange.upgrade),
+                None => return self.fail(502, "Bad Gateway", "Response without a request"),
+            };
+
+            if head.status == 101 {
+                if !upgrade {
+                    return self.fail(502, "Bad Gateway", "Unexpected 101 response");
+                }
+
+                return self.upgrade(head);
+            }
+
+            let length = match head.body_length(&method) {
+                Ok(length) => length,
+                Err(e) => return self.fail(502, "Bad Gateway", &e),
+            };
+
+            // Interim responses are followed by the real one
+            if !head.is_interim() {
+                self.exchanges.pop_front();
+                self.reusable = request_keep_alive && head.keep_alive() &&
+                                length != BodyLength::UntilClose;
+
+                // The client is told by the response head that it can't
+                // send another request, so requests it already sent are
+                // dropped
+                if !head.keep_alive() {
+                    self.closing = true;
+                    self.requests.input.clear();
+                }
+            }
+
+            if let Some(rules) = self.frontend.response_headers() {
+                http::apply_header_rules(rules, &mut head.headers);
+            }
+
+            self.responses.input.drain(..head.length);
+            self.responses.output.extend(head.to_bytes());
+            self.responses.body = Some(BodyReader::new(length));
+        }
+
+        // The next request may have been held back by this response
+        if self.is_between_requests() && !self.requests.input.is_empty() {
+            self.process_requests();
+        }
+    }
+
+    /// Passes on the response that accepts an upgrade, and everything after
+    /// it in both directions
+    fn upgrade(&mut self, mut head: ResponseHead) {
+        if let Some(rules) = self.frontend.response_headers() {
+            http::apply_header_rules(rules, &mut head.headers);
+        }
+
+        self.responses.input.drain(..head.length);
+        self.responses.output.extend(head.to_bytes());
+        self.exchanges.clear();
+        self.upgraded = true;
+        self.reusable = false;
+
+        self.requests.pass_all();
+        self.responses.pass_all();
+    }
+
+    fn add_forwarded_headers(&self, headers: &mut Vec<(String, String)>) {
+        let client_ip = self.client_addr.ip().to_string();
+        let forwarded_for = match http::header(headers, "X-Forwarded-For") {
+            Some(previous) => format!("{}, {}", previous, client_ip),
+            None => client_ip,
+        };
+        let proto = if self.frontend.tls().is_some() { "https" } else { "http" };
+
+        http::set_header(headers, "X-Forwarded-For", &forwarded_for);
+        http::set_header(headers, "X-Forwarded-Proto", proto);
+
+        if http::header(headers, "X-Request-Id").is_none() {
+            headers.push(("X-Request-Id".to_owned(), http::request_id()));
+        }
+    }
+
+    /// Stops relaying after a malformed message. The client is told why,
+    /// unless it is in the middle of receiving a response.
+    fn fail(&mut self, status: u16, reason: &str, error: &str) {
+        warn!("Closing HTTP connection from {}: {}", self.client_addr, error);
+
+        if self.responses.body.is_none() && self.responses.output.is_empty() {
+            self.responses.output = http::error_response(status, reason);
+        }
+
+        self.failed = true;
+        self.waiting = None;
+        self.requests.input.clear();
+        self.requests.output.clear();
+    }
+}
+
+impl Flow {
+    fn new() -> Flow {
+        Flow {
+            input: Vec::new(),
+            output: Vec::new(),
+            body: None,
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.input.len() < MAX_HEAD_SIZE && self.output.len() < BUFFER_SIZE
+    }
+
+    fn read_from<R: TryRead>(&mut self,
+                             src: &mut R,
+                             mirror: Option<&mut Mirror>)
+                             -> io::Result<Option<usize>> {
+        let mut buf = [0; BUFFER_SIZE];
+        let result = src.try_read(&mut buf);
+
+        if let Ok(Some(n)) = result {
+            self.input.extend(buf[..n].iter().cloned());
+
+            if let Some(mirror) = mirror {
+                mirror.push(&buf[..n]);
+            }
+        }
+
+        result
+    }
+
+    fn write_to<W: TryWrite>(&mut self, dest: &mut W) -> io::Result<Option<usize>> {
+        let result = dest.try_write(&self.output);
+
+        if let Ok(Some(n)) = result {
+            self.output.drain(..n);
+        }
+
+        result
+    }
+
+    /// Moves all input to output, on connections that were upgraded
+    fn pass_all(&mut self) {
+        self.output.extend(self.input.drain(..));
+    }
+
+    /// Moves body bytes from input to output. Returns true once the body
+    /// has ended.
+    fn pass_body(&mut self) -> Result<bool, String> {
+        let done = {
+            let body = self.body.as_mut().expect("No message body to pass on");
+            let n = try!(body.consume(&self.input));
+
+            self.output.extend(self.input.drain(..n));
+            body.is_done()
+        };
+
+        if done {
+            self.body = None;
+        }
+
+        Ok(done)
+    }
+}
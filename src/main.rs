@@ -4,6 +4,8 @@
 
 extern crate clap;
 extern crate mio;
+extern crate nix;
+extern crate openssl;
 extern crate slab;
 extern crate toml;
 extern crate rustc_serialize;
@@ -12,12 +14,25 @@ extern crate rustc_serialize;
 extern crate log;
 extern crate env_logger;
 
+mod access_log;
+mod admin;
 mod config;
 mod connection;
 mod frontend;
 mod backend;
+mod health_check;
+mod http;
+mod http_relay;
+mod metrics;
+mod mirror;
+mod pending;
+mod pool;
+mod proxy_protocol;
 mod driver_state;
 mod driver;
+mod reload;
+mod sni;
+mod tls;
 
 use clap::{Arg, App};
 use mio::EventLoop;
@@ -54,6 +69,14 @@ fn main() {
 
     let mut driver = Driver::new(driver_state);
 
+    match reload::install_sighup_handler() {
+        Ok(()) => {
+            driver.set_reloader(reload::spawn_reloader(config_path.to_owned(),
+                                                       event_loop.channel()))
+        }
+        Err(e) => error!("Could not install SIGHUP handler, reloading is disabled: {:?}", e),
+    }
+
     info!("Starting event loop");
 
     event_loop.run(&mut driver).unwrap()
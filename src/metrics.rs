@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+#[derive(Default)]
+struct FrontendCounters {
+    accepted: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    mirrored_bytes: u64,
+    mirror_dropped_bytes: u64,
+}
+
+#[derive(Default)]
+struct TargetCounters {
+    connections: u64,
+    connect_failures: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// Counters that outlive single connections and reconfigurations. Bytes are
+/// only added here once a connection closes, live connections are included
+/// through the samples passed to `render`.
+pub struct Metrics {
+    frontends: HashMap<String, FrontendCounters>,
+    targets: HashMap<(String, SocketAddr), TargetCounters>,
+}
+
+pub struct FrontendSample {
+    pub name: String,
+    pub active_connections: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub mirrored_bytes: u64,
+    pub mirror_dropped_bytes: u64,
+}
+
+pub struct TargetSample {
+    pub backend: String,
+    pub addr: SocketAddr,
+    pub healthy: bool,
+    pub active_connections: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+pub struct SlabSample {
+    pub name: &'static str,
+    pub used: usize,
+    pub capacity: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            frontends: HashMap::new(),
+            targets: HashMap::new(),
+        }
+    }
+
+    pub fn connection_accepted(&mut self, frontend: &str) {
+        self.frontend(frontend).accepted += 1;
+    }
+
+    pub fn connection_opened(&mut self, backend: &str, target: SocketAddr) {
+        self.target(backend, target).connections += 1;
+    }
+
+    pub fn connect_failed(&mut self, backend: &str, target: SocketAddr) {
+        self.target(backend, target).connect_failures += 1;
+    }
+
+    pub fn connection_closed(&mut self,
+                             frontend: &str,
+                             backend: &str,
+                             target: SocketAddr,
+                             bytes_in: u64,
+                             bytes_out: u64) {
+        {
+            let counters = self.frontend(frontend);
+            counters.bytes_in += bytes_in;
+            counters.bytes_out += bytes_out;
+        }
+
+        let counters = self.target(backend, target);
+        counters.bytes_in += bytes_in;
+        counters.bytes_out += bytes_out;
+    }
+
+    pub fn mirror_closed(&mut self, frontend: &str, bytes_sent: u64, bytes_dropped: u64) {
+        let counters = self.frontend(frontend);
+        counters.mirrored_bytes += bytes_sent;
+        counters.mirror_dropped_bytes += bytes_dropped;
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self,
+                  frontends: &[FrontendSample],
+                  targets: &[TargetSample],
+                  slabs: &[SlabSample])
+                  -> String {
+        let empty_frontend = FrontendCounters::default();
+        let empty_target = TargetCounters::default();
+
+        let frontend_counters = |f: &FrontendSample| {
+            self.frontends.get(&f.name).unwrap_or(&empty_frontend)
+        };
+        let target_counters = |t: &TargetSample| {
+            self.targets.get(&(t.backend.clone(), t.addr)).unwrap_or(&empty_target)
+        };
+
+        let frontend_labels = |f: &FrontendSample| format!("frontend=\"{}\"", escape(&f.name));
+        let target_labels = |t: &TargetSample| {
+            format!("backend=\"{}\",target=\"{}\"", escape(&t.backend), t.addr)
+        };
+
+        let mut out = String::new();
+
+        family(&mut out,
+               "loadbalancer_frontend_connections_accepted_total",
+               "counter",
+               "Connections accepted by a frontend",
+               frontends.iter()
+                        .map(|f| (frontend_labels(f), frontend_counters(f).accepted))
+                        .collect());
+        family(&mut out,
+               "loadbalancer_frontend_connections_active",
+               "gauge",
+               "Open connections accepted by a frontend",
+               frontends.iter()
+                        .map(|f| (frontend_labels(f), f.active_connections as u64))
+                        .collect());
+        family(&mut out,
+               "loadbalancer_frontend_bytes_received_total",
+               "counter",
+               "Bytes received from clients",
+               frontends.iter()
+                        .map(|f| {
+                            (frontend_labels(f), frontend_counters(f).bytes_in + f.bytes_in)
+                        })
+                        .collect());
+        family(&mut out,
+               "loadbalancer_frontend_bytes_sent_total",
+               "counter",
+               "Bytes sent to clients",
+               frontends.iter()
+                        .map(|f| {
+                            (frontend_labels(f), frontend_counters(f).bytes_out + f.bytes_out)
+                        })
+                        .collect());
+        family(&mut out,
+               "loadbalancer_frontend_mirrored_bytes_total",
+               "counter",
+               "Bytes from clients copied to a mirror target",
+               frontends.iter()
+                        .map(|f| {
+                            (frontend_labels(f),
+                             frontend_counters(f).mirrored_bytes + f.mirrored_bytes)
+                        })
+                        .collect());
+        family(&mut out,
+               "loadbalancer_frontend_mirror_dropped_bytes_total",
+               "counter",
+               "Bytes from clients not mirrored because the mirror fell behind",
+               frontends.iter()
+                        .map(|f| {
+                            (frontend_labels(f),
+                             frontend_counters(f).mirror_dropped_bytes + f.mirror_dropped_bytes)
+                        })
+                        .collect());
+
+        family(&mut out,
+               "loadbalancer_backend_connections_active",
+               "gauge",
+               "Open connections to the targets of a backend",
+               backend_sums(targets, |t| t.active_connections as u64));
+        family(&mut out,
+               "loadbalancer_backend_connect_failures_total",
+               "counter",
+               "Failed connection attempts to the targets of a backend",
+               backend_sums(targets, |t| target_counters(t).connect_failures));
+        family(&mut out,
+               "loadbalancer_backend_bytes_sent_total",
+               "counter",
+               "Bytes sent to the targets of a backend",
+               backend_sums(targets, |t| target_counters(t).bytes_in + t.bytes_in));
+        family(&mut out,
+               "loadbalancer_backend_bytes_received_total",
+               "counter",
+               "Bytes received from the targets of a backend",
+               backend_sums(targets, |t| target_counters(t).bytes_out + t.bytes_out));
+        family(&mut out,
+               "loadbalancer_backend_healthy_targets",
+               "gauge",
+               "Number of healthy targets in a backend",
+               backend_sums(targets, |t| t.healthy as u64));
+
+        family(&mut out,
+               "loadbalancer_target_connections_total",
+               "counter",
+               "Connections opened to a target",
+               targets.iter()
+                      .map(|t| (target_labels(t), target_counters(t).connections))
+                      .collect());
+        family(&mut out,
+               "loadbalancer_target_connections_active",
+               "gauge",
+               "Open connections to a target",
+               targets.iter()
+                      .map(|t| (target_labels(t), t.active_connections as u64))
+                      .collect());
+        family(&mut out,
+               "loadbalancer_target_connect_failures_total",
+               "counter",
+               "Failed connection attempts to a target",
+               targets.iter()
+                      .map(|t| (target_labels(t), target_counters(t).connect_failures))
+                      .collect());
+        family(&mut out,
+               "loadbalancer_target_bytes_sent_total",
+               "counter",
+               "Bytes sent to a target",
+               targets.iter()
+                      .map(|t| (target_labels(t), target_counters(t).bytes_in + t.bytes_in))
+                      .collect());
+        family(&mut out,
+               "loadbalancer_target_bytes_received_total",
+               "counter",
+               "Bytes received from a target",
+               targets.iter()
+                      .map(|t| (target_labels(t), target_counters(t).bytes_out + t.bytes_out))
+                      .collect());
+        family(&mut out,
+               "loadbalancer_target_healthy",
+               "gauge",
+               "Whether a target passes its health checks",
+               targets.iter().map(|t| (target_labels(t), t.healthy as u64)).collect());
+
+        family(&mut out,
+               "loadbalancer_connection_slots_used",
+               "gauge",
+               "Used connection slots",
+               slabs.iter()
+                    .map(|s| (format!("slab=\"{}\"", s.name), s.used as u64))
+                    .collect());
+        family(&mut out,
+               "loadbalancer_connection_slots_capacity",
+               "gauge",
+               "Total connection slots",
+               slabs.iter()
+                    .map(|s| (format!("slab=\"{}\"", s.name), s.capacity as u64))
+                    .collect());
+
+        out
+    }
+
+    fn frontend(&mut self, name: &str) -> &mut FrontendCounters {
+        self.frontends.entry(name.to_owned()).or_insert_with(FrontendCounters::default)
+    }
+
+    fn target(&mut self, backend: &str, addr: SocketAddr) -> &mut TargetCounters {
+        self.targets
+            .entry((backend.to_owned(), addr))
+            .or_insert_with(TargetCounters::default)
+    }
+}
+
+/// Sums a value over the targets of each backend
+fn backend_sums<F>(targets: &[TargetSample], f: F) -> Vec<(String, u64)>
+    where F: Fn(&TargetSample) -> u64
+{
+    let mut backends: Vec<&str> = targets.iter().map(|t| &t.backend[..]).collect();
+    backends.sort();
+    backends.dedup();
+
+    backends.into_iter()
+            .map(|backend| {
+                let sum = targets.iter().filter(|t| t.backend == backend).map(|t| f(t)).sum();
+
+                (format!("backend=\"{}\"", escape(backend)), sum)
+            })
+            .collect()
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>) {
+    write!(out, "# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind).unwrap();
+
+    for (labels, value) in samples {
+        write!(out, "{}{{{}}} {}\n", name, labels, value).unwrap();
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Metrics, FrontendSample, TargetSample, SlabSample};
+
+    use std::str::FromStr;
+
+    #[test]
+    fn render_includes_closed_and_live_connections() {
+        let target = FromStr::from_str("127.0.0.1:8000").unwrap();
+        let mut metrics = Metrics::new();
+
+        metrics.connection_accepted("in");
+        metrics.connection_accepted("in");
+        metrics.connection_opened("out", target);
+        metrics.connect_failed("out", target);
+        metrics.connection_closed("in", "out", target, 10, 20);
+
+        let rendered = metrics.render(&[FrontendSample {
+                                            name: "in".to_owned(),
+                                            active_connections: 1,
+                                            bytes_in: 5,
+                                            bytes_out: 0,
+                                            mirrored_bytes: 0,
+                                            mirror_dropped_bytes: 0,
+                                        }],
+                                      &[TargetSample {
+                                            backend: "out".to_owned(),
+                                            addr: target,
+                                            healthy: true,
+                                            active_connections: 1,
+                                            bytes_in: 5,
+                                            bytes_out: 0,
+                                        }],
+                                      &[SlabSample {
+                                            name: "incoming",
+                                            used: 1,
+                                            capacity: 4096,
+                                        }]);
+
+        let lines = rendered.lines().collect::<Vec<_>>();
+
+        for expected in &["loadbalancer_frontend_connections_accepted_total{frontend=\"in\"} 2",
+                          "loadbalancer_frontend_bytes_received_total{frontend=\"in\"} 15",
+                          "loadbalancer_frontend_bytes_sent_total{frontend=\"in\"} 20",
+                          "loadbalancer_backend_connect_failures_total{backend=\"out\"} 1",
+                          "loadbalancer_target_bytes_sent_total{backend=\"out\",\
+                           target=\"127.0.0.1:8000\"} 15",
+                          "loadbalancer_target_healthy{backend=\"out\",target=\"127.0.0.1:8000\"} 1",
+                          "loadbalancer_connection_slots_capacity{slab=\"incoming\"} 4096"] {
+            assert!(lines.contains(expected), "Missing {} in {}", expected, rendered);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+
+use mio::{EventSet, TryRead, TryWrite};
+use mio::tcp::TcpStream;
+
+use backend::ConnectionCount;
+use connection::OutgoingToken;
+use tls::Stream;
+
+/// Client data waiting to be written to a mirror is limited to this many
+/// bytes
+pub const MAX_BUFFERED: usize = 65536;
+
+/// A connection to a target of a frontend's mirror backend, which gets a
+/// copy of everything the client sends. Responses are read and discarded.
+pub struct Mirror {
+    stream: Stream,
+    token: OutgoingToken,
+    target: SocketAddr,
+    target_count: Option<ConnectionCount>,
+    header: Vec<u8>,
+    header_sent: usize,
+    buffer: Vec<u8>,
+    bytes_sent: u64,
+    bytes_dropped: u64,
+    closed: bool,
+}
+
+impl Mirror {
+    pub fn new(stream: Stream,
+               token: OutgoingToken,
+               target: SocketAddr,
+               target_count: Option<ConnectionCount>,
+               header: Vec<u8>)
+               -> Mirror {
+        Mirror {
+            stream: stream,
+            token: token,
+            target: target,
+            target_count: target_count,
+            header: header,
+            header_sent: 0,
+            buffer: Vec::new(),
+            bytes_sent: 0,
+            bytes_dropped: 0,
+            closed: false,
+        }
+    }
+
+    pub fn stream<'a>(&'a self) -> &'a TcpStream {
+        self.stream.get_ref()
+    }
+
+    pub fn token(&self) -> OutgoingToken {
+        self.token
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    /// Takes the mirror off the count of its target
+    pub fn uncount_target(&mut self) {
+        if let Some(count) = self.target_count.take() {
+            count.closed();
+        }
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    pub fn bytes_dropped(&self) -> u64 {
+        self.bytes_dropped
+    }
+
+    /// True once the mirror has failed or fallen too far behind, and
+    /// should be closed
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn interest(&self) -> EventSet {
+        let mut interest = EventSet::readable() | EventSet::error() | EventSet::hup();
+
+        if self.header_sent < self.header.len() || !self.buffer.is_empty() {
+            interest.insert(EventSet::writable());
+        }
+
+        interest
+    }
+
+    /// Queues client data. If the mirror can't keep up, the data is dropped
+    /// and the mirror closed, since the target would only see a broken
+    /// stream from then on.
+    pub fn push(&mut self, data: &[u8]) {
+        if self.closed || self.buffer.len() + data.len() > MAX_BUFFERED {
+            if !self.closed {
+                warn!("Mirror to {} fell behind, dropping mirrored data", self.target);
+            }
+
+            self.bytes_dropped += data.len() as u64;
+            self.closed = true;
+        } else {
+            self.buffer.extend(data.iter().cloned());
+        }
+    }
+
+    pub fn ready(&mut self, events: EventSet) {
+        if events.is_error() || events.is_hup() {
+            debug!("Mirror to {} closed", self.target);
+            self.closed = true;
+            return;
+        }
+
+        if events.is_writable() && self.header_sent < self.header.len() {
+            match self.stream.get_mut().try_write(&self.header[self.header_sent..]) {
+                Ok(Some(n)) => self.header_sent += n,
+                Ok(None) => {}
+                Err(e) => return self.fail(e),
+            }
+
+            if self.header_sent < self.header.len() {
+                return;
+            }
+        }
+
+        if events.is_writable() && !self.buffer.is_empty() {
+            match self.stream.try_write(&self.buffer) {
+                Ok(Some(n)) => {
+                    self.buffer.drain(..n);
+                    self.bytes_sent += n as u64;
+                }
+                Ok(None) => {}
+                Err(e) => return self.fail(e),
+            }
+        }
+
+        if events.is_readable() {
+            let mut buf = [0; 4096];
+
+            loop {
+                match self.stream.try_read(&mut buf) {
+                    Ok(Some(0)) => {
+                        self.closed = true;
+                        break;
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => break,
+                    Err(e) => return self.fail(e),
+                }
+            }
+        }
+    }
+
+    fn fail<E: Display>(&mut self, e: E) {
+        warn!("Mirror to {} failed: {}", self.target, e);
+        self.closed = true;
+    }
+}
@@ -0,0 +1,237 @@
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use mio::{Timeout, TryRead, TryWrite};
+use mio::tcp::TcpStream;
+
+use backend::Backend;
+use connection::BUFFER_SIZE;
+use frontend::Frontend;
+use http::{self, RequestHead};
+use proxy_protocol::{self, ParsedHeader};
+use sni::{self, ClientHello};
+use tls::Stream;
+
+/// Clients that don't send a complete PROXY header, ClientHello or request
+/// head within this time are disconnected
+pub const TIMEOUT_MS: u64 = 5000;
+
+/// An accepted connection waiting for data that is needed before a target
+/// can be picked: the PROXY header on frontends with
+/// `accept_proxy_protocol`, the ClientHello on frontends with routes, and
+/// the first request head on HTTP frontends
+pub struct PendingConnection {
+    stream: Stream,
+    frontend: Rc<Frontend>,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    buffer: Vec<u8>,
+    header: Option<ParsedHeader>,
+    client_hello: Option<ClientHello>,
+    tls_started: bool,
+    request: Option<RequestHead>,
+    timeout: Option<Timeout>,
+}
+
+impl PendingConnection {
+    pub fn new(stream: TcpStream,
+               frontend: Rc<Frontend>,
+               peer_addr: SocketAddr,
+               local_addr: SocketAddr)
+               -> PendingConnection {
+        PendingConnection {
+            stream: Stream::plain(stream),
+            frontend: frontend,
+            peer_addr: peer_addr,
+            local_addr: local_addr,
+            buffer: Vec::new(),
+            header: None,
+            client_hello: None,
+            tls_started: false,
+            request: None,
+            timeout: None,
+        }
+    }
+
+    pub fn stream<'a>(&'a self) -> &'a TcpStream {
+        self.stream.get_ref()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn take_timeout(&mut self) -> Option<Timeout> {
+        self.timeout.take()
+    }
+
+    /// Reads from the client until everything needed has arrived. Returns
+    /// `false` while more data is needed, and an error if the PROXY header
+    /// or request is malformed or the client went away.
+    pub fn ready(&mut self) -> Result<bool, String> {
+        let mut buf = [0; BUFFER_SIZE];
+
+        loop {
+            // Never read more after the header than can be passed on to the
+            // connection
+            let limit = match self.header {
+                None if self.frontend.accept_proxy_protocol() => BUFFER_SIZE,
+                _ => BUFFER_SIZE.min(self.max_data() - (self.buffer.len() - self.data_start())),
+            };
+
+            match self.stream.try_read(&mut buf[..limit]) {
+                Ok(Some(0)) => return Err("Connection closed while waiting for data".to_owned()),
+                Ok(Some(n)) => {
+                    self.buffer.extend(buf[..n].iter().cloned());
+
+                    if try!(self.parse()) {
+                        return Ok(true);
+                    }
+                }
+                Ok(None) => return Ok(false),
+                Err(e) => return Err(format!("Could not read from client: {}", e)),
+            }
+        }
+    }
+
+    fn parse(&mut self) -> Result<bool, String> {
+        if !self.tls_started {
+            if !try!(self.parse_plain()) {
+                return Ok(false);
+            }
+
+            // The request head is encrypted on frontends that terminate
+            // TLS, so the session is started here instead of in the
+            // connection
+            if let (true, Some(acceptor)) = (self.frontend.is_http(), self.frontend.tls()) {
+                let length = self.header.as_ref().map_or(0, |h| h.length);
+                let data = self.buffer.split_off(length);
+
+                try!(self.stream.start_accept(acceptor, data));
+                self.buffer.clear();
+                self.tls_started = true;
+
+                return Ok(false);
+            }
+        }
+
+        if self.request.is_none() && self.frontend.is_http() {
+            let data = &self.buffer[self.data_start()..];
+
+            self.request = match http::parse_request_head(data) {
+                Ok(Some(request)) => Some(request),
+                Ok(None) if data.len() < http::MAX_HEAD_SIZE => return Ok(false),
+                Ok(None) => {
+                    self.reject(431, "Request Header Fields Too Large");
+                    return Err("Request head is too large".to_owned());
+                }
+                Err(e) => {
+                    self.reject(400, "Bad Request");
+                    return Err(e);
+                }
+            };
+        }
+
+        Ok(true)
+    }
+
+    /// Reads the parts that come before any TLS session: the PROXY header
+    /// and the ClientHello
+    fn parse_plain(&mut self) -> Result<bool, String> {
+        if self.header.is_none() && self.frontend.accept_proxy_protocol() {
+            match try!(proxy_protocol::parse_header(&self.buffer)) {
+                Some(header) => self.header = Some(header),
+                None => return Ok(false),
+            }
+        }
+
+        if self.client_hello.is_none() && self.frontend.has_routes() {
+            let data = &self.buffer[self.header.as_ref().map_or(0, |h| h.length)..];
+
+            // Clients that don't speak TLS, or whose ClientHello doesn't fit
+            // in a buffer, are sent to the default backend
+            self.client_hello = match sni::parse_client_hello(data) {
+                Ok(Some(hello)) => Some(hello),
+                Ok(None) if data.len() < self.max_data() => return Ok(false),
+                Ok(None) => {
+                    warn!("ClientHello from {} is too large to route by", self.peer_addr);
+                    Some(ClientHello { server_name: None })
+                }
+                Err(e) => {
+                    debug!("Could not read ClientHello from {}: {}", self.peer_addr, e);
+                    Some(ClientHello { server_name: None })
+                }
+            };
+        }
+
+        Ok(true)
+    }
+
+    /// How much data may be read after the PROXY header. Connections on
+    /// HTTP frontends keep it in growable buffers, and can take whole
+    /// request heads.
+    fn max_data(&self) -> usize {
+        if self.frontend.is_http() {
+            http::MAX_HEAD_SIZE
+        } else {
+            BUFFER_SIZE
+        }
+    }
+
+    /// Where the data to forward starts in the buffer, which is after the
+    /// PROXY header unless it was handed to a TLS session
+    fn data_start(&self) -> usize {
+        match self.header {
+            Some(ref header) if !self.tls_started => header.length,
+            _ => 0,
+        }
+    }
+
+    fn reject(&mut self, status: u16, reason: &str) {
+        // The connection is closed right after, so this is best effort
+        if let Err(e) = self.stream.try_write(&http::error_response(status, reason)) {
+            debug!("Could not send {} response to {}: {}", status, self.peer_addr, e);
+        }
+    }
+
+    /// Picks a backend for the connection once everything has been read,
+    /// and splits it into its parts: the stream, the frontend, the backend,
+    /// the client and destination addresses, and any data to send to the
+    /// target first. On frontends that terminate TLS without reading
+    /// requests, the data is handed to the TLS session instead.
+    pub fn finish(mut self)
+                  -> Result<(Stream,
+                             Rc<Frontend>,
+                             Rc<RefCell<Backend>>,
+                             SocketAddr,
+                             SocketAddr,
+                             Vec<u8>),
+                            String> {
+        let start = self.data_start();
+        let mut data = self.buffer.split_off(start);
+
+        if let (false, Some(acceptor)) = (self.tls_started, self.frontend.tls()) {
+            try!(self.stream.start_accept(acceptor, data));
+            data = Vec::new();
+        }
+
+        let server_name = self.client_hello.and_then(|h| h.server_name);
+        let backend = self.frontend.decide_backend(server_name.as_ref().map(|n| &n[..]),
+                                                   self.request.as_ref());
+
+        debug!("Read client data from {}, server name {:?}, picked backend {}",
+               self.peer_addr,
+               server_name,
+               backend.borrow().name());
+
+        let addrs = self.header.and_then(|h| h.addrs);
+        let (client_addr, frontend_addr) = addrs.unwrap_or((self.peer_addr, self.local_addr));
+
+        Ok((self.stream, self.frontend, backend, client_addr, frontend_addr, data))
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use mio::Timeout;
+use mio::tcp::TcpStream;
+
+use connection::OutgoingToken;
+use tls::Stream;
+
+/// A keep-alive connection to a target that finished its last request,
+/// registered on a token of its own while it waits for the next one
+pub struct IdleStream {
+    stream: Stream,
+    token: OutgoingToken,
+    target: SocketAddr,
+    timeout: Option<Timeout>,
+    idle_since: Instant,
+}
+
+/// Idle connections to the targets of each backend, kept open so that
+/// later requests on HTTP frontends don't have to connect again
+pub struct Pool {
+    idle: HashMap<(String, SocketAddr), Vec<IdleStream>>,
+}
+
+impl IdleStream {
+    pub fn new(stream: Stream, token: OutgoingToken, target: SocketAddr) -> IdleStream {
+        IdleStream {
+            stream: stream,
+            token: token,
+            target: target,
+            timeout: None,
+            idle_since: Instant::now(),
+        }
+    }
+
+    pub fn stream<'a>(&'a self) -> &'a TcpStream {
+        self.stream.get_ref()
+    }
+
+    pub fn token(&self) -> OutgoingToken {
+        self.token
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn take_timeout(&mut self) -> Option<Timeout> {
+        self.timeout.take()
+    }
+
+    pub fn into_stream(self) -> Stream {
+        self.stream
+    }
+}
+
+impl Pool {
+    pub fn new() -> Pool {
+        Pool { idle: HashMap::new() }
+    }
+
+    /// Adds a connection to the pool of its target. If that pool already
+    /// holds `max_idle` connections, the one that has been idle the longest
+    /// is returned to be closed.
+    pub fn put(&mut self,
+               backend: &str,
+               stream: IdleStream,
+               max_idle: usize)
+               -> Option<IdleStream> {
+        let streams = self.idle
+                          .entry((backend.to_owned(), stream.target))
+                          .or_insert_with(Vec::new);
+
+        streams.push(stream);
+
+        if streams.len() > max_idle {
+            Some(streams.remove(0))
+        } else {
+            None
+        }
+    }
+
+    /// Takes the connection to `target` that was used most recently
+    pub fn take(&mut self, backend: &str, target: SocketAddr) -> Option<IdleStream> {
+        self.idle
+            .get_mut(&(backend.to_owned(), target))
+            .and_then(|streams| streams.pop())
+    }
+
+    /// Removes the connection that has been idle the longest, to make room
+    /// for another one when the pool is full
+    pub fn remove_oldest(&mut self) -> Option<IdleStream> {
+        let oldest = self.idle
+                         .iter()
+                         .filter_map(|(key, streams)| streams.first().map(|s| (s.idle_since, key)))
+                         .min()
+                         .map(|(_, key)| key.clone());
+
+        oldest.map(|key| self.idle.get_mut(&key).unwrap().remove(0))
+    }
+
+    /// Removes the connection on `token`, after the target closed it or it
+    /// was idle for too long
+    pub fn remove(&mut self, token: OutgoingToken) -> Option<IdleStream> {
+        for streams in self.idle.values_mut() {
+            if let Some(i) = streams.iter().position(|s| s.token == token) {
+                return Some(streams.remove(i));
+            }
+        }
+
+        None
+    }
+
+    /// Removes the connections to the targets for which `keep` returns false
+    pub fn remove_unless<F>(&mut self, mut keep: F) -> Vec<IdleStream>
+        where F: FnMut(&str, SocketAddr) -> bool
+    {
+        let stale = self.idle
+                        .keys()
+                        .filter(|&&(ref backend, target)| !keep(backend, target))
+                        .cloned()
+                        .collect::<Vec<_>>();
+
+        stale.into_iter()
+             .flat_map(|key| self.idle.remove(&key).unwrap_or_else(Vec::new))
+             .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+
+    use mio::tcp::{TcpListener, TcpStream};
+
+    use connection::OutgoingToken;
+    use tls::Stream;
+
+    use super::{IdleStream, Pool};
+
+    #[test]
+    fn oldest_connections_are_evicted_and_newest_reused() {
+        let listener = TcpListener::bind(&FromStr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let target: SocketAddr = listener.local_addr().unwrap();
+        let idle = |token| {
+            IdleStream::new(Stream::plain(TcpStream::connect(&target).unwrap()),
+                            OutgoingToken(token),
+                            target)
+        };
+
+        let mut pool = Pool::new();
+
+        assert!(pool.put("web", idle(1), 2).is_none());
+        assert!(pool.put("web", idle(2), 2).is_none());
+        assert_eq!(pool.put("web", idle(3), 2).map(|s| s.token()), Some(OutgoingToken(1)));
+
+        assert!(pool.take("api", target).is_none());
+        assert_eq!(pool.take("web", target).map(|s| s.token()), Some(OutgoingToken(3)));
+        assert_eq!(pool.remove(OutgoingToken(2)).map(|s| s.token()), Some(OutgoingToken(2)));
+        assert!(pool.take("web", target).is_none());
+
+        assert!(pool.put("web", idle(4), 2).is_none());
+        assert!(pool.put("api", idle(5), 2).is_none());
+
+        let stale = pool.remove_unless(|backend, _| backend == "web");
+        assert_eq!(stale.iter().map(|s| s.token()).collect::<Vec<_>>(), vec![OutgoingToken(5)]);
+        assert!(pool.take("web", target).is_some());
+
+        assert!(pool.put("api", idle(6), 2).is_none());
+        thread::sleep(Duration::from_millis(1));
+        assert!(pool.put("web", idle(7), 2).is_none());
+
+        assert_eq!(pool.remove_oldest().map(|s| s.token()), Some(OutgoingToken(6)));
+        assert_eq!(pool.remove_oldest().map(|s| s.token()), Some(OutgoingToken(7)));
+        assert!(pool.remove_oldest().is_none());
+    }
+}
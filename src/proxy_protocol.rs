@@ -0,0 +1,328 @@
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str;
+
+use config::ProxyProtocolVersion;
+
+const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+
+// Longest possible v1 header, including the line ending
+const V1_MAX_LENGTH: usize = 107;
+
+/// A header read from a client. `addrs` holds the source and destination
+/// addresses, or `None` for connections that don't carry any, like health
+/// checks from the previous load balancer.
+#[derive(Debug, PartialEq)]
+pub struct ParsedHeader {
+    pub addrs: Option<(SocketAddr, SocketAddr)>,
+    pub length: usize,
+}
+
+/// Parses a v1 or v2 header at the start of `buf`. Returns `None` while the
+/// header is still incomplete.
+pub fn parse_header(buf: &[u8]) -> Result<Option<ParsedHeader>, String> {
+    if starts_with(buf, V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if starts_with(buf, b"PROXY ") {
+        parse_v1(buf)
+    } else {
+        Err("Missing PROXY protocol header".to_owned())
+    }
+}
+
+/// Like `starts_with`, but also true if `buf` is a prefix of `prefix`
+fn starts_with(buf: &[u8], prefix: &[u8]) -> bool {
+    if buf.len() < prefix.len() {
+        prefix.starts_with(buf)
+    } else {
+        buf.starts_with(prefix)
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<ParsedHeader>, String> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LENGTH => end,
+        Some(_) => return Err("v1 header too long".to_owned()),
+        None if buf.len() >= V1_MAX_LENGTH => return Err("v1 header too long".to_owned()),
+        None => return Ok(None),
+    };
+
+    let line = try!(str::from_utf8(&buf[..end]).map_err(|_| "v1 header is not valid ASCII"));
+    let parts = line.split(' ').collect::<Vec<_>>();
+
+    let addrs = match &parts[..] {
+        &["PROXY", "UNKNOWN", ..] => None,
+        &["PROXY", protocol, source, destination, source_port, destination_port] => {
+            let source = try!(parse_v1_addr(protocol, source, source_port));
+            let destination = try!(parse_v1_addr(protocol, destination, destination_port));
+
+            Some((source, destination))
+        }
+        _ => return Err(format!("Malformed v1 header \"{}\"", line)),
+    };
+
+    Ok(Some(ParsedHeader {
+        addrs: addrs,
+        length: end + 2,
+    }))
+}
+
+fn parse_v1_addr(protocol: &str, ip: &str, port: &str) -> Result<SocketAddr, String> {
+    let parsed = match protocol {
+        "TCP4" => ip.parse().map(IpAddr::V4).ok(),
+        "TCP6" => ip.parse().map(IpAddr::V6).ok(),
+        _ => return Err(format!("Unknown v1 protocol {}", protocol)),
+    };
+    let ip = try!(parsed.ok_or_else(|| format!("Invalid {} address {}", protocol, ip)));
+    let port = try!(port.parse().map_err(|_| format!("Invalid port {}", port)));
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<ParsedHeader>, String> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+
+    let length = 16 + read_u16(&buf[14..]) as usize;
+
+    if buf[12] >> 4 != 2 {
+        return Err(format!("Unknown v2 version {}", buf[12] >> 4));
+    }
+
+    if buf.len() < length {
+        return Ok(None);
+    }
+
+    let addrs = &buf[16..length];
+
+    let addrs = match (buf[12] & 0xf, buf[13]) {
+        // LOCAL command, the addresses should be ignored
+        (0, _) => None,
+        (1, 0x11) if addrs.len() >= 12 => {
+            let source = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let destination = Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+
+            Some((SocketAddr::V4(SocketAddrV4::new(source, read_u16(&addrs[8..]))),
+                  SocketAddr::V4(SocketAddrV4::new(destination, read_u16(&addrs[10..])))))
+        }
+        (1, 0x21) if addrs.len() >= 36 => {
+            let source = read_ipv6(&addrs[..16]);
+            let destination = read_ipv6(&addrs[16..32]);
+
+            Some((SocketAddr::V6(SocketAddrV6::new(source, read_u16(&addrs[32..]), 0, 0)),
+                  SocketAddr::V6(SocketAddrV6::new(destination, read_u16(&addrs[34..]), 0, 0))))
+        }
+        (1, 0x11) | (1, 0x21) => return Err("v2 address block too short".to_owned()),
+        // Unspecified, UDP or unix socket addresses, treated like LOCAL
+        (1, _) => None,
+        (command, _) => return Err(format!("Unknown v2 command {}", command)),
+    };
+
+    Ok(Some(ParsedHeader {
+        addrs: addrs,
+        length: length,
+    }))
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    ((buf[0] as u16) << 8) | buf[1] as u16
+}
+
+fn read_ipv6(buf: &[u8]) -> Ipv6Addr {
+    let mut segments = [0; 8];
+
+    for (i, segment) in segments.iter_mut().enumerate() {
+        *segment = read_u16(&buf[i * 2..]);
+    }
+
+    Ipv6Addr::new(segments[0],
+                  segments[1],
+                  segments[2],
+                  segments[3],
+                  segments[4],
+                  segments[5],
+                  segments[6],
+                  segments[7])
+}
+
+/// Builds a PROXY protocol header announcing a connection from `source` to
+/// `destination`
+pub fn header(version: ProxyProtocolVersion,
+              source: SocketAddr,
+              destination: SocketAddr)
+              -> Vec<u8> {
+    let (source, destination) = same_family(source, destination);
+
+    match version {
+        ProxyProtocolVersion::V1 => header_v1(source, destination),
+        ProxyProtocolVersion::V2 => header_v2(source, destination),
+    }
+}
+
+/// Builds a header for connections made by the load balancer itself, like
+/// health checks, that don't carry a client address
+pub fn local_header(version: ProxyProtocolVersion) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+
+            // Version 2, LOCAL command, no address
+            header.extend([0x20, 0x00, 0x00, 0x00].iter().cloned());
+
+            header
+        }
+    }
+}
+
+fn header_v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let protocol = match source {
+        SocketAddr::V4(_) => "TCP4",
+        SocketAddr::V6(_) => "TCP6",
+    };
+
+    format!("PROXY {} {} {} {} {}\r\n",
+            protocol,
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port())
+        .into_bytes()
+}
+
+fn header_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+
+    // Version 2, PROXY command
+    header.push(0x21);
+
+    match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            header.push(0x11);
+            push_u16(&mut header, 12);
+            header.extend(source.ip().octets().iter().cloned());
+            header.extend(destination.ip().octets().iter().cloned());
+        }
+        (SocketAddr::V6(source), SocketAddr::V6(destination)) => {
+            header.push(0x21);
+            push_u16(&mut header, 36);
+
+            let (source_segments, destination_segments) = (source.ip().segments(),
+                                                           destination.ip().segments());
+
+            for segment in source_segments.iter().chain(destination_segments.iter()) {
+                push_u16(&mut header, *segment);
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    push_u16(&mut header, source.port());
+    push_u16(&mut header, destination.port());
+
+    header
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+/// Maps both addresses to IPv6 if only one of them is
+fn same_family(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    match (a, b) {
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => (to_v6(a), b),
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => (a, to_v6(b)),
+        _ => (a, b),
+    }
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            SocketAddr::V6(SocketAddrV6::new(ip.to_ipv6_mapped(), addr.port(), 0, 0))
+        }
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{header, local_header, parse_header, ParsedHeader};
+
+    use std::str::FromStr;
+
+    use config::ProxyProtocolVersion;
+
+    #[test]
+    fn v1_header_is_text() {
+        let source = FromStr::from_str("192.168.0.1:56324").unwrap();
+        let destination = FromStr::from_str("10.0.0.1:443").unwrap();
+
+        assert_eq!(header(ProxyProtocolVersion::V1, source, destination),
+                   b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\n".to_vec());
+
+        let destination = FromStr::from_str("[::1]:443").unwrap();
+
+        assert_eq!(header(ProxyProtocolVersion::V1, source, destination),
+                   b"PROXY TCP6 ::ffff:192.168.0.1 ::1 56324 443\r\n".to_vec());
+    }
+
+    #[test]
+    fn v2_header_is_binary() {
+        let source = FromStr::from_str("192.168.0.1:56324").unwrap();
+        let destination = FromStr::from_str("10.0.0.1:443").unwrap();
+
+        assert_eq!(header(ProxyProtocolVersion::V2, source, destination),
+                   vec![0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+                        0x21, 0x11, 0x00, 0x0c, 192, 168, 0, 1, 10, 0, 0, 1, 0xdc, 0x04, 0x01,
+                        0xbb]);
+        assert_eq!(local_header(ProxyProtocolVersion::V2),
+                   vec![0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+                        0x20, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn headers_are_parsed_with_trailing_data() {
+        let source = FromStr::from_str("[2001:db8::1]:56324").unwrap();
+        let destination = FromStr::from_str("[2001:db8::2]:443").unwrap();
+
+        for version in &[ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let mut buf = header(*version, source, destination);
+            let length = buf.len();
+
+            assert_eq!(parse_header(&buf[..length - 1]), Ok(None));
+
+            buf.extend(b"GET / HTTP/1.0\r\n".iter().cloned());
+
+            assert_eq!(parse_header(&buf),
+                       Ok(Some(ParsedHeader {
+                           addrs: Some((source, destination)),
+                           length: length,
+                       })));
+        }
+
+        assert_eq!(parse_header(b"PROXY UNKNOWN\r\n"),
+                   Ok(Some(ParsedHeader {
+                       addrs: None,
+                       length: 15,
+                   })));
+        assert_eq!(parse_header(&local_header(ProxyProtocolVersion::V2)),
+                   Ok(Some(ParsedHeader {
+                       addrs: None,
+                       length: 16,
+                   })));
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        assert!(parse_header(b"GET / HTTP/1.0\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 1.2.3.4\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 ::1 ::1 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 1.2.3.4 5.6.7.8 1 99999\r\n").is_err());
+        assert!(parse_header(&[b'P'; 200][..]).is_err());
+        assert!(parse_header(b"PROXY TCP4 1.2.3.4 5.6.7.8 11111111111111111111111111111111111111\
+                               11111111111111111111111111111111111111111111111111111111111111")
+                    .is_err());
+    }
+}
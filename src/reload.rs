@@ -0,0 +1,174 @@
+use std::thread;
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use mio::Sender;
+
+use nix;
+use nix::c_int;
+use nix::sys::signal;
+use nix::sys::signal::{SigAction, SigSet};
+use nix::sys::signal::signal::SA_RESTART;
+
+use config::RootConfig;
+use driver::DriverMessage;
+
+const POLL_INTERVAL_MS: u64 = 200;
+
+static SIGHUP_RECEIVED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_sighup(_: c_int) {
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// A handle to a running reloader thread
+#[derive(Clone)]
+pub struct Reloader {
+    requested: Arc<AtomicBool>,
+}
+
+impl Reloader {
+    /// Asks the reloader thread to re-read the configuration file, the same
+    /// way SIGHUP does
+    pub fn request_reload(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Installs a SIGHUP handler that flags a configuration reload. The handler
+/// itself only sets a flag, the actual reload is done by `spawn_reloader`.
+pub fn install_sighup_handler() -> nix::Result<()> {
+    let action = SigAction::new(handle_sighup, SA_RESTART, SigSet::empty());
+
+    unsafe { signal::sigaction(signal::SIGHUP, &action) }.map(|_| ())
+}
+
+/// Starts a thread that re-reads `config_path` whenever SIGHUP has been
+/// received or the returned handle asks for it, and forwards the new
+/// configuration to the event loop. Files that fail to parse are logged and
+/// the running configuration is kept.
+pub fn spawn_reloader(config_path: String, sender: Sender<DriverMessage>) -> Reloader {
+    let reloader = Reloader { requested: Arc::new(AtomicBool::new(false)) };
+    let requested = reloader.requested.clone();
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+            let sighup = SIGHUP_RECEIVED.swap(false, Ordering::SeqCst);
+            let requested = requested.swap(false, Ordering::SeqCst);
+
+            let trigger = match (sighup, requested) {
+                (true, _) => "Received SIGHUP",
+                (false, true) => "Reload requested via admin API",
+                (false, false) => continue,
+            };
+
+            info!("{}, reloading configuration from {}", trigger, config_path);
+
+            match RootConfig::read_config(&config_path) {
+                Ok(config) => {
+                    if sender.send(DriverMessage::Reconfigure(config)).is_err() {
+                        error!("Could not send new configuration to the event loop");
+                        return;
+                    }
+                }
+                Err(e) => {
+                    error!("Could not read configuration {}, keeping the running one: {:?}",
+                           config_path,
+                           e)
+                }
+            }
+        }
+    });
+
+    reloader
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handle_sighup, install_sighup_handler, spawn_reloader, SIGHUP_RECEIVED};
+
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    use mio::{EventLoop, Handler};
+
+    use nix::sys::signal;
+    use nix::unistd;
+
+    use config::RootConfig;
+    use driver::DriverMessage;
+
+    struct ReloadHandler {
+        configs: Vec<RootConfig>,
+    }
+
+    impl Handler for ReloadHandler {
+        type Timeout = ();
+        type Message = DriverMessage;
+
+        fn notify(&mut self, event_loop: &mut EventLoop<ReloadHandler>, msg: DriverMessage) {
+            if let DriverMessage::Reconfigure(config) = msg {
+                self.configs.push(config);
+                event_loop.shutdown();
+            }
+        }
+    }
+
+    fn write_file(path: &str, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn wait_until_taken() {
+        while SIGHUP_RECEIVED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn sighup_reloads_config_file() {
+        let path = env::temp_dir().join("loadbalancer-reload-test.toml");
+        let path = path.to_str().unwrap().to_owned();
+
+        let mut event_loop = EventLoop::new().unwrap();
+
+        SIGHUP_RECEIVED.store(false, Ordering::SeqCst);
+        install_sighup_handler().unwrap();
+        spawn_reloader(path.clone(), event_loop.channel());
+
+        // The reloader takes the flag before reading the file, so it's only
+        // done with the invalid file once it has taken the flag a second time
+        write_file(&path, "this is not toml");
+
+        for _ in 0..2 {
+            handle_sighup(0);
+            wait_until_taken();
+        }
+
+        write_file(&path,
+                   "
+[frontends]
+
+[backends.out]
+target_addrs = [\"127.0.0.1:8000\"]
+
+[buffers]
+connections = 4096
+listeners = 128
+");
+        signal::kill(unistd::getpid(), signal::SIGHUP).unwrap();
+
+        let mut handler = ReloadHandler { configs: Vec::new() };
+        event_loop.run(&mut handler).unwrap();
+
+        assert_eq!(handler.configs.len(), 1);
+        assert_eq!(handler.configs[0].backends["out"].target_addrs,
+                   vec!["127.0.0.1:8000".to_owned()]);
+    }
+}
@@ -0,0 +1,193 @@
+use std::str;
+
+const RECORD_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: usize = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// The parts of a TLS ClientHello used for routing
+#[derive(Debug, PartialEq)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+}
+
+/// Parses the ClientHello at the start of `buf`, which may be split over
+/// several records. Returns `None` while it is still incomplete, and an
+/// error if the client doesn't start a TLS handshake.
+pub fn parse_client_hello(buf: &[u8]) -> Result<Option<ClientHello>, String> {
+    let mut handshake = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        if buf.len() > pos && buf[pos] != RECORD_HANDSHAKE {
+            return Err("Not a TLS handshake".to_owned());
+        }
+
+        if buf.len() < pos + 5 {
+            return Ok(None);
+        }
+
+        let length = ((buf[pos + 3] as usize) << 8) + buf[pos + 4] as usize;
+
+        if buf.len() < pos + 5 + length {
+            return Ok(None);
+        }
+
+        handshake.extend(buf[pos + 5..pos + 5 + length].iter().cloned());
+        pos += 5 + length;
+
+        if handshake.len() >= 4 {
+            if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err("TLS handshake does not start with a ClientHello".to_owned());
+            }
+
+            let length = ((handshake[1] as usize) << 16) + ((handshake[2] as usize) << 8) +
+                         handshake[3] as usize;
+
+            if handshake.len() >= 4 + length {
+                return parse_body(&handshake[4..4 + length]).map(Some);
+            }
+        }
+    }
+}
+
+fn parse_body(body: &[u8]) -> Result<ClientHello, String> {
+    let mut reader = Reader { buf: body };
+
+    // Version and random
+    try!(reader.bytes(2 + 32));
+    // Session ID, cipher suites and compression methods
+    try!(reader.vector(1));
+    try!(reader.vector(2));
+    try!(reader.vector(1));
+
+    let mut hello = ClientHello { server_name: None };
+
+    // Extensions are optional
+    if reader.buf.is_empty() {
+        return Ok(hello);
+    }
+
+    let mut extensions = Reader { buf: try!(reader.vector(2)) };
+
+    while !extensions.buf.is_empty() {
+        let extension_type = try!(extensions.number(2));
+        let data = try!(extensions.vector(2));
+
+        if extension_type == EXTENSION_SERVER_NAME {
+            hello.server_name = try!(parse_server_name(data));
+        }
+    }
+
+    Ok(hello)
+}
+
+fn parse_server_name(data: &[u8]) -> Result<Option<String>, String> {
+    let mut names = Reader { buf: try!(Reader { buf: data }.vector(2)) };
+
+    while !names.buf.is_empty() {
+        let name_type = try!(names.bytes(1))[0];
+        let name = try!(names.vector(2));
+
+        if name_type == NAME_TYPE_HOST_NAME {
+            return str::from_utf8(name)
+                       .map(|n| Some(n.to_owned()))
+                       .map_err(|_| "Server name is not valid UTF-8".to_owned());
+        }
+    }
+
+    Ok(None)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() < n {
+            return Err("Truncated ClientHello".to_owned());
+        }
+
+        let (bytes, rest) = self.buf.split_at(n);
+        self.buf = rest;
+
+        Ok(bytes)
+    }
+
+    fn number(&mut self, n: usize) -> Result<usize, String> {
+        Ok(try!(self.bytes(n)).iter().fold(0, |acc, &b| (acc << 8) + b as usize))
+    }
+
+    /// Reads a vector prefixed by its length in `length_bytes` bytes
+    fn vector(&mut self, length_bytes: usize) -> Result<&'a [u8], String> {
+        let length = try!(self.number(length_bytes));
+
+        self.bytes(length)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{parse_client_hello, ClientHello};
+
+    /// Builds a minimal ClientHello record, with a server name extension if
+    /// `server_name` is given
+    pub fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![0xff, 0x01, 0x00, 0x01, 0x00];
+
+        if let Some(name) = server_name {
+            let length = name.len();
+
+            extensions.extend([0x00, 0x00, 0x00, (length + 5) as u8, 0x00, (length + 3) as u8,
+                               0x00, 0x00, length as u8]
+                                  .iter()
+                                  .cloned());
+            extensions.extend(name.bytes());
+        }
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([7; 32].iter().cloned());
+        body.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00].iter().cloned());
+        body.extend([0x00, extensions.len() as u8].iter().cloned());
+        body.extend(extensions);
+
+        let mut hello = vec![0x16, 0x03, 0x01, 0x00, (body.len() + 4) as u8, 0x01, 0x00, 0x00,
+                             body.len() as u8];
+        hello.extend(body);
+
+        hello
+    }
+
+    #[test]
+    fn server_name_is_read_from_client_hello() {
+        let hello = client_hello(Some("api.example.com"));
+
+        assert_eq!(parse_client_hello(&hello[..hello.len() - 1]), Ok(None));
+        assert_eq!(parse_client_hello(&hello),
+                   Ok(Some(ClientHello { server_name: Some("api.example.com".to_owned()) })));
+        assert_eq!(parse_client_hello(&client_hello(None)),
+                   Ok(Some(ClientHello { server_name: None })));
+    }
+
+    #[test]
+    fn client_hello_may_span_records() {
+        let hello = client_hello(Some("api.example.com"));
+        let (first, second) = hello[5..].split_at(20);
+
+        let mut split = vec![0x16, 0x03, 0x01, 0x00, first.len() as u8];
+        split.extend(first.iter().cloned());
+        split.extend([0x16, 0x03, 0x01, 0x00, second.len() as u8].iter().cloned());
+        split.extend(second.iter().cloned());
+
+        assert_eq!(parse_client_hello(&split),
+                   Ok(Some(ClientHello { server_name: Some("api.example.com".to_owned()) })));
+    }
+
+    #[test]
+    fn other_protocols_are_rejected() {
+        assert!(parse_client_hello(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_client_hello(&[0x16, 0x03, 0x01, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00])
+                    .is_err());
+    }
+}
@@ -0,0 +1,474 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Read, Write, ErrorKind};
+
+use mio::tcp::TcpStream;
+
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslAcceptor, SslAcceptorBuilder, SslConnector, SslFiletype, SslMethod,
+                   SslStream, MidHandshakeSslStream, HandshakeError, NameType, SniError};
+use openssl::x509::X509;
+
+use config::CertificateConfig;
+
+/// Builds an acceptor for the given certificates. Clients are served the
+/// certificate matching the server name they send, or the first one if
+/// none matches.
+pub fn make_acceptor(certificates: &[CertificateConfig]) -> Result<SslAcceptor, String> {
+    let mut contexts = Vec::new();
+
+    for certificate in certificates {
+        let names = try!(certificate_names(&certificate.cert_path));
+        let builder = try!(certificate_builder(certificate));
+
+        contexts.push((names, builder.build().into_context()));
+    }
+
+    let mut builder = try!(certificate_builder(&certificates[0]));
+
+    builder.set_servername_callback(move |ssl, _| {
+        let context = match ssl.servername(NameType::HOST_NAME) {
+            Some(name) => {
+                contexts.iter()
+                        .find(|&&(ref names, _)| {
+                            names.iter().any(|n| matches_server_name(n, name))
+                        })
+                        .map(|&(_, ref context)| context.clone())
+            }
+            None => None,
+        };
+
+        if let Some(context) = context {
+            try!(ssl.set_ssl_context(&context).map_err(|_| SniError::ALERT_FATAL));
+        }
+
+        Ok(())
+    });
+
+    Ok(builder.build())
+}
+
+/// Builds a connector for TLS connections to targets. Targets are verified
+/// against the CA bundle at `ca_path`, or the system's trusted CAs, and
+/// are shown the client certificate if one is given.
+pub fn make_connector(ca_path: Option<&str>,
+                      client_certificate: Option<&CertificateConfig>)
+                      -> Result<SslConnector, String> {
+    let mut builder = try!(SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string()));
+
+    if let Some(path) = ca_path {
+        try!(builder.set_ca_file(path)
+                    .map_err(|e| format!("Could not load CA bundle {}: {}", path, e)));
+    }
+
+    if let Some(config) = client_certificate {
+        try!(builder.set_certificate_chain_file(&config.cert_path).map_err(|e| {
+            format!("Could not load certificate {}: {}", config.cert_path, e)
+        }));
+        try!(builder.set_private_key_file(&config.key_path, SslFiletype::PEM)
+                    .map_err(|e| format!("Could not load key {}: {}", config.key_path, e)));
+        try!(builder.check_private_key().map_err(|e| {
+            format!("Key {} does not match certificate {}: {}",
+                    config.key_path,
+                    config.cert_path,
+                    e)
+        }));
+    }
+
+    Ok(builder.build())
+}
+
+fn certificate_builder(config: &CertificateConfig) -> Result<SslAcceptorBuilder, String> {
+    let mut builder = try!(SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+                               .map_err(|e| e.to_string()));
+
+    try!(builder.set_certificate_chain_file(&config.cert_path)
+                .map_err(|e| format!("Could not load certificate {}: {}", config.cert_path, e)));
+    try!(builder.set_private_key_file(&config.key_path, SslFiletype::PEM)
+                .map_err(|e| format!("Could not load key {}: {}", config.key_path, e)));
+    try!(builder.check_private_key().map_err(|e| {
+        format!("Key {} does not match certificate {}: {}",
+                config.key_path,
+                config.cert_path,
+                e)
+    }));
+
+    Ok(builder)
+}
+
+/// Returns the DNS names a certificate is valid for, falling back to its
+/// common name if it has no subject alternative names
+fn certificate_names(path: &str) -> Result<Vec<String>, String> {
+    let mut pem = Vec::new();
+
+    try!(File::open(path)
+             .and_then(|mut f| f.read_to_end(&mut pem))
+             .map_err(|e| format!("Could not read certificate {}: {}", path, e)));
+
+    let certificate = try!(X509::from_pem(&pem)
+                               .map_err(|e| format!("Invalid certificate {}: {}", path, e)));
+
+    let mut names = match certificate.subject_alt_names() {
+        Some(alt_names) => {
+            alt_names.iter().filter_map(|n| n.dnsname()).map(|n| n.to_owned()).collect()
+        }
+        None => Vec::new(),
+    };
+
+    if names.is_empty() {
+        names = certificate.subject_name()
+                           .entries_by_nid(Nid::COMMONNAME)
+                           .filter_map(|e| String::from_utf8(e.data().as_slice().to_vec()).ok())
+                           .collect();
+    }
+
+    Ok(names)
+}
+
+/// Matches a host name against a name like `example.com` or
+/// `*.example.com`. Wildcards only cover a single label.
+pub fn matches_server_name(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+
+    if pattern.starts_with("*.") {
+        match name.find('.') {
+            Some(i) => i > 0 && name[i..] == pattern[1..],
+            None => false,
+        }
+    } else {
+        pattern == name
+    }
+}
+
+/// A stream that replays bytes that were already read from the socket,
+/// like the start of a TLS handshake read together with a PROXY header
+struct PrefixedStream {
+    prefix: Vec<u8>,
+    stream: TcpStream,
+}
+
+impl Read for PrefixedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.stream.read(buf);
+        }
+
+        let n = ::std::cmp::min(buf.len(), self.prefix.len());
+        buf[..n].clone_from_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+
+        Ok(n)
+    }
+}
+
+impl Write for PrefixedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+enum StreamState {
+    Plain(TcpStream),
+    Connecting(Ssl, PrefixedStream),
+    Accepting(Ssl, PrefixedStream),
+    Handshaking(MidHandshakeSslStream<PrefixedStream>),
+    Tls(SslStream<PrefixedStream>),
+    Failed(MidHandshakeSslStream<PrefixedStream>),
+    // The session could not be set up, which leaves only a second handle
+    // to the socket that openssl dropped
+    SetupFailed(TcpStream),
+}
+
+/// The stream to a client or target, either plain TCP or TLS. The TLS
+/// handshake is continued whenever the stream is read from or written to,
+/// and both return `WouldBlock` until it has finished.
+pub struct Stream {
+    state: Option<StreamState>,
+}
+
+impl Stream {
+    pub fn plain(stream: TcpStream) -> Stream {
+        Stream { state: Some(StreamState::Plain(stream)) }
+    }
+
+    /// Starts a TLS handshake. `data` was already read from the client and
+    /// is handed to the TLS session before anything else.
+    pub fn accept(acceptor: &SslAcceptor,
+                  stream: TcpStream,
+                  data: Vec<u8>)
+                  -> Result<Stream, String> {
+        let stream = PrefixedStream {
+            prefix: data,
+            stream: stream,
+        };
+
+        let state = match acceptor.accept(stream) {
+            Ok(stream) => StreamState::Tls(stream),
+            Err(HandshakeError::WouldBlock(stream)) => StreamState::Handshaking(stream),
+            Err(HandshakeError::Failure(stream)) => {
+                return Err(format!("TLS handshake failed: {}", stream.error()))
+            }
+            Err(HandshakeError::SetupFailure(e)) => {
+                return Err(format!("Could not set up TLS session: {}", e))
+            }
+        };
+
+        Ok(Stream { state: Some(state) })
+    }
+
+    /// Starts a TLS handshake on a plain stream that was already read
+    /// from, like `accept`. The handshake starts with the next read or
+    /// write.
+    pub fn start_accept(&mut self, acceptor: &SslAcceptor, data: Vec<u8>) -> Result<(), String> {
+        let ssl = try!(Ssl::new(acceptor.context())
+                           .map_err(|e| format!("Could not set up TLS session: {}", e)));
+
+        match self.state.take() {
+            Some(StreamState::Plain(stream)) => {
+                let stream = PrefixedStream {
+                    prefix: data,
+                    stream: stream,
+                };
+
+                self.state = Some(StreamState::Accepting(ssl, stream));
+
+                Ok(())
+            }
+            state => {
+                self.state = state;
+
+                Err("TLS session was already started".to_owned())
+            }
+        }
+    }
+
+    /// Sets up a TLS session to a target, verified against `server_name`.
+    /// The handshake starts with the first read or write, once the stream
+    /// has connected.
+    pub fn connect(connector: &SslConnector,
+                   server_name: &str,
+                   stream: TcpStream)
+                   -> Result<Stream, String> {
+        let ssl = try!(connector.configure()
+                                .and_then(|c| c.into_ssl(server_name))
+                                .map_err(|e| format!("Could not set up TLS session: {}", e)));
+        let stream = PrefixedStream {
+            prefix: Vec::new(),
+            stream: stream,
+        };
+
+        Ok(Stream { state: Some(StreamState::Connecting(ssl, stream)) })
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        match *self.state.as_ref().expect("Stream is only taken during the handshake") {
+            StreamState::Plain(ref stream) => stream,
+            StreamState::Connecting(_, ref stream) |
+            StreamState::Accepting(_, ref stream) => &stream.stream,
+            StreamState::Handshaking(ref stream) |
+            StreamState::Failed(ref stream) => &stream.get_ref().stream,
+            StreamState::Tls(ref stream) => &stream.get_ref().stream,
+            StreamState::SetupFailed(ref stream) => stream,
+        }
+    }
+
+    /// The socket itself, for writing data that has to go before the TLS
+    /// handshake
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        match *self.state.as_mut().expect("Stream is only taken during the handshake") {
+            StreamState::Plain(ref mut stream) => stream,
+            StreamState::Connecting(_, ref mut stream) |
+            StreamState::Accepting(_, ref mut stream) => &mut stream.stream,
+            StreamState::Handshaking(ref mut stream) |
+            StreamState::Failed(ref mut stream) => &mut stream.get_mut().stream,
+            StreamState::Tls(ref mut stream) => &mut stream.get_mut().stream,
+            StreamState::SetupFailed(ref mut stream) => stream,
+        }
+    }
+
+    /// True if the TLS session holds decrypted data that hasn't been read
+    /// yet, which won't be signalled by the socket
+    pub fn has_pending(&self) -> bool {
+        match self.state {
+            Some(StreamState::Tls(ref stream)) => stream.ssl().pending() > 0,
+            _ => false,
+        }
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        match self.state {
+            Some(StreamState::Connecting(..)) |
+            Some(StreamState::Accepting(..)) |
+            Some(StreamState::Handshaking(_)) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        match self.state {
+            Some(StreamState::Failed(_)) |
+            Some(StreamState::SetupFailed(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Continues the TLS handshake. Returns `WouldBlock` while it hasn't
+    /// finished, and succeeds right away for plain streams.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        // Setting up the session drops the socket if it fails, so a second
+        // handle to it is kept until the handshake has started
+        let (result, spare) = match self.state.take() {
+            Some(StreamState::Connecting(ssl, stream)) => {
+                match stream.stream.try_clone() {
+                    Ok(spare) => (ssl.connect(stream), Some(spare)),
+                    Err(e) => return self.setup_failed(stream.stream, &e),
+                }
+            }
+            Some(StreamState::Accepting(ssl, stream)) => {
+                match stream.stream.try_clone() {
+                    Ok(spare) => (ssl.accept(stream), Some(spare)),
+                    Err(e) => return self.setup_failed(stream.stream, &e),
+                }
+            }
+            Some(StreamState::Handshaking(stream)) => (stream.handshake(), None),
+            state => {
+                self.state = state;
+                return Ok(());
+            }
+        };
+
+        match result {
+            Ok(stream) => {
+                debug!("TLS handshake finished");
+                self.state = Some(StreamState::Tls(stream));
+
+                Ok(())
+            }
+            Err(HandshakeError::WouldBlock(stream)) => {
+                self.state = Some(StreamState::Handshaking(stream));
+
+                Err(io::Error::new(ErrorKind::WouldBlock, "TLS handshake in progress"))
+            }
+            Err(HandshakeError::Failure(stream)) => {
+                warn!("TLS handshake failed: {}", stream.error());
+                self.state = Some(StreamState::Failed(stream));
+
+                Err(io::Error::new(ErrorKind::Other, "TLS handshake failed"))
+            }
+            Err(HandshakeError::SetupFailure(e)) => {
+                match spare {
+                    Some(spare) => self.setup_failed(spare, &e),
+                    None => Err(io::Error::new(ErrorKind::Other, e)),
+                }
+            }
+        }
+    }
+
+    fn setup_failed<E: Display>(&mut self, stream: TcpStream, e: &E) -> io::Result<()> {
+        warn!("Could not set up TLS session: {}", e);
+        self.state = Some(StreamState::SetupFailed(stream));
+
+        Err(io::Error::new(ErrorKind::Other, "TLS handshake failed"))
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.handshake());
+
+        match self.state {
+            Some(StreamState::Plain(ref mut stream)) => stream.read(buf),
+            Some(StreamState::Tls(ref mut stream)) => stream.read(buf),
+            _ => Err(io::Error::new(ErrorKind::Other, "TLS handshake failed")),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.handshake());
+
+        match self.state {
+            Some(StreamState::Plain(ref mut stream)) => stream.write(buf),
+            Some(StreamState::Tls(ref mut stream)) => stream.write(buf),
+            _ => Err(io::Error::new(ErrorKind::Other, "TLS handshake failed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.state {
+            Some(StreamState::Plain(ref mut stream)) => stream.flush(),
+            Some(StreamState::Tls(ref mut stream)) => stream.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::matches_server_name;
+
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509NameBuilder};
+
+    use config::CertificateConfig;
+
+    /// Writes a self-signed certificate and its key for `name` to the
+    /// temporary directory
+    pub fn write_certificate(name: &str) -> CertificateConfig {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = builder.build();
+
+        let dir = env::temp_dir();
+        let cert_path = dir.join(format!("loadbalancer-test-{}.crt", name));
+        let key_path = dir.join(format!("loadbalancer-test-{}.key", name));
+
+        File::create(&cert_path).unwrap().write_all(&certificate.to_pem().unwrap()).unwrap();
+        File::create(&key_path)
+            .unwrap()
+            .write_all(&key.private_key_to_pem_pkcs8().unwrap())
+            .unwrap();
+
+        CertificateConfig {
+            cert_path: cert_path.to_str().unwrap().to_owned(),
+            key_path: key_path.to_str().unwrap().to_owned(),
+        }
+    }
+
+    #[test]
+    fn server_names_match_exactly_or_by_wildcard() {
+        assert!(matches_server_name("example.com", "example.com"));
+        assert!(matches_server_name("example.com", "EXAMPLE.com"));
+        assert!(!matches_server_name("example.com", "www.example.com"));
+
+        assert!(matches_server_name("*.example.com", "www.example.com"));
+        assert!(!matches_server_name("*.example.com", "example.com"));
+        assert!(!matches_server_name("*.example.com", "a.b.example.com"));
+        assert!(!matches_server_name("*.example.com", ".example.com"));
+    }
+}